@@ -45,7 +45,8 @@ fn main() {
     })));
 
     // Add an html object and convert
-    c.add_html_object(os, &html);
+    c.add_html_object(os, &html)
+        .expect("failed to add html object");
     let mut pdfout = c.convert().expect("failed to convert");
 
     // let mut pdfout = pdfout;
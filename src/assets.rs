@@ -0,0 +1,216 @@
+//! Serve in-memory assets to the page being rendered
+//!
+//! HTML rendered with `build_from_html` has no base URL, so relative links to stylesheets, images,
+//!   or fonts can't be resolved without writing them to temporary files. An [`AssetBundle`](struct.AssetBundle.html)
+//!   attached to a builder is served from a loopback HTTP server for the duration of the conversion,
+//!   and the page's base URL is set to that server so that relative links resolve to the bundle.
+//!
+//! Assets are only served for HTML sources, since URLs and files have a base URL of their own.
+//!
+//! ## Example
+//! ```no_run
+//! # #[cfg(any(feature = "native", feature = "dynamic"))] {
+//! use wkhtmltopdf::*;
+//!
+//! let mut assets = AssetBundle::new();
+//! assets
+//!     .insert("style.css", &b"h1 { color: teal }"[..], "text/css")
+//!     .insert("img/logo.png", std::fs::read("logo.png").unwrap(), "image/png");
+//!
+//! let pdf_app = PdfApplication::new().expect("Failed to init PDF application");
+//! let pdfout = pdf_app.builder()
+//!     .assets(&assets)
+//!     .build_from_html(r#"<link rel="stylesheet" href="style.css"><h1><img src="img/logo.png"></h1>"#)
+//!     .expect("failed to build pdf");
+//! # }
+//! ```
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+
+use crate::http::{read_head, respond, Head, LoopbackServer};
+
+/// A set of in-memory files, keyed by their path relative to the page
+#[derive(Debug, Clone, Default)]
+pub struct AssetBundle {
+    assets: HashMap<String, Arc<Asset>>,
+}
+
+#[derive(Debug)]
+struct Asset {
+    data: Cow<'static, [u8]>,
+    content_type: String,
+}
+
+impl AssetBundle {
+    /// Instantiate an empty bundle
+    pub fn new() -> AssetBundle {
+        AssetBundle::default()
+    }
+
+    /// Add a file served at `path` with the MIME type `content_type`, e.g. "text/css"
+    ///
+    /// `path` is relative to the page, e.g. "img/logo.png" (a leading `/` is ignored).
+    ///   Adding a file at an existing path replaces it.
+    pub fn insert<P, D, C>(&mut self, path: P, data: D, content_type: C) -> &mut AssetBundle
+    where
+        P: AsRef<str>,
+        D: Into<Cow<'static, [u8]>>,
+        C: Into<String>,
+    {
+        let asset = Asset {
+            data: data.into(),
+            content_type: content_type.into(),
+        };
+        self.assets.insert(
+            path.as_ref().trim_start_matches('/').to_string(),
+            Arc::new(asset),
+        );
+        self
+    }
+
+    /// The number of files in the bundle
+    pub fn len(&self) -> usize {
+        self.assets.len()
+    }
+
+    /// Whether the bundle has no files
+    pub fn is_empty(&self) -> bool {
+        self.assets.is_empty()
+    }
+
+    // The data and MIME type of the file at `path`
+    pub(crate) fn get(&self, path: &str) -> Option<(&[u8], &str)> {
+        self.assets
+            .get(path.trim_start_matches('/'))
+            .map(|asset| (&*asset.data, asset.content_type.as_str()))
+    }
+}
+
+/// A loopback HTTP server serving an `AssetBundle` for the duration of a conversion
+///
+/// The server stops when dropped.
+pub(crate) struct AssetServer {
+    server: LoopbackServer,
+}
+
+impl AssetServer {
+    /// Start serving `bundle` on an ephemeral loopback port
+    pub(crate) fn start(bundle: &AssetBundle) -> io::Result<AssetServer> {
+        let bundle = bundle.clone();
+        let server = LoopbackServer::start("asset server", move |client| serve(client, &bundle))?;
+        Ok(AssetServer { server })
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.server.addr()
+    }
+
+    /// The URL that relative links in the page resolve against
+    pub(crate) fn base_url(&self) -> String {
+        format!("http://{}/", self.server.addr())
+    }
+}
+
+// Handle one request for an asset, closing the connection after responding
+fn serve(mut client: TcpStream, bundle: &AssetBundle) -> io::Result<()> {
+    let (head, _) = read_head(&mut client)?;
+    let Head { method, target, .. } = match head {
+        Some(head) => head,
+        None => return respond(&mut client, "400 Bad Request"),
+    };
+    let head_only = match method.as_str() {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return respond(&mut client, "405 Method Not Allowed"),
+    };
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    let asset = match percent_decode(path) {
+        Some(path) => bundle.get(&path),
+        None => None,
+    };
+    let (data, content_type) = match asset {
+        Some(asset) => asset,
+        None => return respond(&mut client, "404 Not Found"),
+    };
+    write!(
+        client,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        content_type,
+        data.len()
+    )?;
+    if !head_only {
+        client.write_all(data)?;
+    }
+    client.flush()
+}
+
+// Decode `%XX` escapes in a request path, returning `None` if they aren't valid UTF-8
+pub(crate) fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn serves_bundled_assets() {
+        let mut bundle = AssetBundle::new();
+        bundle
+            .insert("/style.css", &b"h1 { color: teal }"[..], "text/css")
+            .insert("img/my logo.png", vec![0x89, b'P', b'N', b'G'], "image/png");
+        assert_eq!(bundle.len(), 2);
+
+        let server = AssetServer::start(&bundle).unwrap();
+        let request = |method: &str, target: &str| {
+            let mut conn = TcpStream::connect(server.addr()).unwrap();
+            write!(
+                conn,
+                "{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                method, target
+            )
+            .unwrap();
+            let mut response = Vec::new();
+            conn.read_to_end(&mut response).unwrap();
+            response
+        };
+
+        let response = request("GET", "/style.css?v=2");
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\nContent-Type: text/css\r\n"));
+        assert!(response.ends_with(b"\r\n\r\nh1 { color: teal }"));
+
+        let response = request("GET", "/img/my%20logo.png");
+        assert!(response.ends_with(b"\r\n\r\n\x89PNG"));
+
+        let response = request("HEAD", "/style.css");
+        assert!(response.ends_with(b"Content-Length: 18\r\nConnection: close\r\n\r\n"));
+
+        assert!(request("GET", "/missing.css").starts_with(b"HTTP/1.1 404"));
+        assert!(request("POST", "/style.css").starts_with(b"HTTP/1.1 405"));
+        assert!(server.base_url().starts_with("http://127.0.0.1:"));
+    }
+}
@@ -0,0 +1,361 @@
+//! Render many PDFs, continuing past the ones that fail
+//!
+//! [`PdfApplication::render_batch`](../pdf/struct.PdfApplication.html#method.render_batch) renders
+//!   each [`BatchJob`](struct.BatchJob.html) in turn with the application's wkhtmltopdf, and returns
+//!   an iterator of [`BatchResult`](struct.BatchResult.html)s: a failed job is reported with its
+//!   error, and the batch carries on with the next job. Jobs are only rendered as results are consumed.
+//!
+//! With [`BatchOptions::workers`](struct.BatchOptions.html#method.workers), jobs are instead rendered
+//!   in parallel by that many worker processes, each an [`IsolatedRenderer`](../isolated/index.html)
+//!   (so `init_isolated_worker` must be called at the start of `main`). Results are then returned
+//!   in the order the jobs finish, which is why every result carries the `id` of its job.
+//!
+//! ## Example
+//! ```no_run
+//! use wkhtmltopdf::*;
+//!
+//! fn main() {
+//!     init_isolated_worker();
+//!
+//!     let pdf_app = PdfApplication::new().expect("Failed to init PDF application");
+//!     let mut builder = pdf_app.builder();
+//!     builder.title("Invoice");
+//!     let jobs = (1..=1000).map(|number| {
+//!         let html = format!("<h1>Invoice {}</h1>", number);
+//!         BatchJob::new(number, &builder, Source::Html(html))
+//!     });
+//!
+//!     let mut options = BatchOptions::new();
+//!     options.workers(4);
+//!     for result in pdf_app.render_batch_with(jobs, &options) {
+//!         match result.result {
+//!             Ok(pdf) => println!("invoice {}: {} bytes in {:?}", result.id, pdf.len(), result.elapsed),
+//!             Err(err) => println!("invoice {} failed: {}", result.id, err),
+//!         }
+//!     }
+//! }
+//! ```
+use std::ffi::OsString;
+use std::io::Read;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::diagnostics::Diagnostics;
+use crate::error::*;
+use crate::isolated::IsolatedRenderer;
+use crate::library::Capabilities;
+use crate::pdf::{PdfApplication, PdfBuilder};
+use crate::source::Source;
+use crate::sys::lock_or_recover;
+
+/// A PDF to render in a batch, identified by `id` in its result
+#[derive(Clone)]
+pub struct BatchJob<Id> {
+    pub id: Id,
+    pub builder: PdfBuilder,
+    pub source: Source,
+}
+
+impl<Id> BatchJob<Id> {
+    /// Render `source` with the settings of `builder`
+    pub fn new(id: Id, builder: &PdfBuilder, source: Source) -> BatchJob<Id> {
+        BatchJob {
+            id,
+            builder: builder.clone(),
+            source,
+        }
+    }
+}
+
+/// The outcome of a `BatchJob`
+#[derive(Debug)]
+pub struct BatchResult<Id> {
+    /// The `id` of the job
+    pub id: Id,
+    /// The rendered PDF, or the reason the job failed
+    pub result: Result<Vec<u8>>,
+    /// How long the job took to render
+    pub elapsed: Duration,
+    /// Problems reported while rendering the job (empty if it failed)
+    pub diagnostics: Diagnostics,
+}
+
+/// How `PdfApplication::render_batch_with` renders a batch
+#[derive(Debug, Clone, Default)]
+pub struct BatchOptions {
+    workers: usize,
+    command: Option<PathBuf>,
+    args: Vec<OsString>,
+}
+
+impl BatchOptions {
+    /// Render one job at a time in this process
+    pub fn new() -> BatchOptions {
+        BatchOptions::default()
+    }
+
+    /// Render up to `workers` jobs at a time, each in a worker process (default none)
+    ///
+    /// With no workers, jobs are rendered one at a time by the application's wkhtmltopdf.
+    pub fn workers(&mut self, workers: usize) -> &mut BatchOptions {
+        self.workers = workers;
+        self
+    }
+
+    /// Path to the worker executable, which must call `init_isolated_worker` (default the current executable)
+    pub fn worker_command<P: Into<PathBuf>>(&mut self, path: P) -> &mut BatchOptions {
+        self.command = Some(path.into());
+        self
+    }
+
+    /// Arguments passed to the worker executable
+    pub fn worker_args<I, S>(&mut self, args: I) -> &mut BatchOptions
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    // An isolated renderer for one of the batch's workers
+    fn renderer(&self) -> IsolatedRenderer {
+        let mut renderer = IsolatedRenderer::new();
+        if let Some(ref command) = self.command {
+            renderer.command(command);
+        }
+        renderer.args(self.args.iter().cloned());
+        renderer
+    }
+}
+
+/// The results of `PdfApplication::render_batch`, rendering each job as it is needed
+pub struct BatchResults<'a, Id, J> {
+    jobs: J,
+    mode: Mode<'a, Id>,
+}
+
+enum Mode<'a, Id> {
+    // Borrows the application, since wkhtmltopdf must stay initialized while rendering
+    Sequential(Capabilities, PhantomData<&'a PdfApplication>),
+    Parallel(Workers<Id>),
+}
+
+impl<'a, Id, J> BatchResults<'a, Id, J>
+where
+    Id: Send + 'static,
+    J: Iterator<Item = BatchJob<Id>>,
+{
+    pub(crate) fn new(
+        app: &'a PdfApplication,
+        jobs: J,
+        options: &BatchOptions,
+    ) -> BatchResults<'a, Id, J> {
+        let mode = match options.workers {
+            0 => Mode::Sequential(app.capabilities(), PhantomData),
+            workers => Mode::Parallel(Workers::start(workers, options)),
+        };
+        BatchResults { jobs, mode }
+    }
+}
+
+impl<'a, Id, J> Iterator for BatchResults<'a, Id, J>
+where
+    Id: Send + 'static,
+    J: Iterator<Item = BatchJob<Id>>,
+{
+    type Item = BatchResult<Id>;
+
+    fn next(&mut self) -> Option<BatchResult<Id>> {
+        match self.mode {
+            Mode::Sequential(capabilities, _) => self
+                .jobs
+                .next()
+                .map(|job| render_in_process(job, capabilities)),
+            Mode::Parallel(ref mut workers) => workers.next(&mut self.jobs),
+        }
+    }
+}
+
+fn render_in_process<Id>(mut job: BatchJob<Id>, capabilities: Capabilities) -> BatchResult<Id> {
+    let start = Instant::now();
+    let mut diagnostics = Diagnostics::default();
+    let result = job
+        .builder
+        .set_capabilities(capabilities)
+        .build_from_source(&job.source)
+        .and_then(|mut output| {
+            let mut data = Vec::new();
+            output.read_to_end(&mut data)?;
+            diagnostics = output.diagnostics().clone();
+            Ok(data)
+        });
+    BatchResult {
+        id: job.id,
+        result,
+        elapsed: start.elapsed(),
+        diagnostics,
+    }
+}
+
+// Threads that each send jobs to their own worker process
+struct Workers<Id> {
+    jobs: Option<mpsc::Sender<BatchJob<Id>>>,
+    results: mpsc::Receiver<BatchResult<Id>>,
+    threads: Vec<JoinHandle<()>>,
+    in_flight: usize,
+}
+
+impl<Id: Send + 'static> Workers<Id> {
+    fn start(workers: usize, options: &BatchOptions) -> Workers<Id> {
+        let (job_tx, job_rx) = mpsc::channel::<BatchJob<Id>>();
+        let (result_tx, results) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let threads = (0..workers)
+            .map(|_| {
+                let renderer = options.renderer();
+                let jobs = job_rx.clone();
+                let results = result_tx.clone();
+                thread::spawn(move || loop {
+                    // Only hold the lock while waiting, so other threads can take the next job
+                    let job = match lock_or_recover(&jobs).recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    let start = Instant::now();
+                    let (result, diagnostics) =
+                        match renderer.render_pdf_with_diagnostics(&job.builder, &job.source) {
+                            Ok((data, diagnostics)) => (Ok(data), diagnostics),
+                            Err(err) => (Err(err), Diagnostics::default()),
+                        };
+                    let result = BatchResult {
+                        id: job.id,
+                        result,
+                        elapsed: start.elapsed(),
+                        diagnostics,
+                    };
+                    if results.send(result).is_err() {
+                        return;
+                    }
+                })
+            })
+            .collect();
+        Workers {
+            jobs: Some(job_tx),
+            results,
+            threads,
+            in_flight: 0,
+        }
+    }
+
+    fn next<J: Iterator<Item = BatchJob<Id>>>(&mut self, jobs: &mut J) -> Option<BatchResult<Id>> {
+        // Keep every worker busy without reading the whole batch up front
+        while self.in_flight < self.threads.len() {
+            let job = match jobs.next() {
+                Some(job) => job,
+                None => break,
+            };
+            if let Err(mpsc::SendError(job)) = self.jobs.as_ref()?.send(job) {
+                // Every thread has stopped, so the job can't be rendered
+                return Some(BatchResult {
+                    id: job.id,
+                    result: Err(Error::ServiceStopped),
+                    elapsed: Duration::ZERO,
+                    diagnostics: Diagnostics::default(),
+                });
+            }
+            self.in_flight += 1;
+        }
+        if self.in_flight == 0 {
+            return None;
+        }
+        let result = self.results.recv().ok()?;
+        self.in_flight -= 1;
+        Some(result)
+    }
+}
+
+impl<Id> Drop for Workers<Id> {
+    fn drop(&mut self) {
+        // Closing the queue stops each thread once its current job is done, which stops its worker
+        self.jobs = None;
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn continues_past_failed_jobs() {
+        use crate::fixtures::{TempFile, CRASH_ON_REQUEST};
+
+        // A worker that crashes on every request
+        let command = TempFile::script("batch", CRASH_ON_REQUEST);
+        let mut options = BatchOptions::new();
+        options.workers(2).worker_command(command.path());
+        let builder = PdfBuilder::new();
+        let mut jobs =
+            (0..5).map(|id| BatchJob::new(id, &builder, Source::Html("<h1>crash</h1>".into())));
+        let mut workers = Workers::start(2, &options);
+        let mut ids = Vec::new();
+        while let Some(result) = workers.next(&mut jobs) {
+            assert!(
+                matches!(result.result, Err(Error::RendererCrashed { .. })),
+                "{:?}",
+                result.result
+            );
+            ids.push(result.id);
+        }
+        ids.sort();
+        assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn renders_sequentially_as_results_are_consumed() {
+        use std::cell::Cell;
+
+        // Both jobs fail before reaching wkhtmltopdf, so no application is needed
+        let mut confined = PdfBuilder::new();
+        confined.root_dir(std::env::temp_dir());
+        let jobs = vec![
+            BatchJob::new(
+                "missing",
+                &PdfBuilder::new(),
+                Source::Path("/nonexistent/page.html".into()),
+            ),
+            BatchJob::new("outside", &confined, Source::Path("..".into())),
+        ];
+        let taken = Cell::new(0);
+        let mut results = BatchResults {
+            jobs: jobs.into_iter().inspect(|_| taken.set(taken.get() + 1)),
+            mode: Mode::Sequential(Capabilities { extended_qt: false }, PhantomData),
+        };
+        assert_eq!(taken.get(), 0);
+
+        let result = results.next().unwrap();
+        assert_eq!(taken.get(), 1);
+        assert_eq!(result.id, "missing");
+        assert!(
+            matches!(result.result, Err(Error::ObjectSettingFailure(..))),
+            "{:?}",
+            result.result
+        );
+
+        let result = results.next().unwrap();
+        assert_eq!(result.id, "outside");
+        assert!(
+            matches!(result.result, Err(Error::PathNotAllowed(_))),
+            "{:?}",
+            result.result
+        );
+        assert!(results.next().is_none());
+    }
+}
@@ -0,0 +1,100 @@
+//! Render PDFs and images over HTTP (see the `server` module for the API)
+//!
+//! Built with the `server-bin` feature, which loads libwkhtmltox at runtime unless `native` is also enabled.
+//!
+//! Usage: wkhtmltopdf-server [--listen ADDR] [--max-body-size BYTES] [--max-connections N]
+//!   [--timeout SECONDS] [--allow-host HOST]... [--allow-path PATH]... [--no-sandbox]
+//!
+//! Requests are rendered in a `Sandbox`, so pages can only load from the hosts and paths that are
+//!   allowed (including the page of a `url` request). `--no-sandbox` lets every page make requests to
+//!   any host the server can reach (e.g. internal services) and read any file the server can read.
+//!
+//! Conversions time out after 30 seconds unless `--timeout` is set, so a page that never finishes
+//!   loading can't stall every later request.
+use std::env;
+use std::process;
+use std::time::Duration;
+
+use wkhtmltopdf::server::{Server, ServerOptions};
+use wkhtmltopdf::{init_isolated_worker, RenderService, Sandbox};
+
+const USAGE: &str =
+    "usage: wkhtmltopdf-server [--listen ADDR] [--max-body-size BYTES] [--max-connections N]
+                          [--timeout SECONDS] [--allow-host HOST]... [--allow-path PATH]... [--no-sandbox]
+
+Pages can only load from the hosts and paths allowed with --allow-host and --allow-path.
+--no-sandbox lets pages request any host the server can reach and read any file it can read,
+so it must only be used when every client is trusted.
+Conversions time out after --timeout seconds (default 30), which requests can only shorten.";
+
+// Conversions are serialized on one thread, so a hung page must not hold it indefinitely
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn main() {
+    // Conversions with a timeout are run by a worker process started from this executable
+    init_isolated_worker();
+
+    let mut listen = String::from("127.0.0.1:8080");
+    let mut options = ServerOptions::new();
+    options.timeout(DEFAULT_TIMEOUT);
+    // Untrusted requests must not reach internal hosts or local files
+    let mut sandbox = Sandbox::new();
+    let mut sandboxed = true;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            "--no-sandbox" => {
+                sandboxed = false;
+                continue;
+            }
+            _ => args.next().unwrap_or_else(|| fail(&arg)),
+        };
+        match arg.as_str() {
+            "--listen" => listen = value,
+            "--allow-host" => {
+                sandbox.allow_host(value);
+            }
+            "--allow-path" => {
+                sandbox.allow_path(value);
+            }
+            "--max-body-size" => {
+                options.max_body_size(value.parse().unwrap_or_else(|_| fail(&arg)));
+            }
+            "--max-connections" => {
+                options.max_connections(value.parse().unwrap_or_else(|_| fail(&arg)));
+            }
+            "--timeout" => {
+                options.timeout(Duration::from_secs(
+                    value.parse().unwrap_or_else(|_| fail(&arg)),
+                ));
+            }
+            _ => fail(&arg),
+        }
+    }
+    if sandboxed {
+        options.sandbox(&sandbox);
+    } else {
+        eprintln!("warning: rendering without a sandbox, so pages can reach any host and file");
+    }
+
+    // Conversions are queued to the service's thread, which owns wkhtmltopdf and wkhtmltoimage
+    let service = RenderService::start().unwrap_or_else(|err| {
+        eprintln!("failed to initialize wkhtmltopdf: {}", err);
+        process::exit(1);
+    });
+    let server = Server::bind(service.handle(), &*listen, &options).unwrap_or_else(|err| {
+        eprintln!("failed to listen on {}: {}", listen, err);
+        process::exit(1);
+    });
+    println!("listening on http://{}/", server.addr());
+    server.wait();
+}
+
+fn fail(arg: &str) -> ! {
+    eprintln!("invalid argument '{}'\n{}", arg, USAGE);
+    process::exit(2);
+}
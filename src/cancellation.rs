@@ -0,0 +1,155 @@
+//! Timeouts and cancellation for conversions
+//!
+//! libwkhtmltox has no way to abort a conversion once `wkhtmltopdf_convert` has started,
+//!   so conversions with a timeout or cancellation token run in a process that is killed when
+//!   a limit is reached. `IsolatedRenderer` and `SubprocessRenderer` kill their own process.
+//!   In-process conversions (e.g. `PdfBuilder::build_from_html`, or those queued to a `RenderHandle`)
+//!   are run by an isolated worker, which requires calling
+//!   [`init_isolated_worker`](../fn.init_isolated_worker.html) at the start of `main`;
+//!   otherwise they return `Error::WorkerUnavailable` instead of accepting a limit that can't be enforced.
+//!
+//! ## Example
+//! ```
+//! use wkhtmltopdf::*;
+//! use std::time::Duration;
+//!
+//! fn render_report<R: Renderer>(renderer: &R, token: CancellationToken) -> Result<Vec<u8>> {
+//!     let mut builder = renderer.pdf_builder();
+//!     builder
+//!         .timeout(Duration::from_secs(30))
+//!         .cancellation_token(token);
+//!     renderer.render_pdf(&builder, &Source::Html("<h1>Report</h1>".into()))
+//! }
+//!
+//! // e.g. cancelled from another thread when the client disconnects
+//! let token = CancellationToken::new();
+//! token.cancel();
+//! let res = render_report(&MockRenderer::new(), token);
+//! assert!(matches!(res, Err(Error::Cancelled)));
+//! ```
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::*;
+
+/// A token for cancelling conversions from another thread
+///
+/// Clones share the same state, so cancelling any clone cancels every conversion using the token.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Create a token that hasn't been cancelled
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Cancel every conversion using this token
+    ///
+    /// Conversions that haven't finished return `Error::Cancelled`.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether `cancel` has been called on this token (or one of its clones)
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// The timeout and cancellation token configured on a builder
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Limits {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) token: Option<CancellationToken>,
+}
+
+impl Limits {
+    /// Whether a timeout or cancellation token is set
+    #[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+    pub(crate) fn is_set(&self) -> bool {
+        self.timeout.is_some() || self.token.is_some()
+    }
+
+    /// Start enforcing these limits for a conversion starting now
+    pub(crate) fn start(&self) -> Watch {
+        Watch {
+            deadline: self
+                .timeout
+                .map(|timeout| (Instant::now() + timeout, timeout)),
+            token: self.token.clone(),
+        }
+    }
+}
+
+/// The limits of a conversion in progress
+pub(crate) struct Watch {
+    deadline: Option<(Instant, Duration)>,
+    token: Option<CancellationToken>,
+}
+
+impl Watch {
+    /// Returns `Error::Cancelled` or `Error::Timeout` if the conversion should stop
+    pub(crate) fn check(&self) -> Result<()> {
+        if let Some(ref token) = self.token {
+            if token.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+        }
+        match self.deadline {
+            Some((deadline, timeout)) if Instant::now() >= deadline => Err(Error::Timeout(timeout)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether there is a timeout or cancellation token to check while waiting
+    pub(crate) fn is_limited(&self) -> bool {
+        self.deadline.is_some() || self.token.is_some()
+    }
+
+    /// How long to wait before checking again, so a wait is interrupted shortly after a limit is reached
+    pub(crate) fn poll_interval(&self) -> Duration {
+        const INTERVAL: Duration = Duration::from_millis(20);
+        match self.deadline {
+            Some((deadline, _)) => deadline
+                .saturating_duration_since(Instant::now())
+                .min(INTERVAL),
+            None => INTERVAL,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancelling_a_clone_cancels_the_watch() {
+        let token = CancellationToken::new();
+        let limits = Limits {
+            timeout: None,
+            token: Some(token.clone()),
+        };
+        let watch = limits.start();
+        assert!(watch.check().is_ok());
+
+        token.clone().cancel();
+        assert!(matches!(watch.check(), Err(Error::Cancelled)));
+    }
+
+    #[test]
+    fn times_out_after_deadline() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(0)),
+            token: None,
+        };
+        match limits.start().check() {
+            Err(Error::Timeout(timeout)) => assert_eq!(timeout, Duration::from_millis(0)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(Limits::default().start().check().is_ok());
+    }
+}
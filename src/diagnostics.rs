@@ -0,0 +1,140 @@
+//! Problems reported during a conversion that didn't cause it to fail
+//!
+//! By default, a page or resource that fails to load fails the whole conversion. With a
+//!   [`LoadErrorPolicy`](enum.LoadErrorPolicy.html) of `Skip` or `Ignore` (set with `load_error_policy`
+//!   or `media_load_error_policy` on either builder), the conversion continues and the failure is
+//!   reported in the output's diagnostics instead.
+use std::fmt;
+
+use crate::error::{classify_failure, Error};
+
+/// Problems encountered while rendering a document that still produced output
+///
+/// Available from [`PdfOutput::diagnostics`](struct.PdfOutput.html#method.diagnostics)
+///   and [`ImageOutput::diagnostics`](struct.ImageOutput.html#method.diagnostics).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diagnostics {
+    /// Requests that were blocked by the builder's [`Sandbox`](struct.Sandbox.html)
+    pub blocked_requests: Vec<BlockedRequest>,
+    /// Pages and resources that failed to load, but were skipped or ignored by the builder's `LoadErrorPolicy`
+    pub failed_loads: Vec<FailedLoad>,
+}
+
+impl Diagnostics {
+    /// Whether nothing was reported
+    pub fn is_empty(&self) -> bool {
+        self.blocked_requests.is_empty() && self.failed_loads.is_empty()
+    }
+}
+
+/// How to handle a page or resource that fails to load
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoadErrorPolicy {
+    /// Fail the conversion (the default)
+    Abort,
+    /// Leave the page or resource out of the output
+    Skip,
+    /// Render the page with whatever did load
+    Ignore,
+}
+
+impl LoadErrorPolicy {
+    pub(crate) fn value(&self) -> &'static str {
+        match self {
+            LoadErrorPolicy::Abort => "abort",
+            LoadErrorPolicy::Skip => "skip",
+            LoadErrorPolicy::Ignore => "ignore",
+        }
+    }
+}
+
+/// A page or resource that failed to load without failing the conversion
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedLoad {
+    /// The URL of the page or resource
+    pub url: String,
+    /// Why it failed to load, as reported by wkhtmltopdf
+    pub reason: String,
+    /// Whether it was skipped or ignored
+    pub policy: LoadErrorPolicy,
+}
+
+impl FailedLoad {
+    // The load failure reported by a wkhtmltopdf warning, e.g.
+    //   "Failed loading page http://bad/ (skipped)"
+    #[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+    pub(crate) fn from_warning(warning: &str) -> Option<FailedLoad> {
+        let warning = warning.trim();
+        let (message, policy) = if let Some(message) = warning.strip_suffix("(skipped)") {
+            (message, LoadErrorPolicy::Skip)
+        } else {
+            (warning.strip_suffix("(ignored)")?, LoadErrorPolicy::Ignore)
+        };
+        match classify_failure([message])? {
+            Error::LoadFailed { url, reason } | Error::NetworkError { url, reason } => {
+                Some(FailedLoad {
+                    url,
+                    reason,
+                    policy,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FailedLoad {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self.policy {
+            LoadErrorPolicy::Abort => "failed to load",
+            LoadErrorPolicy::Skip => "skipped",
+            LoadErrorPolicy::Ignore => "ignored",
+        };
+        write!(f, "{} {}: {}", action, self.url, self.reason)
+    }
+}
+
+/// A request made by the page that wasn't allowed by its `Sandbox`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockedRequest {
+    /// The requested URL (only the scheme, host, and port are known for HTTPS requests)
+    pub url: String,
+}
+
+impl fmt::Display for BlockedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "blocked request to {}", self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_failed_loads() {
+        let failed = FailedLoad::from_warning("Failed loading page http://bad/ (skipped)").unwrap();
+        assert_eq!(failed.url, "http://bad/");
+        assert_eq!(failed.policy, LoadErrorPolicy::Skip);
+
+        let failed = FailedLoad::from_warning(
+            "Failed to load http://example.com/logo.png, with HTTP status code 404 - Not Found (ignored)",
+        )
+        .unwrap();
+        assert_eq!(failed.url, "http://example.com/logo.png");
+        assert_eq!(failed.reason, "Not Found");
+        assert_eq!(
+            failed.to_string(),
+            "ignored http://example.com/logo.png: Not Found"
+        );
+
+        assert_eq!(
+            FailedLoad::from_warning("Failed loading page http://bad/ (sometimes it will work just to ignore this error with --load-error-handling ignore)"),
+            None
+        );
+        assert_eq!(
+            FailedLoad::from_warning("Javascript warning (skipped)"),
+            None
+        );
+    }
+}
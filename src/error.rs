@@ -1,62 +1,362 @@
-use quick_error::quick_error;
+use std::error::Error as StdError;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
 use std::{io, result};
 
-quick_error! {
-    /// The error type for wkhtmltopdf generation
-    #[derive(Debug)]
-    pub enum Error {
-        /// Indicates an I/O error that occurred during PDF generation
-        IoError(err: io::Error) {
-            description("io error")
-            display("I/O error: {}", err)
-            cause(err)
-            from()
-        }
+/// The error type for wkhtmltopdf generation
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Indicates an I/O error that occurred during PDF generation
+    IoError(io::Error),
 
-        /// Indicates the wkhtmltopdf could be be initialized because it can only be initialized once per process (wkhtmltopdf limitation)
-        IllegalInit {
-            description("illegal initialization")
-            display("Wkhtmltopdf may not be initialized more than once per process")
-        }
+    /// Indicates an error decoding or encoding rendered image output
+    ImageError(::image::ImageError),
 
-        /// Indicates that wkhtmltopdf has not yet been initialized in this process
-        NotInitialized {
-            description("not initialized")
-            display("Wkhtmltopdf is not currently initialized")
-        }
+    /// Indicates the wkhtmltopdf could be be initialized because it can only be initialized once per process (wkhtmltopdf limitation)
+    IllegalInit,
 
-        /// Indicates that wkhtmltopdf is blocked by another request within this process (wkhtmltopdf limitation)
-        Blocked {
-            description("wkhtmltopdf blocked")
-            display("Wkhtmltopdf is currently blocked by another initialized instance")
-        }
+    /// Indicates that libwkhtmltox could not be loaded at runtime (`dynamic` feature)
+    LibraryNotLoaded(String),
 
-        /// Indicates that wkhtmltopdf was initialized on a different thread than this PDF generation atttempt (wkhtmltopdf limitation)
-        ThreadMismatch(before: usize, after: usize) {
-            description("thread mismatch")
-            display("Wkhtmltopdf originally started on thread {:0x}, cannot recreate on thread {:0x}", before, after)
-        }
+    /// Indicates that wkhtmltopdf has not yet been initialized in this process
+    NotInitialized,
 
+    /// Indicates that wkhtmltopdf is blocked by another request within this process (wkhtmltopdf limitation)
+    Blocked,
 
-        /// Indicates that wkhtmltopdf conversion failed - internal error message comes directly from wkhtmltopdf
-        ConversionFailed(msg: String) {
-            description("conversion failed")
-            display("Conversion failed: {}", msg)
-        }
+    /// Indicates that wkhtmltopdf was initialized on a different thread than this PDF generation atttempt (wkhtmltopdf limitation)
+    ThreadMismatch(usize, usize),
+
+    /// Indicates that the `RenderService` thread has stopped, so queued conversions cannot complete
+    ServiceStopped,
+
+    /// Indicates that the worker process of an `IsolatedRenderer` crashed (or was killed) during a conversion
+    ///
+    /// `signal` is the signal that terminated the worker, if known (always `None` on Windows).
+    RendererCrashed { signal: Option<i32> },
+
+    /// Indicates that a conversion took longer than the timeout configured on its builder
+    Timeout(Duration),
+
+    /// Indicates that a conversion was cancelled with a `CancellationToken`
+    Cancelled,
+
+    /// Indicates that an in-process conversion has a timeout or cancellation token, which can only be
+    ///   enforced by an isolated worker, but `init_isolated_worker` wasn't called at the start of `main`
+    WorkerUnavailable,
+
+    /// Indicates that the page (or a resource it requires) could not be loaded, e.g. because the server
+    ///   responded with an HTTP error or a local file doesn't exist
+    LoadFailed { url: String, reason: String },
+
+    /// Indicates that the page (or a resource it requires) could not be reached, e.g. because its host
+    ///   could not be resolved or refused the connection
+    NetworkError { url: String, reason: String },
+
+    /// Indicates that wkhtmltopdf failed to write its output
+    OutputWriteFailed(String),
+
+    /// Indicates that wkhtmltopdf conversion failed - internal error message comes directly from wkhtmltopdf
+    ///
+    /// Failures that wkhtmltopdf describes in a known format are reported as `LoadFailed`, `NetworkError`,
+    ///   or `OutputWriteFailed` instead.
+    ConversionFailed(String),
 
-        /// Indicates that wkhtmltopdf failed to set a particular global setting
-        GlobalSettingFailure(name: String, value: String) {
-            description("global setting failure")
-            display("Failed to update global setting '{}'='{}'", name, value)
+    /// Indicates that a template failed to render in `build_from_template`
+    ///
+    /// `source` is the template engine's error, which is also returned by `Error::source`.
+    Template {
+        /// The name of the template that failed to render
+        name: String,
+        source: Box<dyn StdError + Send + Sync>,
+    },
+
+    /// Indicates that no visible element matched the selector of `CaptureMode::Element`
+    ElementNotFound(String),
+
+    /// Indicates input that can't be passed to wkhtmltopdf, e.g. HTML containing a NUL byte
+    InvalidInput(String),
+
+    /// Indicates that a lock shared with wkhtmltopdf was poisoned by a panic on another thread
+    Poisoned(&'static str),
+
+    /// Indicates that a path is outside of the root directory configured on the builder
+    PathNotAllowed(PathBuf),
+
+    /// Indicates that a resource referenced by a page couldn't be found by `inline_resources`
+    ResourceNotFound(String),
+
+    /// Indicates that a setting requires wkhtmltopdf to be built against its patched version of Qt
+    Unsupported(String),
+
+    /// Indicates that wkhtmltopdf failed to set a particular global setting
+    GlobalSettingFailure(String, String),
+
+    /// Indicates that wkhtmltopdf failed to set a particular object setting
+    ObjectSettingFailure(String, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // These errors are described by their source
+            Error::IoError(_) => write!(f, "I/O error"),
+            Error::ImageError(_) => write!(f, "Image error"),
+            Error::IllegalInit => {
+                write!(
+                    f,
+                    "Wkhtmltopdf may not be initialized more than once per process"
+                )
+            }
+            Error::LibraryNotLoaded(reason) => write!(f, "Failed to load libwkhtmltox: {}", reason),
+            Error::NotInitialized => write!(f, "Wkhtmltopdf is not currently initialized"),
+            Error::Blocked => write!(
+                f,
+                "Wkhtmltopdf is currently blocked by another initialized instance"
+            ),
+            Error::ThreadMismatch(before, after) => write!(
+                f,
+                "Wkhtmltopdf originally started on thread {:0x}, cannot recreate on thread {:0x}",
+                before, after
+            ),
+            Error::ServiceStopped => write!(f, "The render service is no longer running"),
+            Error::RendererCrashed {
+                signal: Some(signal),
+            } => {
+                write!(f, "Renderer process crashed with signal {}", signal)
+            }
+            Error::RendererCrashed { signal: None } => write!(f, "Renderer process crashed"),
+            Error::Timeout(timeout) => write!(f, "Conversion timed out after {:?}", timeout),
+            Error::Cancelled => write!(f, "Conversion was cancelled"),
+            Error::WorkerUnavailable => write!(
+                f,
+                "Timeouts and cancellation of in-process conversions require init_isolated_worker"
+            ),
+            Error::LoadFailed { url, reason } => write!(f, "Failed to load '{}': {}", url, reason),
+            Error::NetworkError { url, reason } => {
+                write!(f, "Network error loading '{}': {}", url, reason)
+            }
+            Error::OutputWriteFailed(reason) => write!(f, "Failed to write output: {}", reason),
+            Error::ConversionFailed(msg) => write!(f, "Conversion failed: {}", msg),
+            Error::Template { name, .. } => write!(f, "Failed to render template '{}'", name),
+            Error::ElementNotFound(selector) => {
+                write!(f, "No visible element matches selector '{}'", selector)
+            }
+            Error::InvalidInput(reason) => write!(f, "Invalid input: {}", reason),
+            Error::Poisoned(lock) => write!(
+                f,
+                "The {} lock was poisoned by a panic on another thread",
+                lock
+            ),
+            Error::PathNotAllowed(path) => write!(
+                f,
+                "Path '{}' is outside of the root directory",
+                path.display()
+            ),
+            Error::ResourceNotFound(path) => write!(
+                f,
+                "Resource '{}' referenced by the page was not found",
+                path
+            ),
+            Error::Unsupported(name) => write!(
+                f,
+                "Setting '{}' requires wkhtmltopdf built with patched Qt",
+                name
+            ),
+            Error::GlobalSettingFailure(name, value) => {
+                write!(f, "Failed to update global setting '{}'='{}'", name, value)
+            }
+            Error::ObjectSettingFailure(name, value) => {
+                write!(f, "Failed to update object setting '{}'='{}'", name, value)
+            }
         }
+    }
+}
 
-        /// Indicates that wkhtmltopdf failed to set a particular object setting
-        ObjectSettingFailure(name: String, value: String) {
-            description("object setting failure")
-            display("Failed to update object setting '{}'='{}'", name, value)
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::IoError(err) => Some(err),
+            Error::ImageError(err) => Some(err),
+            Error::Template { source, .. } => Some(&**source),
+            _ => None,
         }
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+impl From<::image::ImageError> for Error {
+    fn from(err: ::image::ImageError) -> Error {
+        Error::ImageError(err)
+    }
+}
+
+// Describes `err` followed by its sources, for responses and logs that only show a single message
+#[cfg_attr(
+    not(any(feature = "native", feature = "dynamic", feature = "server")),
+    allow(dead_code)
+)]
+pub(crate) fn describe_sources(err: &dyn StdError) -> String {
+    let mut msg = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        msg.push_str(": ");
+        msg.push_str(&err.to_string());
+        source = err.source();
+    }
+    msg
+}
+
 /// A specialized `Result` type for wkhtmltopdf generation
 pub type Result<T> = result::Result<T, Error>;
+
+// The start of the errors wkhtmltopdf and wkhtmltoimage report when they can't write their output
+const WRITE_FAILURES: &[&str] = &[
+    "Unable to write to destination",
+    "Could not write to output file",
+];
+
+/// Classify the error messages wkhtmltopdf reported for a failed conversion
+///
+/// Returns the error for the first message in a known format, since wkhtmltopdf reports the cause
+///   of a failure (e.g. a network error) before its consequences (e.g. the page failing to load).
+pub(crate) fn classify_failure<I, S>(messages: I) -> Option<Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    messages
+        .into_iter()
+        .find_map(|msg| classify_message(msg.as_ref().trim()))
+}
+
+fn classify_message(msg: &str) -> Option<Error> {
+    if WRITE_FAILURES.iter().any(|prefix| msg.starts_with(prefix)) {
+        return Some(Error::OutputWriteFailed(msg.to_string()));
+    }
+
+    // e.g. "Failed to load http://bad/, with network status code 3 and http status code 0 - Host bad not found"
+    //   or "Failed to load http://example.com/logo.png, with HTTP status code 404 - Not Found"
+    if let Some((url, status)) = msg
+        .strip_prefix("Failed to load ")
+        .and_then(|rest| rest.split_once(", with "))
+    {
+        let (status, reason) = status.split_once(" - ").unwrap_or((status, ""));
+        let network_status: u32 = match status.strip_prefix("network status code ") {
+            Some(codes) => codes.split(' ').next()?.parse().ok()?,
+            None if status.starts_with("HTTP status code ") => 0,
+            None => return None,
+        };
+        let http_status = status.rsplit(' ').next().unwrap_or_default();
+        let reason = match reason.trim() {
+            "" => format!("HTTP status {}", http_status),
+            reason => reason.to_string(),
+        };
+        let url = url.to_string();
+        // Qt reports connection and proxy errors below 200, and content and protocol errors above
+        return Some(match network_status {
+            1..=199 => Error::NetworkError { url, reason },
+            _ => Error::LoadFailed { url, reason },
+        });
+    }
+
+    // e.g. "Failed loading page http://bad/ (sometimes it will work just to ignore this error with --load-error-handling ignore)"
+    let page = msg.strip_prefix("Failed loading page ")?;
+    let url = page.split(" (").next().unwrap_or(page).trim();
+    Some(Error::LoadFailed {
+        url: url.to_string(),
+        reason: "the page failed to load".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_conversion_failures() {
+        match classify_failure([
+            "Failed to load http://bad/, with network status code 3 and http status code 0 - Host bad not found",
+            "Failed loading page http://bad/ (sometimes it will work just to ignore this error with --load-error-handling ignore)",
+        ]) {
+            Some(Error::NetworkError { url, reason }) => {
+                assert_eq!(url, "http://bad/");
+                assert_eq!(reason, "Host bad not found");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        match classify_failure(["Failed to load https://example.com/missing.css, with network status code 203 and http status code 404 - Not Found"]) {
+            Some(Error::LoadFailed { url, reason }) => {
+                assert_eq!(url, "https://example.com/missing.css");
+                assert_eq!(reason, "Not Found");
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        match classify_failure([
+            "Failed to load https://example.com/logo.png, with HTTP status code 403",
+        ]) {
+            Some(Error::LoadFailed { reason, .. }) => assert_eq!(reason, "HTTP status 403"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+        match classify_failure(["Failed loading page http://bad/ (sometimes it will work just to ignore this error with --load-error-handling ignore)"]) {
+            Some(Error::LoadFailed { url, .. }) => assert_eq!(url, "http://bad/"),
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(matches!(
+            classify_failure(["Unable to write to destination"]),
+            Some(Error::OutputWriteFailed(_))
+        ));
+        assert!(classify_failure(["Failed loading page"]).is_none());
+        assert!(classify_failure(Vec::<String>::new()).is_none());
+    }
+
+    #[test]
+    fn chains_error_sources() {
+        let err = Error::from(io::Error::other("disk full"));
+        assert_eq!(err.to_string(), "I/O error");
+        assert_eq!(err.source().unwrap().to_string(), "disk full");
+        assert_eq!(describe_sources(&err), "I/O error: disk full");
+        assert!(Error::Cancelled.source().is_none());
+
+        // Like template engines, describes only itself and returns the cause from `source`
+        #[derive(Debug)]
+        struct RenderError(io::Error);
+        impl fmt::Display for RenderError {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "failed to render 'invoice.html'")
+            }
+        }
+        impl StdError for RenderError {
+            fn source(&self) -> Option<&(dyn StdError + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let err = Error::Template {
+            name: "invoice.html".into(),
+            source: Box::new(RenderError(io::Error::other("variable `number` not found"))),
+        };
+        assert_eq!(err.to_string(), "Failed to render template 'invoice.html'");
+        let mut sources = Vec::new();
+        let mut source = err.source();
+        while let Some(err) = source {
+            sources.push(err.to_string());
+            source = err.source();
+        }
+        assert_eq!(
+            sources,
+            [
+                "failed to render 'invoice.html'",
+                "variable `number` not found"
+            ]
+        );
+        assert!(err.source().unwrap().is::<RenderError>());
+    }
+}
@@ -0,0 +1,49 @@
+//! Stand-in executables for tests of the renderers that run wkhtmltopdf in another process
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Reads the start of a request, then crashes with SIGSEGV like a renderer hitting a Qt bug
+#[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+pub(crate) const CRASH_ON_REQUEST: &str = "head -c 1 > /dev/null\nkill -SEGV $$";
+
+/// A file in the temp dir, which is removed when dropped
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Create an empty file named after `name`
+    pub(crate) fn new(name: &str) -> TempFile {
+        // The counter keeps tests running in parallel from replacing each other's files
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "wkhtmltopdf-rs-{}-{}-{}",
+            name,
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::write(&path, "").unwrap();
+        TempFile { path }
+    }
+
+    /// Create an executable script named after `name` that runs `body` with sh
+    pub(crate) fn script(name: &str, body: &str) -> TempFile {
+        let file = TempFile::new(name);
+        fs::write(&file.path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&file.path, fs::Permissions::from_mode(0o755)).unwrap();
+        file
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
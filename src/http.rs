@@ -0,0 +1,255 @@
+//! Minimal HTTP/1.1 plumbing for the loopback servers that run alongside a conversion
+use log::debug;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+// Largest request head accepted from wkhtmltopdf
+const MAX_HEAD: usize = 64 * 1024;
+
+// Handles a connection that was refused because the server was already handling too many
+type Reject = Box<dyn Fn(TcpStream) + Send>;
+
+/// A server handling each connection on its own thread, usually on an ephemeral loopback port
+///
+/// The server stops accepting connections when dropped.
+pub(crate) struct LoopbackServer {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl LoopbackServer {
+    pub(crate) fn start<F>(name: &str, handler: F) -> io::Result<LoopbackServer>
+    where
+        F: Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static,
+    {
+        // Only wkhtmltopdf connects to these during its conversion, so they aren't limited
+        LoopbackServer::listen(name, ("127.0.0.1", 0), None, handler)
+    }
+
+    /// Start a server listening on `addr` instead of an ephemeral loopback port
+    ///
+    /// At most `max_connections` are handled at once, and further connections are passed to `reject`
+    ///   (on the thread accepting connections) until one of them closes.
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn bind<A, F, R>(
+        name: &str,
+        addr: A,
+        max_connections: usize,
+        reject: R,
+        handler: F,
+    ) -> io::Result<LoopbackServer>
+    where
+        A: ToSocketAddrs,
+        F: Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static,
+        R: Fn(TcpStream) + Send + 'static,
+    {
+        let limit: (usize, Reject) = (max_connections, Box::new(reject));
+        LoopbackServer::listen(name, addr, Some(limit), handler)
+    }
+
+    fn listen<A, F>(
+        name: &str,
+        addr: A,
+        limit: Option<(usize, Reject)>,
+        handler: F,
+    ) -> io::Result<LoopbackServer>
+    where
+        A: ToSocketAddrs,
+        F: Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopped = stopped.clone();
+            let handler = Arc::new(handler);
+            let active = Arc::new(AtomicUsize::new(0));
+            let name = name.to_string();
+            thread::Builder::new()
+                .name(format!("wkhtmltox-{}", name))
+                .spawn(move || {
+                    for client in listener.incoming() {
+                        if stopped.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        let client = match client {
+                            Ok(client) => client,
+                            Err(err) => {
+                                debug!("{} failed to accept: {}", name, err);
+                                continue;
+                            }
+                        };
+                        if let Some((max_connections, ref reject)) = limit {
+                            if active.load(Ordering::SeqCst) >= max_connections {
+                                debug!("{} is handling {} connections", name, max_connections);
+                                reject(client);
+                                continue;
+                            }
+                        }
+                        let connection = Connection::open(&active);
+                        let handler = handler.clone();
+                        let name = name.clone();
+                        thread::spawn(move || {
+                            if let Err(err) = handler(client) {
+                                debug!("{} connection failed: {}", name, err);
+                            }
+                            drop(connection);
+                        });
+                    }
+                })?
+        };
+        debug!("{} listening on {}", name, addr);
+        Ok(LoopbackServer {
+            addr,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Block until the server stops accepting connections
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Counts a connection as active until dropped, even if its handler panics
+struct Connection {
+    active: Arc<AtomicUsize>,
+}
+
+impl Connection {
+    fn open(active: &Arc<AtomicUsize>) -> Connection {
+        active.fetch_add(1, Ordering::SeqCst);
+        Connection {
+            active: active.clone(),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for LoopbackServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the listener so it notices that it was stopped
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect(addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The request line and headers of a request
+pub(crate) struct Head {
+    pub(crate) method: String,
+    pub(crate) target: String,
+    pub(crate) version: String,
+    pub(crate) headers: Vec<String>,
+}
+
+impl Head {
+    /// The value of the first header named `name` (ignoring case)
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find_map(|line| {
+            let (header, value) = line.split_once(':')?;
+            if header.trim().eq_ignore_ascii_case(name) {
+                Some(value.trim())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Read up to the end of the request head, returning it and any bytes read past it
+///
+/// Returns `Ok(None)` if the request line is malformed.
+pub(crate) fn read_head(client: &mut TcpStream) -> io::Result<(Option<Head>, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let end = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+        let read = client.read(&mut chunk)?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    };
+    let body = buf.split_off(end + 4);
+    let head = String::from_utf8_lossy(&buf[..end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let head = match (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) {
+        (Some(method), Some(target), Some(version)) => Some(Head {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers: lines.map(str::to_string).collect(),
+        }),
+        _ => None,
+    };
+    Ok((head, body))
+}
+
+/// Respond with an empty body and close the connection
+pub(crate) fn respond(client: &mut TcpStream, status: &str) -> io::Result<()> {
+    write!(
+        client,
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    )
+}
+
+/// Respond with `body` and close the connection
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub(crate) fn respond_with(
+    client: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        client,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    client.write_all(body)?;
+    client.flush()
+}
@@ -0,0 +1,124 @@
+//! Screenshot capture modes for `ImageBuilder`
+use super::Crop;
+
+// Prefix of the console message used to report an element's bounding box back to the crate
+const BOUNDING_BOX_MARKER: &str = "wkhtmltopdf-rs-bbox:";
+
+/// The region of the page captured by `ImageBuilder`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum CaptureMode {
+    /// Capture the full length of the page (default)
+    #[default]
+    FullPage,
+    /// Capture only the "above the fold" region of a fixed size screen
+    Viewport { width: u32, height: u32 },
+    /// Capture the region occupied by the first element matching a CSS selector
+    Element(String),
+}
+
+impl CaptureMode {
+    /// The crop region implied by this mode, if it can be determined before rendering
+    pub(crate) fn viewport_crop(&self) -> Option<Crop> {
+        match *self {
+            CaptureMode::Viewport { width, height } => Some(Crop {
+                left: 0,
+                top: 0,
+                width: Some(width),
+                height: Some(height),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Script run after the page loads to log the bounding box of the first element matching `selector`
+pub(crate) fn bounding_box_script(selector: &str) -> String {
+    format!(
+        "(function() {{ \
+           var e = document.querySelector({}); \
+           if (!e) return; \
+           var r = e.getBoundingClientRect(); \
+           if (r.width <= 0 || r.height <= 0) return; \
+           console.log('{}' + [r.left + window.pageXOffset, r.top + window.pageYOffset, r.width, r.height].join(',')); \
+         }})();",
+        js_string(selector),
+        BOUNDING_BOX_MARKER
+    )
+}
+
+/// Parse the bounding box logged by `bounding_box_script` from a wkhtmltoimage warning
+///
+/// Coordinates are reported in CSS pixels, so they are scaled by `zoom` to match the rendered image.
+pub(crate) fn parse_bounding_box(warning: &str, zoom: f32) -> Option<Crop> {
+    let start = warning.find(BOUNDING_BOX_MARKER)? + BOUNDING_BOX_MARKER.len();
+    let values = warning[start..]
+        .trim()
+        .split(',')
+        .map(|v| v.trim().parse::<f32>().ok().map(|v| v * zoom))
+        .collect::<Option<Vec<_>>>()?;
+    match values[..] {
+        [left, top, width, height] => Some(Crop {
+            left: left.max(0.0).floor() as u32,
+            top: top.max(0.0).floor() as u32,
+            width: Some(width.ceil() as u32),
+            height: Some(height.ceil() as u32),
+        }),
+        _ => None,
+    }
+}
+
+// Quote `s` as a JavaScript string literal
+fn js_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            c if (c as u32) < 0x20 || c == '\u{2028}' || c == '\u{2029}' => {
+                quoted.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bounding_box_from_console_warning() {
+        let warning = "file:///tmp/page.html:1 wkhtmltopdf-rs-bbox:10.5,20,300.2,40";
+        let crop = parse_bounding_box(warning, 1.0).unwrap();
+        assert_eq!(
+            crop,
+            Crop {
+                left: 10,
+                top: 20,
+                width: Some(301),
+                height: Some(40)
+            }
+        );
+
+        let zoomed = parse_bounding_box(warning, 2.0).unwrap();
+        assert_eq!(zoomed.left, 21);
+        assert_eq!(zoomed.width, Some(601));
+    }
+
+    #[test]
+    fn ignores_unrelated_warnings() {
+        assert_eq!(parse_bounding_box("Javascript alert: hi", 1.0), None);
+        assert_eq!(parse_bounding_box("wkhtmltopdf-rs-bbox:1,2", 1.0), None);
+    }
+
+    #[test]
+    fn escapes_selector() {
+        let script = bounding_box_script("a[title=\"x\"]\n");
+        assert!(script.contains(r#"document.querySelector("a[title=\"x\"]\n")"#));
+    }
+}
@@ -14,31 +14,21 @@ use std::marker::PhantomData;
 use std::os::raw::{c_char, c_int};
 use std::sync::{mpsc, Arc, Mutex};
 use std::{ptr, slice};
-use wkhtmltox_sys::image::*;
 
 use super::{Error, ImageOutput, Result};
-
-enum WkhtmltoimageState {
-    // Wkhtmltoimage has not yet been initialized
-    New,
-    // Wkhtmltoimage backend is available for image generation
-    Ready,
-    // Wkhtmltoimage backend is busy, so attempts to init a `ImageGlobalSettings` instance will return `Error::Blocked`
-    Busy,
-    // Once dropped, wkthmltoimage cannot be used again for the life of this process
-    Dropped,
-}
+use crate::diagnostics::Diagnostics;
+use crate::error::classify_failure;
+use crate::state::{self, Backend};
+use crate::sys::{self, c_string, image::*, lock, lock_or_recover};
+use crate::telemetry;
 
 lazy_static! {
-    // Globally count wkhtmltoimage handles so we can safely init/deinit the underlying wkhtmltoimage singleton
-    static ref WKHTMLTOIMAGE_STATE: Mutex<WkhtmltoimageState> = Mutex::new(WkhtmltoimageState::New);
-    static ref WKHTMLTOIMAGE_INIT_THREAD: usize = thread_id::get();
-
     // Globally track callbacks since wkhtmltoimage doesn't allow injecting any userdata
     // The HashMap key is the converter's raw pointer cast as usize, so we can have unique callbacks per converter
     static ref FINISHED_CALLBACKS: Mutex<HashMap<usize, Box<dyn FnMut(i32) + 'static + Send>>> = Mutex::new(HashMap::new());
     static ref ERROR_CALLBACKS: Mutex<HashMap<usize, Box<dyn FnMut(String) + 'static + Send>>> = Mutex::new(HashMap::new());
-    // TODO: 3 more callback types
+    static ref WARNING_CALLBACKS: Mutex<HashMap<usize, Box<dyn FnMut(String) + 'static + Send>>> = Mutex::new(HashMap::new());
+    // TODO: 2 more callback types
 }
 
 /// Handles initialization and deinitialization of wkhtmltoimage
@@ -64,8 +54,9 @@ pub struct ImageGlobalSettings {
 /// Safe wrapper for working with the wkhtmltoimage converter
 pub struct ImageConverter {
     converter: *mut wkhtmltoimage_converter,
-    // imageGlobalSettings::drop also manages wkhtmktoimage_deinit, take ownership to delay drop
+    // ImageGlobalSettings::drop also releases the backend for other conversions, take ownership to delay drop
     _global: ImageGlobalSettings,
+    warning_callback: Arc<Mutex<Option<Box<dyn FnMut(String) + 'static + Send>>>>,
 }
 
 /// Initializes wkhtmltoimage
@@ -77,24 +68,18 @@ pub struct ImageConverter {
 ///
 /// Subsequent attempts to initialize wkhtmltoimage will return `Error:IllegalInit`
 pub fn image_init() -> Result<ImageGuard> {
-    let mut wk_state = WKHTMLTOIMAGE_STATE.lock().unwrap();
-    match *wk_state {
-        WkhtmltoimageState::New => {
-            debug!("wkhtmltoimage_init graphics=0");
-            let success = unsafe { wkhtmltoimage_init(0) == 1 };
-            if success {
-                *wk_state = WkhtmltoimageState::Ready;
-                // first eval of the lazy static - effectively stores the thread id
-                let _ = *WKHTMLTOIMAGE_INIT_THREAD;
-            } else {
-                error!("failed to initialize wkhtmltoimage");
-            }
-            Ok(ImageGuard {
-                _private: PhantomData,
-            })
+    sys::load()?;
+    state::init(Backend::Image, || {
+        debug!("wkhtmltoimage_init graphics=0");
+        let success = unsafe { wkhtmltoimage_init(0) == 1 };
+        if !success {
+            error!("failed to initialize wkhtmltoimage");
         }
-        _ => Err(Error::IllegalInit),
-    }
+        success
+    })?;
+    Ok(ImageGuard {
+        _private: PhantomData,
+    })
 }
 
 impl ImageGlobalSettings {
@@ -102,30 +87,14 @@ impl ImageGlobalSettings {
     ///
     /// This may only be called after `image_init` has successfully initialized wkhtmltoimage
     pub fn new() -> Result<ImageGlobalSettings> {
-        if *WKHTMLTOIMAGE_INIT_THREAD != thread_id::get() {
-            // A lot of QT functionality expects to run from the same thread that it was first initialized on
-            return Err(Error::ThreadMismatch(
-                *WKHTMLTOIMAGE_INIT_THREAD,
-                thread_id::get(),
-            ));
-        }
-
-        let mut wk_state = WKHTMLTOIMAGE_STATE.lock().unwrap();
-        match *wk_state {
-            WkhtmltoimageState::New => Err(Error::NotInitialized),
-            WkhtmltoimageState::Dropped => Err(Error::NotInitialized),
-            WkhtmltoimageState::Busy => Err(Error::Blocked),
-            WkhtmltoimageState::Ready => {
-                debug!("wkhtmltoimage_create_global_settings");
-                let gs = unsafe { wkhtmltoimage_create_global_settings() };
-                // TODO: is it possible to delay setting Busy until convert is called?
-                *wk_state = WkhtmltoimageState::Busy;
-                Ok(ImageGlobalSettings {
-                    global_settings: gs,
-                    needs_delete: true,
-                })
-            }
-        }
+        state::acquire(Backend::Image)?;
+        debug!("wkhtmltoimage_create_global_settings");
+        let gs = unsafe { wkhtmltoimage_create_global_settings() };
+        // TODO: is it possible to delay acquiring the backend until convert is called?
+        Ok(ImageGlobalSettings {
+            global_settings: gs,
+            needs_delete: true,
+        })
     }
 
     /// Set a global setting for the wkhtmltoimage instance
@@ -134,9 +103,8 @@ impl ImageGlobalSettings {
     ///
     /// Unsafe as it may cause undefined behavior (generally segfault) if name or value are not valid
     pub unsafe fn set(&mut self, name: &str, value: &str) -> Result<()> {
-        let c_name = CString::new(name).expect("setting name may not contain interior null bytes");
-        let c_value =
-            CString::new(value).expect("setting value may not contain interior null bytes");
+        let c_name = c_string("setting name", name)?;
+        let c_value = c_string("setting value", value)?;
 
         debug!("wkhtmltoimage_set_global_setting {}='{}'", name, value);
         match wkhtmltoimage_set_global_setting(
@@ -144,39 +112,49 @@ impl ImageGlobalSettings {
             c_name.as_ptr(),
             c_value.as_ptr(),
         ) {
-            0 => Err(Error::GlobalSettingFailure(name.into(), value.into())),
             1 => Ok(()),
-            _ => unreachable!("wkhtmltoimage_set_global_setting returned invalid value"),
+            _ => Err(Error::GlobalSettingFailure(name.into(), value.into())),
         }
     }
 
     /// calls wkhtmltoimage_create_converter which consumes global_settings
     ///   and thus we no longer need concern ourselves with deleting it
-    pub fn create_converter(mut self, data: Option<&str>) -> ImageConverter {
-        debug!("wkhtmltoimage_create_converter");
+    ///
+    /// Returns `Error::InvalidInput` if `data` contains a NUL byte.
+    pub fn create_converter(mut self, data: Option<&str>) -> Result<ImageConverter> {
         let c_data = match data {
             None => CString::default(),
-            Some(d) => CString::new(d).expect("data may not contain interior null bytes"),
+            Some(d) => c_string("HTML", d)?,
         };
+        debug!("wkhtmltoimage_create_converter");
         let converter =
             unsafe { wkhtmltoimage_create_converter(self.global_settings, c_data.as_ptr()) };
         self.needs_delete = false;
 
-        ImageConverter {
+        Ok(ImageConverter {
             converter,
             _global: self,
-        }
+            warning_callback: Arc::new(Mutex::new(None)),
+        })
     }
 }
 
 impl ImageConverter {
+    /// Call the warning function when a warning is issued
+    pub fn set_warning_callback(
+        &mut self,
+        on_warning: Option<Box<dyn FnMut(String) + 'static + Send>>,
+    ) {
+        *lock_or_recover(&self.warning_callback) = on_warning;
+    }
+
     /// Performs the HTML to image conversion
     ///
     /// This method does not do any additional allocations of the output,
     ///   so the `ImageConverter` will be owned by `ImageOutput` so that
     ///   it is not dropped until the `ImageOutput` is dropped.
     pub fn convert<'a>(self) -> Result<ImageOutput<'a>> {
-        let rx = self.setup_callbacks();
+        let rx = self.setup_callbacks()?;
         debug!("wkhtmltoimage_convert");
         let success = unsafe { wkhtmltoimage_convert(self.converter) == 1 };
         self.remove_callbacks();
@@ -188,14 +166,24 @@ impl ImageConverter {
                 let bytes = wkhtmltoimage_get_output(self.converter, &mut buf_ptr) as usize;
                 let image_slice = slice::from_raw_parts(buf_ptr, bytes);
                 Ok(ImageOutput {
-                    data: image_slice,
-                    _converter: self,
+                    data: image_slice.into(),
+                    pos: 0,
+                    _converter: Some(self),
+                    diagnostics: Diagnostics::default(),
+                    #[cfg(feature = "image-processing")]
+                    quality: super::raster::DEFAULT_JPEG_QUALITY,
                 })
             }
         } else {
-            match rx.recv().expect("sender disconnected") {
-                Ok(_) => unreachable!("failed without errors"),
-                Err(err) => Err(err),
+            match rx.recv() {
+                Ok(Err(err)) => Err(err),
+                Ok(Ok(())) => Err(Error::ConversionFailed(
+                    "wkhtmltoimage failed without reporting an error".into(),
+                )),
+                // The finished callback was never called
+                Err(_) => Err(Error::ConversionFailed(
+                    "wkhtmltoimage did not report the result of the conversion".into(),
+                )),
             }
         }
     }
@@ -203,38 +191,49 @@ impl ImageConverter {
     fn remove_callbacks(&self) {
         let id = self.converter as usize;
 
-        let _ = ERROR_CALLBACKS.lock().unwrap().remove(&id);
-        let _ = FINISHED_CALLBACKS.lock().unwrap().remove(&id);
+        let _ = lock_or_recover(&WARNING_CALLBACKS).remove(&id);
+        let _ = lock_or_recover(&ERROR_CALLBACKS).remove(&id);
+        let _ = lock_or_recover(&FINISHED_CALLBACKS).remove(&id);
     }
 
-    fn setup_callbacks(&self) -> mpsc::Receiver<Result<()>> {
+    fn setup_callbacks(&self) -> Result<mpsc::Receiver<Result<()>>> {
         let (tx, rx) = mpsc::channel();
         let errors = Arc::new(Mutex::new(Vec::new()));
 
         let tx_finished = tx;
         let errors_finished = errors.clone();
         let on_finished = move |i| {
-            let errors = errors_finished.lock().unwrap();
+            let errors = lock_or_recover(&errors_finished);
 
             let res = match i {
                 1 => Ok(()),
-                _ => Err(Error::ConversionFailed(errors.join(", "))),
+                _ => Err(classify_failure(errors.iter())
+                    .unwrap_or_else(|| Error::ConversionFailed(errors.join(", ")))),
             };
             let _ = tx_finished.send(res);
         };
 
         let on_error = move |err| {
-            let mut errors = errors.lock().unwrap();
+            let mut errors = lock_or_recover(&errors);
             errors.push(err);
         };
 
-        // Insert into our lazy static callbacks
+        let warning_cb = self.warning_callback.clone();
+        let on_warning = move |warn| {
+            if let Some(cb) = lock_or_recover(&warning_cb).as_mut() {
+                cb(warn);
+            }
+        };
+
+        // Insert into our lazy static callbacks, locking all of them first so none are left behind on error
         {
             let id = self.converter as usize;
-            let mut finished_callbacks = FINISHED_CALLBACKS.lock().unwrap();
+            let mut finished_callbacks = lock(&FINISHED_CALLBACKS, "finished callbacks")?;
+            let mut error_callbacks = lock(&ERROR_CALLBACKS, "error callbacks")?;
+            let mut warning_callbacks = lock(&WARNING_CALLBACKS, "warning callbacks")?;
             finished_callbacks.insert(id, Box::new(on_finished));
-            let mut error_callbacks = ERROR_CALLBACKS.lock().unwrap();
             error_callbacks.insert(id, Box::new(on_error));
+            warning_callbacks.insert(id, Box::new(on_warning));
         }
 
         unsafe {
@@ -242,37 +241,38 @@ impl ImageConverter {
             wkhtmltoimage_set_finished_callback(self.converter, Some(finished_callback));
             debug!("wkhtmltoimage_set_error_callback");
             wkhtmltoimage_set_error_callback(self.converter, Some(error_callback));
+            debug!("wkhtmltoimage_set_warning_callback");
+            wkhtmltoimage_set_warning_callback(self.converter, Some(warning_callback));
             // wkhtmltoimage_set_progress_changed_callback(self.converter, Some(progress_changed));
-            // wkhtmltoimage_set_phase_changed_callback(self.converter, Some(phase_changed));
-            // wkhtmltoimage_set_warning_callback(self.converter, Some(warning_cb));
+            debug!("wkhtmltoimage_set_phase_changed_callback");
+            wkhtmltoimage_set_phase_changed_callback(self.converter, Some(phase_changed));
         }
 
-        rx
+        Ok(rx)
     }
 }
 
-impl Drop for ImageConverter {
+impl Drop for ImageGlobalSettings {
     fn drop(&mut self) {
-        debug!("wkhtmltoimage_destroy_converter");
-        unsafe { wkhtmltoimage_destroy_converter(self.converter) }
+        // wkhtmltoimage has no function for destroying unused global settings
+        debug!("wkhtmltoimage ready again");
+        state::release();
     }
 }
 
-// TODO: is it possible to revert to ready after convert finishes?
-impl<'a> Drop for ImageOutput<'a> {
+impl Drop for ImageConverter {
     fn drop(&mut self) {
-        let mut wk_state = WKHTMLTOIMAGE_STATE.lock().unwrap();
-        debug!("wkhtmltoimage ready again");
-        *wk_state = WkhtmltoimageState::Ready;
+        debug!("wkhtmltoimage_destroy_converter");
+        unsafe { wkhtmltoimage_destroy_converter(self.converter) }
     }
 }
 
 impl Drop for ImageGuard {
     fn drop(&mut self) {
-        let mut wk_state = WKHTMLTOIMAGE_STATE.lock().unwrap();
-        debug!("wkhtmltoimage_deinit");
-        let success = unsafe { wkhtmltoimage_deinit() == 1 };
-        *wk_state = WkhtmltoimageState::Dropped;
+        let success = state::deinit(Backend::Image, || {
+            debug!("wkhtmltoimage_deinit");
+            unsafe { wkhtmltoimage_deinit() == 1 }
+        });
         if !success {
             warn!("Failed to deinitialize wkhtmltoimage")
         }
@@ -283,7 +283,7 @@ unsafe extern "C" fn finished_callback(converter: *mut wkhtmltoimage_converter,
     let id = converter as usize;
     {
         // call and remove this converter's FINISHED_CALLBACK
-        let mut callbacks = FINISHED_CALLBACKS.lock().unwrap();
+        let mut callbacks = lock_or_recover(&FINISHED_CALLBACKS);
         if let Some(mut cb) = callbacks.remove(&id) {
             cb(val as i32);
         }
@@ -295,7 +295,7 @@ unsafe extern "C" fn error_callback(
     msg_ptr: *const c_char,
 ) {
     let cstr = CStr::from_ptr(msg_ptr);
-    let mut callbacks = ERROR_CALLBACKS.lock().unwrap();
+    let mut callbacks = lock_or_recover(&ERROR_CALLBACKS);
     let id = converter as usize;
     let msg = cstr.to_string_lossy().into_owned();
     match callbacks.get_mut(&id) {
@@ -304,17 +304,28 @@ unsafe extern "C" fn error_callback(
     }
 }
 
-// unsafe extern fn warning_cb(_converter: *mut wkhtmltoimage_converter, msg_ptr: *const c_char) {
-//     let msg = CStr::from_ptr(msg_ptr).to_string_lossy();
-//     println!("Warning: {}", msg);
-// }
+unsafe extern "C" fn warning_callback(
+    converter: *mut wkhtmltoimage_converter,
+    msg_ptr: *const c_char,
+) {
+    let cstr = CStr::from_ptr(msg_ptr);
+    let mut callbacks = lock_or_recover(&WARNING_CALLBACKS);
+    let id = converter as usize;
+    let msg = cstr.to_string_lossy().into_owned();
+    match callbacks.get_mut(&id) {
+        Some(cb) => cb(msg),
+        None => println!("No callback for warning: {}", msg),
+    }
+}
 
 // unsafe extern fn progress_changed(_converter: *mut wkhtmltoimage_converter, val: c_int) {
 //     println!("{:3}", val);
 // }
 
-// unsafe extern fn phase_changed(converter: *mut wkhtmltoimage_converter) {
-//     let phase = wkhtmltoimage_current_phase(converter);
-//     let desc = wkhtmltoimage_phase_description(converter, phase);
-// 	println!("Phase: {}", CStr::from_ptr(desc).to_string_lossy());
-// }
+unsafe extern "C" fn phase_changed(converter: *mut wkhtmltoimage_converter) {
+    let phase = wkhtmltoimage_current_phase(converter);
+    let desc = wkhtmltoimage_phase_description(converter, phase);
+    if !desc.is_null() {
+        telemetry::phase("image", phase, &CStr::from_ptr(desc).to_string_lossy());
+    }
+}
@@ -7,6 +7,7 @@
 //!
 //! ## Example
 //! ```no_run
+//! # #[cfg(any(feature = "native", feature = "dynamic"))] {
 //! use wkhtmltopdf::*;
 //!
 //! let image_app = ImageApplication::new().expect("Failed to init image application");
@@ -16,6 +17,7 @@
 //!     .expect("failed to build image");
 //!
 //! imageout.save("foo.png").expect("failed to save foo.png");
+//! # }
 //! ```
 //!
 //! Other examples can be seen in the documentation for
@@ -28,33 +30,80 @@
 //! Addtionally, the [`lowlevel`](lowlevel/index.html) module provides safe abstractions
 //!   that allow full configuration of wkhtmltoimage.
 
+use crate::assets::AssetBundle;
+use crate::cancellation::{CancellationToken, Limits};
+use crate::diagnostics::LoadErrorPolicy;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::diagnostics::{Diagnostics, FailedLoad};
 use crate::error::*;
+use crate::inline::{inline_resources, Resources};
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::isolated;
+#[cfg(all(feature = "markdown", any(feature = "native", feature = "dynamic")))]
+use crate::markdown;
+use crate::sandbox::{file_access_settings, Sandbox};
+use crate::session::Session;
+use crate::source::Source;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::source::{decode_html, resolve_path, Document, TempHtml};
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::sys::lock_or_recover;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::telemetry::Conversion;
+mod capture;
+#[cfg(any(feature = "native", feature = "dynamic"))]
 pub mod lowlevel;
+#[cfg(feature = "image-processing")]
+pub mod process;
+mod raster;
+pub use capture::CaptureMode;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use log::debug;
 use log::warn;
+#[cfg(any(feature = "native", feature = "dynamic"))]
 use lowlevel::*;
+#[cfg(feature = "image-processing")]
+pub use process::EncodeFormat;
 use std::borrow::Cow;
 use std::collections::HashMap;
+#[cfg(any(feature = "native", feature = "dynamic"))]
 use std::fs::File;
+#[cfg(any(feature = "native", feature = "dynamic"))]
 use std::io::{self, Read};
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use std::mem;
 use std::path::Path;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(any(feature = "native", feature = "dynamic"))]
 use url::Url;
 
 /// Generated image output
+#[cfg(any(feature = "native", feature = "dynamic"))]
 pub struct ImageOutput<'a> {
-    // slice of the data owned by the wkhtmltoimage_converter
-    data: &'a [u8],
-    // Don't drop the converter until data lifetime ends
-    _converter: ImageConverter,
+    // slice of the data owned by the wkhtmltoimage_converter, or post-processed data
+    data: Cow<'a, [u8]>,
+    // position of the next read within data
+    pos: usize,
+    // Don't drop the converter until data lifetime ends (there is none for an isolated worker's output)
+    _converter: Option<ImageConverter>,
+    diagnostics: Diagnostics,
+    // JPEG quality used when re-encoding processed data
+    #[cfg(feature = "image-processing")]
+    quality: u8,
 }
 
 /// Structure for initializing the underlying wkhtmltoimage
 ///
 /// This is effective a wrapper around `ImageGuard` that provides
 /// a method for instantiating a builder
+#[cfg(any(feature = "native", feature = "dynamic"))]
 pub struct ImageApplication {
     _guard: ImageGuard,
 }
 
+#[cfg(any(feature = "native", feature = "dynamic"))]
 impl ImageApplication {
     /// Initializes Wkhtmltoimage
     ///
@@ -62,6 +111,10 @@ impl ImageApplication {
     /// Wkhtmltoimage may only be initialized once per process, and
     /// and all image generation must happen from the same thread that initialized wkhtmltoimage.
     ///
+    /// Wkhtmltopdf and wkhtmltoimage share the same Qt instance, so they must be initialized
+    /// on the same thread and only one PDF or image conversion may run at a time.
+    /// Use [`Wkhtmltox`](struct.Wkhtmltox.html) to initialize both together.
+    ///
     /// Subsequent attempts to initialize wkhtmltoimage will return `Error:IllegalInit`.
     pub fn new() -> Result<ImageApplication> {
         image_init().map(|guard| ImageApplication { _guard: guard })
@@ -73,7 +126,7 @@ impl ImageApplication {
     /// [basic limitation of wkhtmltoimage](https://github.com/wkhtmltoimage/wkhtmltoimage/issues/1711).
     /// Parallel execution is currently only possible by spawning multiple processes.
     pub fn builder(&self) -> ImageBuilder {
-        ImageBuilder { gs: HashMap::new() }
+        ImageBuilder::new()
     }
 }
 
@@ -98,13 +151,53 @@ impl ImageFormat {
     }
 }
 
+/// Region of the rendered image to keep
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Crop {
+    /// Pixels to skip from the left edge
+    pub left: u32,
+    /// Pixels to skip from the top edge
+    pub top: u32,
+    /// Width of the region (default is the remaining width)
+    pub width: Option<u32>,
+    /// Height of the region (default is the remaining height)
+    pub height: Option<u32>,
+}
+
 /// High-level builder for generating images (initialized from `ImageApplication`)
 #[derive(Clone)]
 pub struct ImageBuilder {
     gs: HashMap<&'static str, Cow<'static, str>>,
+    crop: Option<Crop>,
+    capture: CaptureMode,
+    limits: Limits,
+    sandbox: Option<Sandbox>,
+    assets: Option<AssetBundle>,
+    resources: Option<Resources>,
+    #[cfg(feature = "markdown")]
+    markdown_stylesheet: Option<String>,
+    root_dir: Option<String>,
+    // Files written for wkhtmltoimage to load, e.g. documents read by `build_from_reader`
+    generated_files: Vec<String>,
 }
 
 impl ImageBuilder {
+    pub(crate) fn new() -> ImageBuilder {
+        ImageBuilder {
+            gs: HashMap::new(),
+            crop: None,
+            capture: CaptureMode::FullPage,
+            limits: Limits::default(),
+            sandbox: None,
+            assets: None,
+            resources: None,
+            #[cfg(feature = "markdown")]
+            markdown_stylesheet: None,
+            root_dir: None,
+            generated_files: Vec::new(),
+        }
+    }
+
     /// The with of the screen used to render in pixels, e.g "800"
     pub fn screen_width(&mut self, screen_width: u32) -> &mut ImageBuilder {
         self.gs
@@ -112,32 +205,72 @@ impl ImageBuilder {
         self
     }
 
-    /* Pending https://github.com/wkhtmltopdf/wkhtmltopdf/issues/4714
-    /// The with of the screen used to render in pixels, e.g "800"
+    /// The height of the screen used to render in pixels (default is the full height of the page)
+    pub fn screen_height(&mut self, screen_height: u32) -> &mut ImageBuilder {
+        self.gs
+            .insert("screenHeight", screen_height.to_string().into());
+        self
+    }
+
+    /// Extend `screen_width` to fit unbreakable content (default true)
+    pub fn smart_width(&mut self, smart_width: bool) -> &mut ImageBuilder {
+        self.gs.insert("smartWidth", smart_width.to_string().into());
+        self
+    }
+
+    /// Zoom factor used when rendering the page (default 1.0)
+    pub fn zoom(&mut self, zoom: f32) -> &mut ImageBuilder {
+        self.gs.insert("load.zoomFactor", zoom.to_string().into());
+        self
+    }
+
+    /// Number of pixels to crop from the left edge of the rendered image
+    ///
+    /// Note: the wkhtmltoimage crop settings are ignored by libwkhtmltox due to
+    ///   [this upstream issue](https://github.com/wkhtmltopdf/wkhtmltopdf/issues/4714),
+    ///   so PNG, JPG, and BMP output is cropped by this crate after rendering.
+    ///   SVG output still relies on the upstream crop settings.
     pub fn crop_left(&mut self, crop_left: u32) -> &mut ImageBuilder {
-        self.gs.insert("crop.left", crop_left.to_string().into());
+        self.crop.get_or_insert_with(Crop::default).left = crop_left;
         self
     }
 
-    /// The with of the screen used to render in pixels, e.g "800"
+    /// Number of pixels to crop from the top edge of the rendered image
+    ///
+    /// See [`crop_left`](#method.crop_left) for how cropping is applied.
     pub fn crop_top(&mut self, crop_top: u32) -> &mut ImageBuilder {
-        self.gs.insert("crop.top", crop_top.to_string().into());
+        self.crop.get_or_insert_with(Crop::default).top = crop_top;
         self
     }
 
-    /// The with of the screen used to render in pixels, e.g "800"
+    /// Width in pixels of the cropped image (default is the remaining width)
+    ///
+    /// See [`crop_left`](#method.crop_left) for how cropping is applied.
     pub fn crop_width(&mut self, crop_width: u32) -> &mut ImageBuilder {
-        self.gs.insert("crop.width", crop_width.to_string().into());
+        self.crop.get_or_insert_with(Crop::default).width = Some(crop_width);
         self
     }
 
-    /// The with of the screen used to render in pixels, e.g "800"
+    /// Height in pixels of the cropped image (default is the remaining height)
+    ///
+    /// See [`crop_left`](#method.crop_left) for how cropping is applied.
     pub fn crop_height(&mut self, crop_height: u32) -> &mut ImageBuilder {
-        self.gs
-            .insert("crop.height", crop_height.to_string().into());
+        self.crop.get_or_insert_with(Crop::default).height = Some(crop_height);
+        self
+    }
+
+    /// The region of the page to capture (default `CaptureMode::FullPage`)
+    ///
+    /// `CaptureMode::Viewport` sets the screen width and crops the image to the viewport height.
+    /// `CaptureMode::Element` renders the full page, then crops it to the bounding box
+    ///   of the first element matching the selector as computed by the page itself
+    ///   (this requires JavaScript to be enabled and is not supported for SVG output).
+    ///
+    /// Capture modes other than `FullPage` take precedence over the `crop_*` settings.
+    pub fn capture_mode(&mut self, capture: CaptureMode) -> &mut ImageBuilder {
+        self.capture = capture;
         self
     }
-    */
 
     /// JPEG image compression quality in percentage (default 94). Only used
     /// when format is 'jpg'.
@@ -160,6 +293,86 @@ impl ImageBuilder {
         self
     }
 
+    /// Maximum time to spend on a conversion before returning `Error::Timeout` (default none)
+    ///
+    /// Since wkhtmltopdf can't abort a conversion, in-process conversions (e.g. `build_from_html`)
+    ///   with a timeout are run by an isolated worker that is killed when the timeout is reached.
+    ///   This requires calling [`init_isolated_worker`](fn.init_isolated_worker.html) at the start
+    ///   of `main`, otherwise they return `Error::WorkerUnavailable`. See the
+    ///   [`cancellation`](cancellation/index.html) module for how each backend enforces the timeout.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut ImageBuilder {
+        self.limits.timeout = Some(timeout);
+        self
+    }
+
+    /// Return `Error::Cancelled` if `token` is cancelled before the conversion finishes
+    ///
+    /// In-process conversions with a token are run by an isolated worker, as described for `timeout`.
+    pub fn cancellation_token(&mut self, token: CancellationToken) -> &mut ImageBuilder {
+        self.limits.token = Some(token);
+        self
+    }
+
+    /// How to handle the page failing to load (default `LoadErrorPolicy::Abort`)
+    ///
+    /// Pages that are skipped or ignored are reported in the output's
+    ///   [`diagnostics`](diagnostics/index.html) instead of failing the conversion.
+    pub fn load_error_policy(&mut self, policy: LoadErrorPolicy) -> &mut ImageBuilder {
+        self.gs
+            .insert("load.loadErrorHandling", policy.value().into());
+        self
+    }
+
+    /// How to handle media (e.g. images) failing to load (default `LoadErrorPolicy::Ignore`)
+    ///
+    /// Media that is skipped or ignored is reported in the output's diagnostics.
+    pub fn media_load_error_policy(&mut self, policy: LoadErrorPolicy) -> &mut ImageBuilder {
+        self.gs
+            .insert("load.mediaLoadErrorHandling", policy.value().into());
+        self
+    }
+
+    /// Restrict the network and local file access of the page (default unrestricted)
+    ///
+    /// See the [`sandbox`](sandbox/index.html) module for how the sandbox is enforced.
+    pub fn sandbox(&mut self, sandbox: &Sandbox) -> &mut ImageBuilder {
+        self.sandbox = Some(sandbox.clone());
+        self
+    }
+
+    /// Serve `assets` to HTML sources, resolving their relative links (default none)
+    ///
+    /// See the [`assets`](assets/index.html) module for how the assets are served.
+    pub fn assets(&mut self, assets: &AssetBundle) -> &mut ImageBuilder {
+        self.assets = Some(assets.clone());
+        self
+    }
+
+    /// Embed the resources referenced by HTML sources from `resources` before rendering (default none)
+    ///
+    /// See the [`inline`](inline/index.html) module for which references are embedded.
+    pub fn inline_resources(&mut self, resources: &Resources) -> &mut ImageBuilder {
+        self.resources = Some(resources.clone());
+        self
+    }
+
+    /// The stylesheet of documents built with `build_from_markdown` (default `markdown::DEFAULT_STYLESHEET`)
+    #[cfg(feature = "markdown")]
+    pub fn markdown_stylesheet<S: Into<String>>(&mut self, css: S) -> &mut ImageBuilder {
+        self.markdown_stylesheet = Some(css.into());
+        self
+    }
+
+    /// Only build from local files under `root` (default unrestricted)
+    ///
+    /// Relative paths given to `build_from_path` are resolved against `root`, and paths that resolve
+    ///   outside of it (e.g. via `..` or symlinks) return `Error::PathNotAllowed`. The page may load
+    ///   local files (e.g. its stylesheets and images) from under `root`, but no other local files.
+    pub fn root_dir<P: AsRef<Path>>(&mut self, root: P) -> &mut ImageBuilder {
+        self.root_dir = Some(root.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
     /// Set a global setting not explicitly supported by the ImageBuilder
     ///
     /// Valid settings can be found [here](https://wkhtmltopdf.org/libwkhtmltox/pagesettings.html#pageImageGlobal)
@@ -177,6 +390,147 @@ impl ImageBuilder {
         self
     }
 
+    // The settings passed to wkhtmltoimage, including those implied by the capture mode and sandbox
+    //   and, if `include_crop` is set, the crop region
+    pub(crate) fn settings(&self, include_crop: bool) -> Vec<(&'static str, Cow<'_, str>)> {
+        let mut settings: Vec<(&'static str, Cow<'_, str>)> = self
+            .gs
+            .iter()
+            .map(|(name, val)| (*name, Cow::Borrowed(val.as_ref())))
+            .collect();
+        match self.capture {
+            CaptureMode::FullPage => {}
+            CaptureMode::Viewport { width, .. } => {
+                settings.push(("screenWidth", width.to_string().into()));
+                settings.push(("smartWidth", "false".into()));
+            }
+            CaptureMode::Element(ref selector) => {
+                // The page reports the element's bounding box to the warning callback via console.log
+                settings.push(("load.debugJavascript", "true".into()));
+                settings.push((
+                    "load.runScript.append",
+                    capture::bounding_box_script(selector).into(),
+                ));
+            }
+        }
+        let file_access = file_access_settings(
+            self.sandbox.as_ref(),
+            self.root_dir.as_deref(),
+            &self.generated_files,
+        );
+        settings.extend(
+            file_access
+                .into_iter()
+                .map(|(name, val)| (name, Cow::Borrowed(val))),
+        );
+        if let (Some(crop), true) = (self.crop_region(), include_crop) {
+            settings.push(("crop.left", crop.left.to_string().into()));
+            settings.push(("crop.top", crop.top.to_string().into()));
+            if let Some(width) = crop.width {
+                settings.push(("crop.width", width.to_string().into()));
+            }
+            if let Some(height) = crop.height {
+                settings.push(("crop.height", height.to_string().into()));
+            }
+        }
+        settings
+    }
+
+    pub(crate) fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    // Starts the servers used by a conversion of `source`, which is only served assets if it is HTML
+    pub(crate) fn start_session(&self, source: &Source) -> Result<Session> {
+        let assets = match source {
+            Source::Html(_) => self.assets.as_ref(),
+            _ => None,
+        };
+        Ok(Session::start(self.sandbox.as_ref(), assets)?)
+    }
+
+    // Checks that a `Path` source exists and is within the root directory,
+    //   and embeds the resources of an `Html` source
+    pub(crate) fn resolve_source(&self, source: &Source) -> Result<Source> {
+        match source {
+            Source::Html(html) => Ok(Source::Html(self.prepare_html(html)?.into_owned())),
+            source => source.resolve(self.root_dir.as_deref(), "in", Error::GlobalSettingFailure),
+        }
+    }
+
+    // Embeds the resources referenced by `html` if `inline_resources` was set
+    pub(crate) fn prepare_html<'a>(&self, html: &'a str) -> Result<Cow<'a, str>> {
+        match self.resources {
+            Some(ref resources) => Ok(Cow::Owned(inline_resources(html, resources)?)),
+            None => Ok(Cow::Borrowed(html)),
+        }
+    }
+
+    pub(crate) fn is_svg(&self) -> bool {
+        matches!(self.gs.get("fmt"), Some(fmt) if fmt == "svg")
+    }
+
+    // The crop region that can be determined before rendering
+    fn crop_region(&self) -> Option<Crop> {
+        match self.capture {
+            CaptureMode::FullPage => self.crop,
+            CaptureMode::Viewport { .. } => self.capture.viewport_crop(),
+            CaptureMode::Element(_) => None,
+        }
+    }
+
+    // Zoom factor used to render the page
+    fn zoom_factor(&self) -> f32 {
+        self.gs
+            .get("load.zoomFactor")
+            .and_then(|z| z.parse().ok())
+            .unwrap_or(1.0)
+    }
+
+    // The bounding box of the captured element if it was reported by this wkhtmltoimage warning
+    pub(crate) fn element_bounds(&self, warning: &str) -> Option<Crop> {
+        match self.capture {
+            CaptureMode::Element(_) => capture::parse_bounding_box(warning, self.zoom_factor()),
+            _ => None,
+        }
+    }
+
+    // Applies the transformations that wkhtmltoimage can't be trusted to perform itself,
+    //   returning `None` if the rendered image can be used as-is
+    pub(crate) fn post_process(
+        &self,
+        data: &[u8],
+        element: Option<Crop>,
+    ) -> Result<Option<Vec<u8>>> {
+        if self.is_svg() {
+            if let CaptureMode::Element(_) = self.capture {
+                warn!("element capture is not supported for SVG output");
+            }
+            return Ok(None);
+        }
+        let crop = match self.capture {
+            CaptureMode::Element(ref selector) => {
+                Some(element.ok_or_else(|| Error::ElementNotFound(selector.clone()))?)
+            }
+            _ => self.crop_region(),
+        };
+        match crop {
+            Some(crop) => Ok(Some(raster::crop(data, &crop, self.jpeg_quality())?)),
+            None => Ok(None),
+        }
+    }
+
+    // The quality of JPEG output, which is also used when re-encoding it
+    fn jpeg_quality(&self) -> u8 {
+        self.gs
+            .get("imageQuality")
+            .and_then(|q| q.parse().ok())
+            .unwrap_or(raster::DEFAULT_JPEG_QUALITY)
+    }
+}
+
+#[cfg(any(feature = "native", feature = "dynamic"))]
+impl ImageBuilder {
     /// Build an image using a URL as the source input
     ///
     /// ## Example
@@ -192,12 +546,7 @@ impl ImageBuilder {
     /// This method should be safe if using only safe builder methods, or if usage
     /// of `unsafe` methods (e.g. adding custom settings) is properly handled by wkhtmltoimage
     pub fn build_from_url<'a, 'b>(&'a mut self, url: &Url) -> Result<ImageOutput<'b>> {
-        let mut global = self.global_settings()?;
-        unsafe {
-            global.set("in", &*url.as_str())?;
-        }
-        let converter = global.create_converter(None);
-        converter.convert()
+        self.build("url", url.as_str(), None)
     }
 
     /// Build an image using the provided HTML from a local file
@@ -218,23 +567,13 @@ impl ImageBuilder {
         &'a mut self,
         path: P,
     ) -> Result<ImageOutput<'b>> {
-        let path = path.as_ref();
-        // Check that the file exists - otherwise wkhtmltopdf will silently fall back
-        // to trying it as a URL:
-        // https://github.com/wkhtmltopdf/wkhtmltopdf/blob/5fb6a6e479409c0a270e56d852a5a9e7b2b7651b/src/lib/multipageloader.cc#L690
-        if !path.is_file() {
-            warn!("the file {} does not exist", path.to_string_lossy());
-            return Err(Error::GlobalSettingFailure(
-                "in".to_string(),
-                path.to_string_lossy().to_string(),
-            ));
-        }
-        let mut global = self.global_settings()?;
-        unsafe {
-            global.set("in", &path.to_string_lossy())?;
-        }
-        let converter = global.create_converter(None);
-        converter.convert()
+        let path = resolve_path(
+            self.root_dir.as_deref(),
+            path.as_ref(),
+            "in",
+            Error::GlobalSettingFailure,
+        )?;
+        self.build("path", &path.to_string_lossy(), None)
     }
 
     /// Build an image using the provided HTML string
@@ -255,25 +594,180 @@ impl ImageBuilder {
         &'a mut self,
         html: S,
     ) -> Result<ImageOutput<'b>> {
-        let mut global = self.global_settings()?;
-        unsafe {
-            global.set("in", "-")?;
+        let html = self.prepare_html(html.as_ref())?;
+        self.build("html", "-", Some(&html))
+    }
+
+    /// Build an image from HTML read from `reader`
+    ///
+    /// UTF-8 (or UTF-16 with a byte order mark) is built like `build_from_html`. HTML in other encodings
+    ///   is loaded from a temporary file, and decoded with the charset declared by its `<meta>` tags
+    ///   (which is also set as `web.defaultEncoding`). HTML containing NUL bytes returns `Error::InvalidInput`.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// # use wkhtmltopdf::{ImageApplication, ImageFormat};
+    /// let mut image_app = ImageApplication::new().expect("Failed to init image application");
+    /// let file = std::fs::File::open("/path/to/static/index.html").unwrap();
+    /// let mut imageout = image_app.builder()
+    ///        .format(ImageFormat::Png)
+    ///        .build_from_reader(file)
+    ///        .expect("failed to build image");
+    /// ```
+    pub fn build_from_reader<'a, 'b, R: Read>(
+        &'a mut self,
+        mut reader: R,
+    ) -> Result<ImageOutput<'b>> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let (data, charset) = match decode_html(data)? {
+            Document::Html(html) => return self.build_from_html(html),
+            Document::Encoded { data, charset } => (data, charset),
+        };
+        let file = TempHtml::write("input", data)?;
+        let path = file.path().to_string_lossy().into_owned();
+        let mut builder = self.clone();
+        if let Some(charset) = charset {
+            builder.gs.insert("web.defaultEncoding", charset.into());
         }
-        let converter = global.create_converter(Some(html.as_ref()));
-        converter.convert()
+        builder.generated_files.push(path.clone());
+        // Not `build_from_path`, since the file is outside of any root directory
+        builder.build("path", &path, None)
+    }
+
+    /// Build an image from any `Source`
+    ///
+    /// This dispatches to the matching `build_from_url`, `build_from_path`, or `build_from_html` method.
+    pub fn build_from_source<'b>(&mut self, source: &Source) -> Result<ImageOutput<'b>> {
+        match source {
+            Source::Url(url) => self.build_from_url(url),
+            Source::Path(path) => self.build_from_path(path),
+            Source::Html(html) => self.build_from_html(html),
+        }
+    }
+
+    /// Build an image from a Markdown document
+    ///
+    /// The document is converted to HTML styled with the `markdown_stylesheet`
+    ///   (see the [`markdown`](markdown/index.html) module).
+    #[cfg(feature = "markdown")]
+    pub fn build_from_markdown<'a, 'b, S: AsRef<str>>(
+        &'a mut self,
+        markdown: S,
+    ) -> Result<ImageOutput<'b>> {
+        let stylesheet = self
+            .markdown_stylesheet
+            .as_deref()
+            .unwrap_or(markdown::DEFAULT_STYLESHEET);
+        let html = markdown::to_html(markdown.as_ref(), stylesheet);
+        self.build_from_html(html)
     }
 
     /// Use the relevant settings to construct a low-level instance of `ImageGlobalSettings`
     pub fn global_settings(&self) -> Result<ImageGlobalSettings> {
         let mut global = ImageGlobalSettings::new()?;
-        for (ref name, ref val) in &self.gs {
+        // Raster output is cropped in `post_process`, but SVG output can only use the upstream settings
+        for (name, val) in self.settings(self.is_svg()) {
             unsafe { global.set(name, &val) }?;
         }
         Ok(global)
     }
+
+    // Builds an image with `build_input`, instrumented as a conversion of `kind` ("url", "path", or "html")
+    fn build<'b>(
+        &self,
+        kind: &'static str,
+        input: &str,
+        html: Option<&str>,
+    ) -> Result<ImageOutput<'b>> {
+        let settings = self.settings(self.is_svg()).len();
+        Conversion::start("image", kind, settings).run(
+            || match self.limits.is_set() {
+                true => self.build_isolated(kind, input, html),
+                false => self.build_input(input, html),
+            },
+            |output| output.data.len(),
+        )
+    }
+
+    // Builds an image in an isolated worker, which can be killed when the builder's limits are reached
+    fn build_isolated<'b>(
+        &self,
+        kind: &str,
+        input: &str,
+        html: Option<&str>,
+    ) -> Result<ImageOutput<'b>> {
+        let source = Source::from_input(kind, input, html)?;
+        let (data, diagnostics) = isolated::render_limited_image(self, source)?;
+        Ok(ImageOutput {
+            data: Cow::Owned(data),
+            pos: 0,
+            _converter: None,
+            diagnostics,
+            #[cfg(feature = "image-processing")]
+            quality: self.jpeg_quality(),
+        })
+    }
+
+    // Builds an image from the `in` setting (or `html` if it is "-"), routing the page's requests
+    //   through a proxy if it is sandboxed and serving its assets if it is HTML
+    fn build_input<'b>(&self, input: &str, html: Option<&str>) -> Result<ImageOutput<'b>> {
+        let session = Session::start(self.sandbox.as_ref(), html.and(self.assets.as_ref()))?;
+        let mut global = self.global_settings()?;
+        unsafe {
+            global.set("in", input)?;
+            if let Some(proxy) = session.proxy_url() {
+                global.set("load.proxy", &proxy)?;
+            }
+        }
+        let html = html.map(|html| session.html(html));
+        let converter = global.create_converter(html.as_deref())?;
+        let mut output = self.convert(converter)?;
+        output.diagnostics.blocked_requests = session.finish();
+        Ok(output)
+    }
+
+    // Runs the conversion, capturing anything reported by the page that is needed for post-processing,
+    //   and collecting the loads skipped or ignored by the builder's `LoadErrorPolicy`
+    fn convert<'b>(&self, mut converter: ImageConverter) -> Result<ImageOutput<'b>> {
+        let element = Arc::new(Mutex::new(None));
+        let failed_loads = Arc::new(Mutex::new(Vec::new()));
+        {
+            let builder = self.clone();
+            let element = element.clone();
+            let failed_loads = failed_loads.clone();
+            converter.set_warning_callback(Some(Box::new(move |warn: String| {
+                if let Some(bbox) = builder.element_bounds(&warn) {
+                    *lock_or_recover(&element) = Some(bbox);
+                } else if let Some(failed) = FailedLoad::from_warning(&warn) {
+                    lock_or_recover(&failed_loads).push(failed);
+                } else {
+                    debug!("wkhtmltoimage warning: {}", warn);
+                }
+            })));
+        }
+        let mut output = converter.convert()?;
+        output.diagnostics.failed_loads = mem::take(&mut *lock_or_recover(&failed_loads));
+        #[cfg(feature = "image-processing")]
+        {
+            output.quality = self.jpeg_quality();
+        }
+        let bbox = lock_or_recover(&element).take();
+        if let Some(data) = self.post_process(&output.data, bbox)? {
+            output.data = Cow::Owned(data);
+            output.pos = 0;
+        }
+        Ok(output)
+    }
 }
 
+#[cfg(any(feature = "native", feature = "dynamic"))]
 impl<'a> ImageOutput<'a> {
+    /// Problems reported during the conversion, e.g. requests blocked by the builder's `Sandbox`
+    pub fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+
     /// Save the image output to a local file
     pub fn save<P: AsRef<Path>>(&mut self, path: P) -> io::Result<File> {
         let mut file = File::create(path)?;
@@ -282,12 +776,16 @@ impl<'a> ImageOutput<'a> {
     }
 }
 
+#[cfg(any(feature = "native", feature = "dynamic"))]
 impl<'a> Read for ImageOutput<'a> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.data.read(buf)
+        let n = (&self.data[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
     }
 }
 
+#[cfg(any(feature = "native", feature = "dynamic"))]
 impl<'a> std::fmt::Debug for ImageOutput<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         self.data.fmt(f)
@@ -0,0 +1,252 @@
+//! Post-processing of generated images (requires the `image-processing` feature)
+//!
+//! wkhtmltoimage only emits JPG, PNG, BMP, and SVG at the rendered size.
+//! These functions decode the rendered bitmap so it can be resized or transcoded
+//! without a separate image pipeline. They operate on encoded bytes, so they can be used
+//! with the output of any `Renderer` (e.g. `SubprocessRenderer`), and are also available
+//! as methods of `ImageOutput`.
+//!
+//! ## Example
+//! ```no_run
+//! # #[cfg(any(feature = "native", feature = "dynamic"))] {
+//! use wkhtmltopdf::*;
+//!
+//! let image_app = ImageApplication::new().expect("Failed to init image application");
+//! let mut thumbnail = image_app.builder()
+//!     .format(ImageFormat::Png)
+//!     .screen_width(1200)
+//!     .screen_height(630)
+//!     .build_from_path("card.html")
+//!     .expect("failed to build image")
+//!     .thumbnail(600, 315)
+//!     .and_then(|out| out.transcode(EncodeFormat::WebP))
+//!     .expect("failed to process image");
+//!
+//! thumbnail.save("card.webp").expect("failed to save card.webp");
+//! # }
+//! ```
+use ::image::imageops::FilterType;
+use ::image::{DynamicImage, ImageFormat};
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use std::borrow::Cow;
+
+use super::raster::{self, DEFAULT_JPEG_QUALITY};
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use super::ImageOutput;
+use super::Result;
+
+/// Formats that processed images can be encoded as
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EncodeFormat {
+    Png,
+    /// JPEG with the given quality in percentage
+    Jpeg(u8),
+    Bmp,
+    Gif,
+    Tiff,
+    Ico,
+    /// Lossless WebP
+    WebP,
+}
+
+impl EncodeFormat {
+    fn format(&self) -> ImageFormat {
+        match self {
+            EncodeFormat::Png => ImageFormat::Png,
+            EncodeFormat::Jpeg(_) => ImageFormat::Jpeg,
+            EncodeFormat::Bmp => ImageFormat::Bmp,
+            EncodeFormat::Gif => ImageFormat::Gif,
+            EncodeFormat::Tiff => ImageFormat::Tiff,
+            EncodeFormat::Ico => ImageFormat::Ico,
+            EncodeFormat::WebP => ImageFormat::WebP,
+        }
+    }
+
+    fn quality(&self) -> u8 {
+        match self {
+            EncodeFormat::Jpeg(quality) => *quality,
+            _ => DEFAULT_JPEG_QUALITY,
+        }
+    }
+}
+
+#[cfg(any(feature = "native", feature = "dynamic"))]
+impl<'a> ImageOutput<'a> {
+    /// Decode the generated image into a bitmap
+    ///
+    /// SVG output cannot be decoded and will return `Error::ImageError`.
+    pub fn decode(&self) -> Result<DynamicImage> {
+        decode(&self.data)
+    }
+
+    /// Resize the image to exactly `width` x `height` pixels, ignoring the aspect ratio
+    ///
+    /// JPEG output is re-encoded with the builder's `image_quality`.
+    pub fn resize(self, width: u32, height: u32) -> Result<ImageOutput<'a>> {
+        let data = resize(&self.data, width, height, self.quality)?;
+        Ok(self.replace(data))
+    }
+
+    /// Resize the image to fit within `max_width` x `max_height` pixels, preserving the aspect ratio
+    ///
+    /// Images that already fit within the bounds are left at their original size.
+    pub fn resize_to_fit(self, max_width: u32, max_height: u32) -> Result<ImageOutput<'a>> {
+        let data = resize_to_fit(&self.data, max_width, max_height, self.quality)?;
+        Ok(self.replace(data))
+    }
+
+    /// Generate a thumbnail that fits within `max_width` x `max_height` pixels
+    ///
+    /// Like `resize_to_fit`, images that already fit are left at their original size, but this uses
+    /// a faster, lower quality resampling that is better suited to small previews.
+    pub fn thumbnail(self, max_width: u32, max_height: u32) -> Result<ImageOutput<'a>> {
+        let data = thumbnail(&self.data, max_width, max_height, self.quality)?;
+        Ok(self.replace(data))
+    }
+
+    /// Re-encode the image in a different format
+    pub fn transcode(self, format: EncodeFormat) -> Result<ImageOutput<'a>> {
+        let data = transcode(&self.data, format)?;
+        Ok(self.replace(data))
+    }
+
+    fn replace(mut self, data: Vec<u8>) -> ImageOutput<'a> {
+        self.data = Cow::Owned(data);
+        self.pos = 0;
+        self
+    }
+}
+
+/// Decode the encoded image `data` into a bitmap
+///
+/// SVG images cannot be decoded and will return `Error::ImageError`.
+pub fn decode(data: &[u8]) -> Result<DynamicImage> {
+    Ok(::image::load_from_memory(data)?)
+}
+
+/// Resize the encoded image `data` to exactly `width` x `height` pixels, ignoring the aspect ratio
+///
+/// The image is re-encoded in its original format, and `quality` is only used for JPEG images.
+pub fn resize(data: &[u8], width: u32, height: u32, quality: u8) -> Result<Vec<u8>> {
+    map_image(data, quality, |img| {
+        img.resize_exact(width, height, FilterType::Lanczos3)
+    })
+}
+
+/// Resize the encoded image `data` to fit within `max_width` x `max_height` pixels, preserving the aspect ratio
+///
+/// Images that already fit within the bounds are left at their original size.
+/// The image is re-encoded in its original format, and `quality` is only used for JPEG images.
+pub fn resize_to_fit(data: &[u8], max_width: u32, max_height: u32, quality: u8) -> Result<Vec<u8>> {
+    map_image(data, quality, |img| {
+        if img.width() <= max_width && img.height() <= max_height {
+            img
+        } else {
+            img.resize(max_width, max_height, FilterType::Lanczos3)
+        }
+    })
+}
+
+/// Generate a thumbnail of the encoded image `data` that fits within `max_width` x `max_height` pixels
+///
+/// Like `resize_to_fit`, images that already fit are left at their original size, but this uses
+/// a faster, lower quality resampling that is better suited to small previews.
+pub fn thumbnail(data: &[u8], max_width: u32, max_height: u32, quality: u8) -> Result<Vec<u8>> {
+    map_image(data, quality, |img| {
+        // `DynamicImage::thumbnail` would scale small images up to fill the bounds
+        if img.width() <= max_width && img.height() <= max_height {
+            img
+        } else {
+            img.thumbnail(max_width, max_height)
+        }
+    })
+}
+
+// Decode, transform, and re-encode an image in its original format
+fn map_image<F>(data: &[u8], quality: u8, f: F) -> Result<Vec<u8>>
+where
+    F: FnOnce(DynamicImage) -> DynamicImage,
+{
+    let format = ::image::guess_format(data)?;
+    let img = f(::image::load_from_memory_with_format(data, format)?);
+    raster::encode(&img, format, quality)
+}
+
+/// Re-encode the encoded image `data` in a different format
+pub fn transcode(data: &[u8], format: EncodeFormat) -> Result<Vec<u8>> {
+    let img = ::image::load_from_memory(data)?;
+    // Not every encoder supports every color type (e.g. JPEG has no alpha channel)
+    let img = match format {
+        EncodeFormat::Jpeg(_) | EncodeFormat::Bmp => DynamicImage::ImageRgb8(img.into_rgb8()),
+        EncodeFormat::Ico | EncodeFormat::WebP | EncodeFormat::Gif => {
+            DynamicImage::ImageRgba8(img.into_rgba8())
+        }
+        EncodeFormat::Png | EncodeFormat::Tiff => img,
+    };
+    raster::encode(&img, format.format(), format.quality())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::image::{GenericImageView, RgbaImage};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(width, height));
+        raster::encode(&img, ImageFormat::Png, DEFAULT_JPEG_QUALITY).unwrap()
+    }
+
+    fn dimensions(data: &[u8]) -> (u32, u32) {
+        ::image::load_from_memory(data).unwrap().dimensions()
+    }
+
+    #[test]
+    fn resize_to_fit_preserves_aspect_ratio() {
+        let out = resize_to_fit(&png(400, 200), 100, 100, DEFAULT_JPEG_QUALITY).unwrap();
+        assert_eq!(dimensions(&out), (100, 50));
+        assert_eq!(::image::guess_format(&out).unwrap(), ImageFormat::Png);
+
+        // Images that already fit are not upscaled
+        let out = resize_to_fit(&png(40, 20), 100, 100, DEFAULT_JPEG_QUALITY).unwrap();
+        assert_eq!(dimensions(&out), (40, 20));
+    }
+
+    #[test]
+    fn resizes_and_thumbnails() {
+        let out = resize(&png(400, 200), 50, 50, DEFAULT_JPEG_QUALITY).unwrap();
+        assert_eq!(dimensions(&out), (50, 50));
+        let out = thumbnail(&png(400, 200), 100, 100, DEFAULT_JPEG_QUALITY).unwrap();
+        assert_eq!(dimensions(&out), (100, 50));
+
+        // Like `resize_to_fit`, images that already fit are not upscaled
+        let out = thumbnail(&png(40, 20), 100, 100, DEFAULT_JPEG_QUALITY).unwrap();
+        assert_eq!(dimensions(&out), (40, 20));
+    }
+
+    #[test]
+    fn reencodes_jpeg_with_quality() {
+        let img = DynamicImage::ImageRgb8(::image::RgbImage::from_fn(64, 64, |x, y| {
+            ::image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8])
+        }));
+        let jpeg = raster::encode(&img, ImageFormat::Jpeg, 100).unwrap();
+        let low = resize(&jpeg, 48, 48, 10).unwrap();
+        let high = resize(&jpeg, 48, 48, 100).unwrap();
+        assert_eq!(::image::guess_format(&low).unwrap(), ImageFormat::Jpeg);
+        assert!(low.len() < high.len(), "{} >= {}", low.len(), high.len());
+    }
+
+    #[test]
+    fn transcodes_between_formats() {
+        for format in &[
+            EncodeFormat::Jpeg(80),
+            EncodeFormat::WebP,
+            EncodeFormat::Gif,
+            EncodeFormat::Bmp,
+        ] {
+            let out = transcode(&png(16, 8), *format).unwrap();
+            assert_eq!(::image::guess_format(&out).unwrap(), format.format());
+            let img = ::image::load_from_memory(&out).unwrap();
+            assert_eq!(img.dimensions(), (16, 8));
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Post-processing of rendered raster output (PNG, JPG and BMP)
+//!
+//! Some wkhtmltoimage settings (e.g. cropping) are ignored when using libwkhtmltox,
+//!   so the equivalent transformations are applied to the rendered bytes instead.
+use ::image::codecs::jpeg::JpegEncoder;
+use ::image::{DynamicImage, GenericImageView, ImageFormat};
+use std::io::Cursor;
+
+use super::{Crop, Result};
+use crate::error::Error;
+
+/// JPEG quality used by wkhtmltoimage when `imageQuality` is not set
+pub(crate) const DEFAULT_JPEG_QUALITY: u8 = 94;
+
+/// Crop the encoded image `data` and re-encode it in its original format
+///
+/// The crop region is clamped to the bounds of the rendered image, and returns `Error::InvalidInput`
+/// if nothing of the image is left. `quality` is only used when re-encoding JPEG output.
+pub(crate) fn crop(data: &[u8], crop: &Crop, quality: u8) -> Result<Vec<u8>> {
+    let format = ::image::guess_format(data)?;
+    let img = ::image::load_from_memory_with_format(data, format)?;
+    let (width, height) = img.dimensions();
+
+    let left = crop.left.min(width);
+    let top = crop.top.min(height);
+    let crop_width = crop.width.unwrap_or(width).min(width - left);
+    let crop_height = crop.height.unwrap_or(height).min(height - top);
+    if crop_width == 0 || crop_height == 0 {
+        return Err(Error::InvalidInput(
+            "crop region is outside the rendered image".into(),
+        ));
+    }
+
+    let cropped = img.crop_imm(left, top, crop_width, crop_height);
+    encode(&cropped, format, quality)
+}
+
+/// Encode `img` into a new buffer
+///
+/// `quality` is only used by the JPEG encoder.
+pub(crate) fn encode(img: &DynamicImage, format: ImageFormat, quality: u8) -> Result<Vec<u8>> {
+    let mut buf = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => {
+            img.write_with_encoder(JpegEncoder::new_with_quality(&mut buf, quality))?
+        }
+        _ => img.write_to(&mut buf, format)?,
+    }
+    Ok(buf.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::image::RgbImage;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(width, height));
+        encode(&img, ImageFormat::Png, 94).unwrap()
+    }
+
+    #[test]
+    fn crops_to_region() {
+        let crop = Crop {
+            left: 10,
+            top: 20,
+            width: Some(30),
+            height: Some(40),
+        };
+        let out = super::crop(&png(100, 100), &crop, 94).unwrap();
+        let img = ::image::load_from_memory(&out).unwrap();
+        assert_eq!(img.dimensions(), (30, 40));
+        assert_eq!(::image::guess_format(&out).unwrap(), ImageFormat::Png);
+    }
+
+    #[test]
+    fn clamps_crop_to_image_bounds() {
+        let crop = Crop {
+            left: 80,
+            top: 0,
+            width: Some(50),
+            height: None,
+        };
+        let out = super::crop(&png(100, 60), &crop, 94).unwrap();
+        let img = ::image::load_from_memory(&out).unwrap();
+        assert_eq!(img.dimensions(), (20, 60));
+    }
+
+    #[test]
+    fn rejects_empty_crop_regions() {
+        let regions = [
+            (100, 0, None, None),
+            (0, 60, None, None),
+            (0, 0, Some(0), None),
+            (0, 0, None, Some(0)),
+        ];
+        for (left, top, width, height) in regions {
+            let crop = Crop {
+                left,
+                top,
+                width,
+                height,
+            };
+            match super::crop(&png(100, 60), &crop, 94) {
+                Err(Error::InvalidInput(msg)) => {
+                    assert_eq!(msg, "crop region is outside the rendered image")
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,474 @@
+//! Embed the resources referenced by HTML into the HTML itself
+//!
+//! As an alternative to serving [`assets`](../assets/index.html) during the conversion,
+//!   [`inline_resources`](fn.inline_resources.html) rewrites a page so that it doesn't reference
+//!   any relative URLs, making the rendered output independent of file and network access:
+//!
+//! - `<link rel="stylesheet" href="...">` is replaced with a `<style>` block
+//! - `<script src="...">` is replaced with an inline `<script>` block
+//! - `<img src="...">` is replaced with a `data:` URI
+//! - `url(...)` in stylesheets (e.g. in `@font-face` rules) is replaced with a `data:` URI,
+//!   resolving it relative to the stylesheet
+//!
+//! Absolute URLs (e.g. `https://...` or `data:...`) are left unchanged, and a relative URL that
+//!   can't be found returns `Error::ResourceNotFound`.
+//!
+//! The same rewriting is applied to HTML sources by the builders' `inline_resources` method.
+//!
+//! ## Example
+//! ```no_run
+//! use wkhtmltopdf::*;
+//!
+//! let html = r#"<link rel="stylesheet" href="css/site.css"><img src="img/logo.png">"#;
+//! let inlined = inline_resources(html, &Resources::Dir("/srv/site".into()))
+//!     .expect("failed to inline resources");
+//! assert!(!inlined.contains("css/site.css"));
+//! ```
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assets::{percent_decode, AssetBundle};
+use crate::error::*;
+use crate::source::resolve_path;
+
+/// Where `inline_resources` finds the resources referenced by a page
+#[derive(Debug, Clone)]
+pub enum Resources {
+    /// Files under a local directory (references may not resolve outside of it)
+    Dir(PathBuf),
+    /// Files in an `AssetBundle`
+    Assets(AssetBundle),
+}
+
+impl Resources {
+    // Load the resource at `path` (relative to the root of the resources), with its MIME type
+    fn load(&self, path: &str) -> Result<(Cow<'_, [u8]>, Cow<'_, str>)> {
+        match self {
+            Resources::Dir(dir) => {
+                let file = resolve_path(
+                    Some(&dir.to_string_lossy()),
+                    Path::new(path),
+                    "",
+                    |_, path| Error::ResourceNotFound(path),
+                )?;
+                let content_type = content_type(&file);
+                Ok((Cow::Owned(fs::read(file)?), Cow::Borrowed(content_type)))
+            }
+            Resources::Assets(assets) => match assets.get(path) {
+                Some((data, content_type)) => {
+                    Ok((Cow::Borrowed(data), Cow::Borrowed(content_type)))
+                }
+                None => Err(Error::ResourceNotFound(path.to_string())),
+            },
+        }
+    }
+
+    // Load the resource at `path` as text, e.g. a stylesheet
+    fn load_text(&self, path: &str) -> Result<String> {
+        let (data, _) = self.load(path)?;
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    // Load the resource at `path` as a `data:` URI
+    fn load_data_uri(&self, path: &str) -> Result<String> {
+        let (data, content_type) = self.load(path)?;
+        Ok(format!("data:{};base64,{}", content_type, base64(&data)))
+    }
+}
+
+/// Embed the stylesheets, scripts, images, and fonts referenced by `html` from `resources`
+///
+/// See the [module documentation](index.html) for which references are embedded.
+pub fn inline_resources(html: &str, resources: &Resources) -> Result<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut inlined = String::with_capacity(html.len());
+    // Start of the input that hasn't been copied to `inlined` yet
+    let mut copied = 0;
+    let mut pos = 0;
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        if lower[start..].starts_with("<!--") {
+            pos = lower[start..]
+                .find("-->")
+                .map_or(html.len(), |end| start + end + 3);
+            continue;
+        }
+        let tag = match Tag::parse(html, start) {
+            Some(tag) => tag,
+            None => {
+                pos = start + 1;
+                continue;
+            }
+        };
+        pos = tag.end;
+        match tag.name.as_str() {
+            "link" if tag.is_stylesheet() => {
+                let href = match tag.attr("href").and_then(relative_path) {
+                    Some(href) => href,
+                    None => continue,
+                };
+                let href = join("", &href);
+                let css = resources.load_text(&href)?;
+                inlined.push_str(&html[copied..start]);
+                inlined.push_str("<style");
+                if let Some(media) = tag.attr("media") {
+                    inlined.push_str(&format!(r#" media="{}""#, media));
+                }
+                inlined.push('>');
+                inlined.push_str(&inline_css(&css, parent(&href), resources)?);
+                inlined.push_str("</style>");
+                copied = tag.end;
+            }
+            "img" => {
+                let (src, range) = match tag.attr_range("src") {
+                    Some(range) => (&html[range.clone()], range),
+                    None => continue,
+                };
+                let path = match relative_path(&decode_entities(src)) {
+                    Some(path) => join("", &path),
+                    None => continue,
+                };
+                inlined.push_str(&html[copied..range.start]);
+                inlined.push_str(&resources.load_data_uri(&path)?);
+                copied = range.end;
+            }
+            "script" => {
+                let close = close_tag(&lower, tag.end, "script");
+                let src = tag.attr("src").and_then(relative_path);
+                if let Some(src) = src {
+                    // Closing the script early would let it inject markup into the page
+                    let script = resources
+                        .load_text(&join("", &src))?
+                        .replace("</script", "<\\/script");
+                    inlined.push_str(&html[copied..start]);
+                    inlined.push_str(&tag.without_attr(html, "src"));
+                    inlined.push_str(&script);
+                    copied = close.start;
+                }
+                pos = close.end;
+            }
+            "style" => {
+                let close = close_tag(&lower, tag.end, "style");
+                inlined.push_str(&html[copied..tag.end]);
+                inlined.push_str(&inline_css(&html[tag.end..close.start], "", resources)?);
+                copied = close.start;
+                pos = close.end;
+            }
+            _ => {}
+        }
+    }
+    inlined.push_str(&html[copied..]);
+    Ok(inlined)
+}
+
+// Replace the relative `url(...)` references in `css` with `data:` URIs, resolving them against `dir`
+fn inline_css(css: &str, dir: &str, resources: &Resources) -> Result<String> {
+    let lower = css.to_ascii_lowercase();
+    let mut inlined = String::with_capacity(css.len());
+    let mut copied = 0;
+    let mut pos = 0;
+    while let Some(offset) = lower[pos..].find("url(") {
+        let open = pos + offset + 4;
+        let close = match css[open..].find(')') {
+            Some(close) => open + close,
+            None => break,
+        };
+        pos = close + 1;
+        let reference = css[open..close]
+            .trim()
+            .trim_matches(|c| c == '"' || c == '\'');
+        let path = match relative_path(reference) {
+            Some(path) => join(dir, &path),
+            None => continue,
+        };
+        inlined.push_str(&css[copied..open]);
+        inlined.push_str(&format!(r#""{}""#, resources.load_data_uri(&path)?));
+        copied = close;
+    }
+    inlined.push_str(&css[copied..]);
+    Ok(inlined)
+}
+
+// An opening tag, with the byte ranges of its attribute values in the page
+struct Tag {
+    name: String,
+    attrs: Vec<(String, std::ops::Range<usize>)>,
+    values: Vec<String>,
+    end: usize,
+}
+
+impl Tag {
+    // Parse the tag starting at `start`, returning `None` if it isn't an opening tag
+    fn parse(html: &str, start: usize) -> Option<Tag> {
+        let bytes = html.as_bytes();
+        let name_len = html[start + 1..]
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(html.len() - start - 1);
+        if name_len == 0 || !bytes[start + 1].is_ascii_alphabetic() {
+            return None;
+        }
+        let name = html[start + 1..start + 1 + name_len].to_ascii_lowercase();
+        let mut attrs = Vec::new();
+        let mut values = Vec::new();
+        let mut i = start + 1 + name_len;
+        loop {
+            while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+                i += 1;
+            }
+            if i >= bytes.len() {
+                return None;
+            }
+            if bytes[i] == b'>' {
+                break;
+            }
+            let attr_start = i;
+            while i < bytes.len() && !b" \t\r\n/>=".contains(&bytes[i]) {
+                i += 1;
+            }
+            let attr = html[attr_start..i].to_ascii_lowercase();
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            let range = if bytes.get(i) == Some(&b'=') {
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                match bytes.get(i) {
+                    Some(&quote) if quote == b'"' || quote == b'\'' => {
+                        let value_end = i + 1 + html[i + 1..].find(quote as char)?;
+                        let range = i + 1..value_end;
+                        i = value_end + 1;
+                        range
+                    }
+                    _ => {
+                        let value_start = i;
+                        while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>'
+                        {
+                            i += 1;
+                        }
+                        value_start..i
+                    }
+                }
+            } else {
+                i..i
+            };
+            values.push(decode_entities(&html[range.clone()]));
+            attrs.push((attr, range));
+        }
+        Some(Tag {
+            name,
+            attrs,
+            values,
+            end: i + 1,
+        })
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .position(|(attr, _)| attr == name)
+            .map(|i| self.values[i].as_str())
+    }
+
+    fn attr_range(&self, name: &str) -> Option<std::ops::Range<usize>> {
+        self.attrs
+            .iter()
+            .find(|(attr, _)| attr == name)
+            .map(|(_, range)| range.clone())
+    }
+
+    fn is_stylesheet(&self) -> bool {
+        self.attr("rel").is_some_and(|rel| {
+            rel.split_ascii_whitespace()
+                .any(|rel| rel.eq_ignore_ascii_case("stylesheet"))
+        })
+    }
+
+    // The tag as it appears in `html`, without the attribute `name`
+    fn without_attr(&self, html: &str, name: &str) -> String {
+        let start = html[..self.end].rfind('<').unwrap_or_default();
+        let mut tag = html[start..self.end].to_string();
+        if let Some(range) = self.attr_range(name) {
+            // Remove from the attribute name to the end of its (possibly quoted) value
+            let lower = html[start..range.start].to_ascii_lowercase();
+            let attr_start = start + lower.rfind(name).unwrap_or_default();
+            let quoted = html[range.end..].starts_with(['"', '\'']);
+            let attr_end = range.end + if quoted { 1 } else { 0 };
+            tag = format!(
+                "{}{}",
+                html[start..attr_start].trim_end(),
+                &html[attr_end..self.end]
+            );
+        }
+        tag
+    }
+}
+
+// The range of the closing tag for `name` at or after `from`, or an empty range at the end of `lower`
+fn close_tag(lower: &str, from: usize, name: &str) -> std::ops::Range<usize> {
+    let close = format!("</{}", name);
+    match lower[from..].find(&close) {
+        Some(offset) => {
+            let start = from + offset;
+            let end = lower[start..]
+                .find('>')
+                .map_or(lower.len(), |end| start + end + 1);
+            start..end
+        }
+        None => lower.len()..lower.len(),
+    }
+}
+
+// The path of a relative reference without its query or fragment, or `None` if it is absolute
+fn relative_path(reference: &str) -> Option<String> {
+    let reference = reference.trim();
+    let path = reference.split(['?', '#']).next().unwrap_or_default();
+    let scheme = path
+        .find(':')
+        .is_some_and(|colon| !path[..colon].contains('/'));
+    if path.is_empty() || scheme || path.starts_with("//") {
+        return None;
+    }
+    percent_decode(path)
+}
+
+// Resolve `path` relative to the directory `dir`, both relative to the root of the resources
+//
+// A leading `/` refers to the root, which `..` can't go above.
+fn join(dir: &str, path: &str) -> String {
+    let dir = if path.starts_with('/') { "" } else { dir };
+    let mut segments: Vec<&str> = dir.split('/').filter(|s| !s.is_empty()).collect();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+// The directory containing `path`
+fn parent(path: &str) -> &str {
+    path.rfind('/').map_or("", |slash| &path[..slash])
+}
+
+// Decode the character references that commonly appear in URLs
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+// The MIME type of a file, based on its extension
+fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("bmp") => "image/bmp",
+        Some("svg") => "image/svg+xml",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        _ => "application/octet-stream",
+    }
+}
+
+// Standard base64 with padding, as used by `data:` URIs
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | ((byte as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((n >> (18 - 6 * i)) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assets() -> Resources {
+        let mut assets = AssetBundle::new();
+        assets
+            .insert(
+                "css/site.css",
+                &b"@font-face { src: url('../fonts/a.woff') } h1 { color: teal }"[..],
+                "text/css",
+            )
+            .insert("fonts/a.woff", &b"wOFF"[..], "font/woff")
+            .insert("img/logo.png", &b"PNG"[..], "image/png")
+            .insert(
+                "js/app.js",
+                &b"document.write('</script>')"[..],
+                "text/javascript",
+            );
+        Resources::Assets(assets)
+    }
+
+    #[test]
+    fn inlines_referenced_resources() {
+        let html = concat!(
+            r#"<head><LINK rel="stylesheet" href="css/site.css?v=1" media=print></head>"#,
+            r#"<body><img alt="logo" src='img/logo.png'><script src="js/app.js" defer></script>"#,
+            r#"<!-- <img src="missing.png"> --><img src="https://example.com/a.png"></body>"#
+        );
+        assert_eq!(
+            inline_resources(html, &assets()).unwrap(),
+            concat!(
+                r#"<head><style media="print">@font-face { src: url("data:font/woff;base64,d09GRg==") } h1 { color: teal }</style></head>"#,
+                r#"<body><img alt="logo" src='data:image/png;base64,UE5H'><script defer>document.write('<\/script>')</script>"#,
+                r#"<!-- <img src="missing.png"> --><img src="https://example.com/a.png"></body>"#
+            )
+        );
+    }
+
+    #[test]
+    fn inlines_urls_in_style_blocks() {
+        let html = "<style>body { background: url(img/logo.png) }</style><p>url(img/x.png)</p>";
+        assert_eq!(
+            inline_resources(html, &assets()).unwrap(),
+            r#"<style>body { background: url("data:image/png;base64,UE5H") }</style><p>url(img/x.png)</p>"#
+        );
+    }
+
+    #[test]
+    fn reports_missing_resources() {
+        match inline_resources(r#"<img src="img/missing.png">"#, &assets()) {
+            Err(Error::ResourceNotFound(path)) => assert_eq!(path, "img/missing.png"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+}
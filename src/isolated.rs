@@ -0,0 +1,1012 @@
+//! Render in a supervised worker process that can crash without taking the application down
+//!
+//! Malformed pages and invalid settings can crash Qt, which would otherwise abort the whole process.
+//!   `IsolatedRenderer` runs conversions in a child process that initializes its own wkhtmltopdf:
+//!   if the worker crashes, the conversion returns `Error::RendererCrashed` and a new worker is started
+//!   for the next conversion. Timeouts and cancellation kill the worker, so a hung conversion
+//!   is actually stopped (in-process conversions with a timeout or cancellation token are also
+//!   run by a worker, see the [`cancellation`](../cancellation/index.html) module).
+//!
+//! By default, the worker is the current executable started with the `WKHTMLTOPDF_ISOLATED_WORKER`
+//!   environment variable set, so [`init_isolated_worker`](fn.init_isolated_worker.html)
+//!   must be called at the start of `main`, before anything else that shouldn't run in the worker.
+//!
+//! ## Example
+//! ```no_run
+//! use wkhtmltopdf::*;
+//!
+//! fn main() {
+//!     // Serves conversions and exits if this process was started as a worker
+//!     init_isolated_worker();
+//!
+//!     let renderer = IsolatedRenderer::new();
+//!     let html = Source::Html("<h1>Untrusted page</h1>".into());
+//!     match renderer.render_pdf(&renderer.pdf_builder(), &html) {
+//!         Ok(pdf) => println!("rendered {} bytes", pdf.len()),
+//!         Err(Error::RendererCrashed { signal }) => println!("worker crashed: {:?}", signal),
+//!         Err(err) => println!("failed to render: {}", err),
+//!     }
+//! }
+//! ```
+use lazy_static::lazy_static;
+use log::{debug, error};
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::Duration;
+
+use crate::cancellation::{Limits, Watch};
+use crate::diagnostics::{Diagnostics, FailedLoad};
+use crate::error::*;
+use crate::image::lowlevel::ImageGlobalSettings;
+use crate::image::ImageBuilder;
+use crate::pdf::lowlevel::{PdfGlobalSettings, PdfObjectSettings};
+use crate::pdf::PdfBuilder;
+use crate::renderer::{RenderKind, Renderer};
+use crate::source::Source;
+use crate::sys::lock_or_recover;
+use crate::wkhtmltox::Wkhtmltox;
+
+// Set in the environment of workers started by `IsolatedRenderer`
+const WORKER_ENV: &str = "WKHTMLTOPDF_ISOLATED_WORKER";
+
+// Precedes every response, so unexpected output on the worker's stdout is detected
+const MAGIC: &[u8; 4] = b"WKHT";
+
+// Set by `init_isolated_worker` in the application, which shows its executable can serve as a worker
+static WORKER_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // Renders the in-process conversions that have a timeout or cancellation token
+    static ref LIMITED: IsolatedRenderer = IsolatedRenderer::new();
+}
+
+/// Serve conversions for an `IsolatedRenderer` if this process was started as its worker
+///
+/// This returns immediately in any other process, and allows in-process conversions with a timeout
+///   or cancellation token to be run by a worker. In a worker, it initializes wkhtmltopdf,
+///   serves conversions until the `IsolatedRenderer` stops the worker, and exits the process.
+pub fn init_isolated_worker() {
+    if env::var_os(WORKER_ENV).is_none() {
+        WORKER_SUPPORTED.store(true, Ordering::SeqCst);
+        return;
+    }
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let code = match serve(stdin.lock(), stdout.lock()) {
+        Ok(()) => 0,
+        Err(err) => {
+            error!("isolated worker failed: {}", err);
+            1
+        }
+    };
+    process::exit(code);
+}
+
+/// Renders PDFs and images in a supervised worker process
+///
+/// Conversions are sent to the worker one at a time (a conversion waiting for the worker still stops
+///   at its timeout or cancellation); separate `IsolatedRenderer`s use separate workers, so they may
+///   render in parallel. A worker is started by the first conversion,
+///   and replaced after it crashes, is killed by a timeout or cancellation, or has completed
+///   `conversions_per_worker` conversions.
+pub struct IsolatedRenderer {
+    command: PathBuf,
+    args: Vec<OsString>,
+    conversions_per_worker: Option<usize>,
+    worker: Mutex<Option<Worker>>,
+}
+
+impl IsolatedRenderer {
+    /// Use the current executable as the worker (see `init_isolated_worker`)
+    pub fn new() -> IsolatedRenderer {
+        IsolatedRenderer {
+            command: env::current_exe().unwrap_or_default(),
+            args: Vec::new(),
+            conversions_per_worker: None,
+            worker: Mutex::new(None),
+        }
+    }
+
+    /// Path to the worker executable, which must call `init_isolated_worker`
+    pub fn command<P: Into<PathBuf>>(&mut self, path: P) -> &mut IsolatedRenderer {
+        self.command = path.into();
+        self
+    }
+
+    /// Arguments passed to the worker executable
+    pub fn args<I, S>(&mut self, args: I) -> &mut IsolatedRenderer
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Replace the worker after this many conversions
+    ///
+    /// Use 1 to run every conversion in a fresh process. By default, a worker is reused until it stops.
+    pub fn conversions_per_worker(&mut self, conversions: usize) -> &mut IsolatedRenderer {
+        self.conversions_per_worker = Some(conversions.max(1));
+        self
+    }
+
+    fn render(&self, request: &Request, limits: &Limits) -> Result<Rendered> {
+        let watch = limits.start();
+        watch.check()?;
+        let mut slot = self.wait_for_worker(&watch)?;
+        let mut worker = match slot.take() {
+            Some(worker) => worker,
+            None => Worker::spawn(&self.command, &self.args)?,
+        };
+        let res = worker.render(request, &watch);
+        let recycle = match self.conversions_per_worker {
+            Some(limit) => worker.conversions >= limit,
+            None => false,
+        };
+        // A worker is only reused if it responded, so it is known to be waiting for the next request
+        match res {
+            Ok(_) if !recycle => *slot = Some(worker),
+            _ => worker.stop(),
+        }
+        res?.map_err(|err| {
+            err.into_error()
+                .unwrap_or_else(|err| Error::ConversionFailed(err.to_string()))
+        })
+    }
+
+    // Wait for the conversion using the worker to finish, unless a limit is reached first
+    fn wait_for_worker(&self, watch: &Watch) -> Result<MutexGuard<'_, Option<Worker>>> {
+        if !watch.is_limited() {
+            return Ok(lock_or_recover(&self.worker));
+        }
+        loop {
+            match self.worker.try_lock() {
+                // Checked again, since a worker must not be killed for time spent waiting for it
+                Ok(slot) => return watch.check().map(|()| slot),
+                Err(TryLockError::Poisoned(err)) => {
+                    return watch.check().map(|()| err.into_inner())
+                }
+                Err(TryLockError::WouldBlock) => {
+                    watch.check()?;
+                    thread::sleep(watch.poll_interval());
+                }
+            }
+        }
+    }
+}
+
+impl Default for IsolatedRenderer {
+    fn default() -> IsolatedRenderer {
+        IsolatedRenderer::new()
+    }
+}
+
+impl Drop for IsolatedRenderer {
+    fn drop(&mut self) {
+        if let Ok(mut slot) = self.worker.lock() {
+            if let Some(worker) = slot.take() {
+                worker.stop();
+            }
+        }
+    }
+}
+
+impl IsolatedRenderer {
+    // Renders a PDF, also returning the problems reported during the conversion
+    pub(crate) fn render_pdf_with_diagnostics(
+        &self,
+        builder: &PdfBuilder,
+        source: &Source,
+    ) -> Result<(Vec<u8>, Diagnostics)> {
+        let source = builder.resolve_source(source)?;
+        self.render_resolved_pdf(builder, source)
+    }
+
+    // Renders a PDF from a source that was already checked and prepared by `builder`
+    fn render_resolved_pdf(
+        &self,
+        builder: &PdfBuilder,
+        source: Source,
+    ) -> Result<(Vec<u8>, Diagnostics)> {
+        // The worker reaches the proxy and assets over loopback
+        let session = builder.start_session(&source)?;
+        let (gs, os) = builder.settings();
+        let mut request = Request {
+            kind: RenderKind::Pdf,
+            global_settings: owned(gs),
+            object_settings: owned(os),
+            source: session.prepare(source),
+        };
+        if let Some(proxy) = session.proxy_url() {
+            request.object_settings.push(("load.proxy".into(), proxy));
+        }
+        let rendered = self.render(&request, builder.limits())?;
+        let mut diagnostics = Diagnostics::default();
+        for line in &rendered.warnings {
+            match FailedLoad::from_warning(line) {
+                Some(failed) => diagnostics.failed_loads.push(failed),
+                None => debug!("wkhtmltopdf warning: {}", line),
+            }
+        }
+        diagnostics.blocked_requests = session.finish();
+        Ok((rendered.data, diagnostics))
+    }
+}
+
+impl Renderer for IsolatedRenderer {
+    fn render_pdf(&self, builder: &PdfBuilder, source: &Source) -> Result<Vec<u8>> {
+        // Blocked requests are also logged by the proxy
+        let (data, _) = self.render_pdf_with_diagnostics(builder, source)?;
+        Ok(data)
+    }
+
+    fn render_image(&self, builder: &ImageBuilder, source: &Source) -> Result<Vec<u8>> {
+        let source = builder.resolve_source(source)?;
+        let (data, _) = self.render_resolved_image(builder, source)?;
+        Ok(data)
+    }
+}
+
+impl IsolatedRenderer {
+    // Renders an image from a source that was already checked and prepared by `builder`,
+    //   also returning the problems reported during the conversion
+    fn render_resolved_image(
+        &self,
+        builder: &ImageBuilder,
+        source: Source,
+    ) -> Result<(Vec<u8>, Diagnostics)> {
+        let session = builder.start_session(&source)?;
+        let settings = builder.settings(builder.is_svg());
+        let mut request = Request {
+            kind: RenderKind::Image,
+            global_settings: owned(settings.iter().map(|(name, val)| (*name, val.as_ref()))),
+            object_settings: Vec::new(),
+            source: session.prepare(source),
+        };
+        if let Some(proxy) = session.proxy_url() {
+            request.global_settings.push(("load.proxy".into(), proxy));
+        }
+        let rendered = self.render(&request, builder.limits())?;
+        let mut element = None;
+        let mut diagnostics = Diagnostics::default();
+        for line in &rendered.warnings {
+            if let Some(bbox) = builder.element_bounds(line) {
+                element = Some(bbox);
+            } else if let Some(failed) = FailedLoad::from_warning(line) {
+                diagnostics.failed_loads.push(failed);
+            } else {
+                debug!("wkhtmltoimage warning: {}", line);
+            }
+        }
+        diagnostics.blocked_requests = session.finish();
+        match builder.post_process(&rendered.data, element)? {
+            Some(data) => Ok((data, diagnostics)),
+            None => Ok((rendered.data, diagnostics)),
+        }
+    }
+}
+
+// Renders an in-process PDF conversion with a timeout or cancellation token in a worker,
+//   which is killed when a limit is reached since wkhtmltopdf can't abort a conversion
+pub(crate) fn render_limited_pdf(
+    builder: &PdfBuilder,
+    source: Source,
+) -> Result<(Vec<u8>, Diagnostics)> {
+    limited_renderer()?.render_resolved_pdf(builder, source)
+}
+
+// Renders an in-process image conversion with a timeout or cancellation token in a worker
+pub(crate) fn render_limited_image(
+    builder: &ImageBuilder,
+    source: Source,
+) -> Result<(Vec<u8>, Diagnostics)> {
+    limited_renderer()?.render_resolved_image(builder, source)
+}
+
+fn limited_renderer() -> Result<&'static IsolatedRenderer> {
+    match WORKER_SUPPORTED.load(Ordering::SeqCst) {
+        true => Ok(&LIMITED),
+        false => Err(Error::WorkerUnavailable),
+    }
+}
+
+fn owned<'a, I: IntoIterator<Item = (&'a str, &'a str)>>(settings: I) -> Vec<(String, String)> {
+    settings
+        .into_iter()
+        .map(|(name, val)| (name.to_string(), val.to_string()))
+        .collect()
+}
+
+// A running worker process, with a thread reading its responses
+struct Worker {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    responses: mpsc::Receiver<io::Result<Response>>,
+    conversions: usize,
+}
+
+impl Worker {
+    fn spawn(command: &Path, args: &[OsString]) -> Result<Worker> {
+        // Without this, a worker that didn't call `init_isolated_worker` would start workers of its own
+        if env::var_os(WORKER_ENV).is_some() {
+            return Err(Error::ConversionFailed(
+                "isolated worker did not call init_isolated_worker".into(),
+            ));
+        }
+        debug!("starting isolated worker {}", command.display());
+        let mut child = Command::new(command)
+            .args(args)
+            .env(WORKER_ENV, "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("worker stdin is piped");
+        let mut stdout = BufReader::new(child.stdout.take().expect("worker stdout is piped"));
+        let (tx, responses) = mpsc::channel();
+        thread::spawn(move || loop {
+            let res = Response::read(&mut stdout);
+            let stop = res.is_err();
+            if tx.send(res).is_err() || stop {
+                break;
+            }
+        });
+        Ok(Worker {
+            child,
+            stdin: BufWriter::new(stdin),
+            responses,
+            conversions: 0,
+        })
+    }
+
+    // Returns the worker's response, or the error that stopped it from responding
+    fn render(
+        &mut self,
+        request: &Request,
+        watch: &Watch,
+    ) -> Result<std::result::Result<Rendered, WorkerError>> {
+        self.conversions += 1;
+        let sent = request
+            .write(&mut self.stdin)
+            .and_then(|_| self.stdin.flush());
+        if sent.is_err() {
+            // The worker closed its stdin, which is reported by its exit status
+            return Err(self.crashed());
+        }
+        let response = if watch.is_limited() {
+            loop {
+                match self.responses.recv_timeout(watch.poll_interval()) {
+                    Ok(response) => break response,
+                    Err(mpsc::RecvTimeoutError::Timeout) => watch.check()?,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return Err(self.crashed()),
+                }
+            }
+        } else {
+            match self.responses.recv() {
+                Ok(response) => response,
+                Err(_) => return Err(self.crashed()),
+            }
+        };
+        match response {
+            Ok(Response::Rendered(rendered)) => Ok(Ok(rendered)),
+            Ok(Response::Failed(err)) => Ok(Err(err)),
+            Err(err) => {
+                debug!("failed to read from isolated worker: {}", err);
+                Err(self.crashed())
+            }
+        }
+    }
+
+    // Waits for a worker that stopped responding and describes how it exited
+    fn crashed(&mut self) -> Error {
+        let status = match self.child.try_wait() {
+            Ok(Some(status)) => Some(status),
+            // Still running, e.g. after writing something other than a response to stdout
+            _ => {
+                let _ = self.child.kill();
+                self.child.wait().ok()
+            }
+        };
+        let signal = status.and_then(signal);
+        error!("isolated worker stopped unexpectedly: {:?}", status);
+        Error::RendererCrashed { signal }
+    }
+
+    fn stop(mut self) {
+        debug!("stopping isolated worker");
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(unix)]
+fn signal(status: ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn signal(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+// Worker: initializes wkhtmltopdf and renders each request read from `input`
+fn serve<R: Read, W: Write>(input: R, output: W) -> Result<()> {
+    let mut input = BufReader::new(input);
+    let mut output = BufWriter::new(output);
+    // Initialization errors are reported to the renderer as the response to every request
+    let wk = Wkhtmltox::new();
+    while let Some(request) = Request::read(&mut input)? {
+        let res = match wk {
+            Ok(ref wk) => render(wk, &request),
+            Err(ref err) => Err(WorkerError::from_error(err)),
+        };
+        let response = match res {
+            Ok(rendered) => Response::Rendered(rendered),
+            Err(err) => Response::Failed(err),
+        };
+        response.write(&mut output)?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+fn render(wk: &Wkhtmltox, request: &Request) -> std::result::Result<Rendered, WorkerError> {
+    let res = match request.kind {
+        RenderKind::Pdf => render_pdf(wk, request),
+        RenderKind::Image => render_image(request),
+    };
+    res.map_err(|err| WorkerError::from_error(&err))
+}
+
+fn render_pdf(wk: &Wkhtmltox, request: &Request) -> Result<Rendered> {
+    let capabilities = wk.capabilities();
+    let mut global = PdfGlobalSettings::new()?;
+    for (name, val) in &request.global_settings {
+        capabilities.check(name, val)?;
+        unsafe { global.set(name, val) }?;
+    }
+    let mut object = PdfObjectSettings::new();
+    for (name, val) in &request.object_settings {
+        capabilities.check(name, val)?;
+        unsafe { object.set(name, val) }?;
+    }
+    let mut converter = global.create_converter();
+    match request.source {
+        Source::Url(ref url) => converter.add_page_object(object, url.as_str())?,
+        Source::Path(ref path) => converter.add_page_object(object, &path.to_string_lossy())?,
+        Source::Html(ref html) => converter.add_html_object(object, html)?,
+    }
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let collected = warnings.clone();
+    converter.set_warning_callback(Some(Box::new(move |warn: String| {
+        lock_or_recover(&collected).push(warn)
+    })));
+    let mut output = converter.convert()?;
+    let mut data = Vec::new();
+    output.read_to_end(&mut data)?;
+    let warnings = lock_or_recover(&warnings).clone();
+    Ok(Rendered { data, warnings })
+}
+
+fn render_image(request: &Request) -> Result<Rendered> {
+    let mut global = ImageGlobalSettings::new()?;
+    for (name, val) in &request.global_settings {
+        unsafe { global.set(name, val) }?;
+    }
+    let html = match request.source {
+        Source::Url(ref url) => {
+            unsafe { global.set("in", url.as_str()) }?;
+            None
+        }
+        Source::Path(ref path) => {
+            unsafe { global.set("in", &path.to_string_lossy()) }?;
+            None
+        }
+        Source::Html(ref html) => {
+            unsafe { global.set("in", "-") }?;
+            Some(html.as_str())
+        }
+    };
+    let mut converter = global.create_converter(html)?;
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let collected = warnings.clone();
+    converter.set_warning_callback(Some(Box::new(move |warn: String| {
+        lock_or_recover(&collected).push(warn)
+    })));
+    let mut output = converter.convert()?;
+    let mut data = Vec::new();
+    output.read_to_end(&mut data)?;
+    let warnings = lock_or_recover(&warnings).clone();
+    Ok(Rendered { data, warnings })
+}
+
+// A conversion sent to the worker
+#[derive(Debug, PartialEq)]
+struct Request {
+    kind: RenderKind,
+    global_settings: Vec<(String, String)>,
+    object_settings: Vec<(String, String)>,
+    source: Source,
+}
+
+// The output of a successful conversion
+#[derive(Debug, PartialEq)]
+struct Rendered {
+    data: Vec<u8>,
+    warnings: Vec<String>,
+}
+
+// An error from the worker, encoded as a tag and fields so it can be reported across processes
+#[derive(Debug, PartialEq)]
+struct WorkerError {
+    tag: u8,
+    fields: Vec<String>,
+}
+
+impl WorkerError {
+    fn from_error(err: &Error) -> WorkerError {
+        let (tag, fields) = match err {
+            Error::ConversionFailed(msg) => (0, vec![msg.clone()]),
+            Error::IoError(err) => (1, vec![err.to_string()]),
+            Error::ImageError(err) => (2, vec![err.to_string()]),
+            Error::IllegalInit => (3, vec![]),
+            Error::LibraryNotLoaded(reason) => (4, vec![reason.clone()]),
+            Error::NotInitialized => (5, vec![]),
+            Error::Blocked => (6, vec![]),
+            Error::ThreadMismatch(before, after) => {
+                (7, vec![before.to_string(), after.to_string()])
+            }
+            Error::ServiceStopped => (8, vec![]),
+            Error::RendererCrashed { signal } => {
+                (9, signal.iter().map(ToString::to_string).collect())
+            }
+            Error::Timeout(timeout) => (10, vec![timeout.as_nanos().to_string()]),
+            Error::Cancelled => (11, vec![]),
+            Error::WorkerUnavailable => (12, vec![]),
+            Error::LoadFailed { url, reason } => (13, vec![url.clone(), reason.clone()]),
+            Error::NetworkError { url, reason } => (14, vec![url.clone(), reason.clone()]),
+            Error::OutputWriteFailed(reason) => (15, vec![reason.clone()]),
+            // The engine's error can't cross the process boundary, so only its description does
+            Error::Template { name, source } => {
+                (16, vec![name.clone(), describe_sources(&**source)])
+            }
+            Error::ElementNotFound(selector) => (17, vec![selector.clone()]),
+            Error::InvalidInput(reason) => (18, vec![reason.clone()]),
+            Error::PathNotAllowed(path) => (19, vec![path.to_string_lossy().into_owned()]),
+            Error::ResourceNotFound(path) => (20, vec![path.clone()]),
+            Error::Unsupported(name) => (21, vec![name.clone()]),
+            Error::GlobalSettingFailure(name, val) => (22, vec![name.clone(), val.clone()]),
+            Error::ObjectSettingFailure(name, val) => (23, vec![name.clone(), val.clone()]),
+            // Only the message of a lock in the worker can be reported
+            Error::Poisoned(_) => (0, vec![err.to_string()]),
+        };
+        WorkerError { tag, fields }
+    }
+
+    fn into_error(self) -> io::Result<Error> {
+        let tag = self.tag;
+        let mut fields = self.fields.into_iter();
+        let mut field = || fields.next().ok_or_else(|| invalid("missing error field"));
+        let err = match tag {
+            0 => Error::ConversionFailed(field()?),
+            1 => Error::IoError(io::Error::other(field()?)),
+            2 => Error::ImageError(::image::ImageError::IoError(io::Error::other(field()?))),
+            3 => Error::IllegalInit,
+            4 => Error::LibraryNotLoaded(field()?),
+            5 => Error::NotInitialized,
+            6 => Error::Blocked,
+            7 => Error::ThreadMismatch(parse(field()?)?, parse(field()?)?),
+            8 => Error::ServiceStopped,
+            9 => Error::RendererCrashed {
+                signal: field().ok().map(parse).transpose()?,
+            },
+            10 => Error::Timeout(Duration::from_nanos(parse(field()?)?)),
+            11 => Error::Cancelled,
+            12 => Error::WorkerUnavailable,
+            13 => Error::LoadFailed {
+                url: field()?,
+                reason: field()?,
+            },
+            14 => Error::NetworkError {
+                url: field()?,
+                reason: field()?,
+            },
+            15 => Error::OutputWriteFailed(field()?),
+            16 => Error::Template {
+                name: field()?,
+                source: field()?.into(),
+            },
+            17 => Error::ElementNotFound(field()?),
+            18 => Error::InvalidInput(field()?),
+            19 => Error::PathNotAllowed(field()?.into()),
+            20 => Error::ResourceNotFound(field()?),
+            21 => Error::Unsupported(field()?),
+            22 => Error::GlobalSettingFailure(field()?, field()?),
+            23 => Error::ObjectSettingFailure(field()?, field()?),
+            _ => return Err(invalid("unknown error")),
+        };
+        Ok(err)
+    }
+}
+
+fn parse<T: std::str::FromStr>(field: String) -> io::Result<T> {
+    field.parse().map_err(|_| invalid("invalid error field"))
+}
+
+#[derive(Debug, PartialEq)]
+enum Response {
+    Rendered(Rendered),
+    Failed(WorkerError),
+}
+
+// Messages are sequences of tags, and length-prefixed strings and byte arrays
+
+impl Request {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_tag(
+            w,
+            match self.kind {
+                RenderKind::Pdf => 0,
+                RenderKind::Image => 1,
+            },
+        )?;
+        write_settings(w, &self.global_settings)?;
+        write_settings(w, &self.object_settings)?;
+        match self.source {
+            Source::Url(ref url) => {
+                write_tag(w, 0)?;
+                write_str(w, url.as_str())
+            }
+            Source::Path(ref path) => {
+                write_tag(w, 1)?;
+                write_str(w, &path.to_string_lossy())
+            }
+            Source::Html(ref html) => {
+                write_tag(w, 2)?;
+                write_str(w, html)
+            }
+        }
+    }
+
+    // Returns `None` once the renderer closes the worker's stdin
+    fn read<R: Read>(r: &mut R) -> io::Result<Option<Request>> {
+        let mut tag = [0];
+        if r.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let kind = match tag[0] {
+            0 => RenderKind::Pdf,
+            1 => RenderKind::Image,
+            _ => return Err(invalid("unknown render kind")),
+        };
+        let global_settings = read_settings(r)?;
+        let object_settings = read_settings(r)?;
+        let source = match read_tag(r)? {
+            0 => Source::Url(read_str(r)?.parse().map_err(|_| invalid("invalid url"))?),
+            1 => Source::Path(read_str(r)?.into()),
+            2 => Source::Html(read_str(r)?),
+            _ => return Err(invalid("unknown source")),
+        };
+        Ok(Some(Request {
+            kind,
+            global_settings,
+            object_settings,
+            source,
+        }))
+    }
+}
+
+impl Response {
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        match self {
+            Response::Rendered(rendered) => {
+                write_tag(w, 0)?;
+                write_bytes(w, &rendered.data)?;
+                write_strs(w, &rendered.warnings)
+            }
+            Response::Failed(err) => {
+                write_tag(w, 1)?;
+                write_tag(w, err.tag)?;
+                write_strs(w, &err.fields)
+            }
+        }
+    }
+
+    fn read<R: Read>(r: &mut R) -> io::Result<Response> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("unexpected output from worker"));
+        }
+        match read_tag(r)? {
+            0 => {
+                let data = read_bytes(r)?;
+                let warnings = read_strs(r)?;
+                Ok(Response::Rendered(Rendered { data, warnings }))
+            }
+            1 => {
+                let tag = read_tag(r)?;
+                let fields = read_strs(r)?;
+                Ok(Response::Failed(WorkerError { tag, fields }))
+            }
+            _ => Err(invalid("unknown response")),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_tag<W: Write>(w: &mut W, tag: u8) -> io::Result<()> {
+    w.write_all(&[tag])
+}
+
+fn read_tag<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut tag = [0];
+    r.read_exact(&mut tag)?;
+    Ok(tag[0])
+}
+
+fn write_len<W: Write>(w: &mut W, len: usize) -> io::Result<()> {
+    w.write_all(&(len as u64).to_le_bytes())
+}
+
+fn read_len<R: Read>(r: &mut R) -> io::Result<usize> {
+    let mut len = [0; 8];
+    r.read_exact(&mut len)?;
+    Ok(u64::from_le_bytes(len) as usize)
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_len(w, bytes.len())?;
+    w.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_len(r)?;
+    let mut bytes = Vec::new();
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    write_bytes(w, s.as_bytes())
+}
+
+fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(r)?).map_err(|_| invalid("invalid utf-8"))
+}
+
+fn write_strs<W: Write, S: AsRef<str>>(w: &mut W, strs: &[S]) -> io::Result<()> {
+    write_len(w, strs.len())?;
+    strs.iter().try_for_each(|s| write_str(w, s.as_ref()))
+}
+
+fn read_strs<R: Read>(r: &mut R) -> io::Result<Vec<String>> {
+    (0..read_len(r)?).map(|_| read_str(r)).collect()
+}
+
+fn write_settings<W: Write>(w: &mut W, settings: &[(String, String)]) -> io::Result<()> {
+    write_len(w, settings.len())?;
+    settings.iter().try_for_each(|(name, val)| {
+        write_str(w, name)?;
+        write_str(w, val)
+    })
+}
+
+fn read_settings<R: Read>(r: &mut R) -> io::Result<Vec<(String, String)>> {
+    (0..read_len(r)?)
+        .map(|_| Ok((read_str(r)?, read_str(r)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_messages() {
+        let request = Request {
+            kind: RenderKind::Pdf,
+            global_settings: vec![("documentTitle".into(), "Report".into())],
+            object_settings: vec![("web.defaultEncoding".into(), "utf-8".into())],
+            source: Source::Html("<h1>Report</h1>".into()),
+        };
+        let mut buf = Vec::new();
+        request.write(&mut buf).unwrap();
+        let mut r = buf.as_slice();
+        assert_eq!(Request::read(&mut r).unwrap(), Some(request));
+        assert_eq!(Request::read(&mut r).unwrap(), None);
+
+        let responses = vec![
+            Response::Rendered(Rendered {
+                data: b"%PDF-1.4".to_vec(),
+                warnings: vec!["Failed to load image".into()],
+            }),
+            Response::Failed(WorkerError::from_error(&Error::GlobalSettingFailure(
+                "dpi".into(),
+                "lots".into(),
+            ))),
+            Response::Failed(WorkerError::from_error(&Error::NetworkError {
+                url: "http://bad/".into(),
+                reason: "Host bad not found".into(),
+            })),
+        ];
+        let mut buf = Vec::new();
+        for response in &responses {
+            response.write(&mut buf).unwrap();
+        }
+        let mut r = buf.as_slice();
+        for response in responses {
+            assert_eq!(Response::read(&mut r).unwrap(), response);
+        }
+        assert!(Response::read(&mut &b"Loading pages"[..]).is_err());
+    }
+
+    #[test]
+    fn round_trips_every_error() {
+        let errors = vec![
+            Error::ConversionFailed("Loading pages failed".into()),
+            Error::IoError(io::Error::other("disk full")),
+            Error::ImageError(::image::ImageError::IoError(io::Error::other("truncated"))),
+            Error::IllegalInit,
+            Error::LibraryNotLoaded("not found".into()),
+            Error::NotInitialized,
+            Error::Blocked,
+            Error::ThreadMismatch(1, 2),
+            Error::ServiceStopped,
+            Error::RendererCrashed { signal: Some(11) },
+            Error::RendererCrashed { signal: None },
+            Error::Timeout(Duration::from_millis(1500)),
+            Error::Cancelled,
+            Error::WorkerUnavailable,
+            Error::LoadFailed {
+                url: "http://example.com/".into(),
+                reason: "Not Found".into(),
+            },
+            Error::NetworkError {
+                url: "http://bad/".into(),
+                reason: "Host bad not found".into(),
+            },
+            Error::OutputWriteFailed("Unable to write to destination".into()),
+            Error::Template {
+                name: "header".into(),
+                source: "unknown variable".into(),
+            },
+            Error::ElementNotFound("#chart".into()),
+            Error::InvalidInput("NUL byte".into()),
+            Error::PathNotAllowed("/etc/passwd".into()),
+            Error::ResourceNotFound("logo.png".into()),
+            Error::Unsupported("outline".into()),
+            Error::GlobalSettingFailure("dpi".into(), "lots".into()),
+            Error::ObjectSettingFailure("page".into(), "-".into()),
+        ];
+        for err in errors {
+            let mut buf = Vec::new();
+            Response::Failed(WorkerError::from_error(&err))
+                .write(&mut buf)
+                .unwrap();
+            let decoded = match Response::read(&mut buf.as_slice()).unwrap() {
+                Response::Failed(decoded) => decoded.into_error().unwrap(),
+                other => panic!("unexpected response: {:?}", other),
+            };
+            assert_eq!(
+                std::mem::discriminant(&decoded),
+                std::mem::discriminant(&err)
+            );
+            assert_eq!(decoded.to_string(), err.to_string());
+            assert_eq!(describe_sources(&decoded), describe_sources(&err));
+        }
+
+        let poisoned = WorkerError::from_error(&Error::Poisoned("callbacks"));
+        assert!(matches!(
+            poisoned.into_error(),
+            Ok(Error::ConversionFailed(msg)) if msg.contains("callbacks lock was poisoned")
+        ));
+    }
+
+    #[test]
+    fn in_process_limits_require_a_worker() {
+        // `init_isolated_worker` is never called by the test harness
+        let mut builder = PdfBuilder::new();
+        builder.timeout(Duration::from_secs(1));
+        let res = builder.build_from_html("<h1>slow</h1>");
+        assert!(matches!(res, Err(Error::WorkerUnavailable)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reports_crashes_and_respawns() {
+        use crate::fixtures::{TempFile, CRASH_ON_REQUEST};
+
+        // A worker that counts its starts, then crashes on the first request
+        let starts = TempFile::new("starts");
+        let command = TempFile::script(
+            "crash",
+            &format!(
+                "echo started >> '{}'\n{}",
+                starts.path().display(),
+                CRASH_ON_REQUEST
+            ),
+        );
+
+        let mut renderer = IsolatedRenderer::new();
+        renderer.command(command.path());
+        let html = Source::Html("<h1>crash</h1>".into());
+        for _ in 0..2 {
+            match renderer.render_pdf(&renderer.pdf_builder(), &html) {
+                Err(Error::RendererCrashed { signal }) => assert_eq!(signal, Some(11)),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        let starts = std::fs::read_to_string(starts.path()).unwrap();
+        assert_eq!(starts.lines().count(), 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn queued_conversions_time_out_while_waiting() {
+        use crate::fixtures::{TempFile, CRASH_ON_REQUEST};
+        use std::time::Instant;
+
+        // A worker that counts its starts, then takes a second to crash on the first request
+        let starts = TempFile::new("starts");
+        let command = TempFile::script(
+            "slow",
+            &format!(
+                "echo started >> '{}'\nsleep 1\n{}",
+                starts.path().display(),
+                CRASH_ON_REQUEST
+            ),
+        );
+        let count_starts = || {
+            std::fs::read_to_string(starts.path())
+                .unwrap()
+                .lines()
+                .count()
+        };
+
+        let mut renderer = IsolatedRenderer::new();
+        renderer.command(command.path());
+        let renderer = Arc::new(renderer);
+        let html = Source::Html("<h1>slow</h1>".into());
+        let slow = {
+            let renderer = renderer.clone();
+            let html = html.clone();
+            thread::spawn(move || renderer.render_pdf(&renderer.pdf_builder(), &html))
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while count_starts() == 0 {
+            assert!(Instant::now() < deadline, "worker never started");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut builder = renderer.pdf_builder();
+        builder.timeout(Duration::from_millis(100));
+        let started = Instant::now();
+        match renderer.render_pdf(&builder, &html) {
+            Err(Error::Timeout(timeout)) => assert_eq!(timeout, Duration::from_millis(100)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_millis(800));
+
+        // The slow conversion kept its worker, and no worker was started for the queued one
+        match slow.join().unwrap() {
+            Err(Error::RendererCrashed { signal }) => assert_eq!(signal, Some(11)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(count_starts(), 1);
+    }
+}
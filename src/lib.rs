@@ -1,12 +1,71 @@
+// Without a backend, only the builders and `MockRenderer` are available
+#![cfg_attr(
+    not(any(feature = "native", feature = "dynamic", feature = "subprocess")),
+    allow(dead_code)
+)]
+pub mod assets;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub mod batch;
+pub mod cancellation;
+pub mod diagnostics;
 pub mod error;
+#[cfg(all(test, unix))]
+mod fixtures;
+mod http;
 pub mod image;
+pub mod inline;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub mod isolated;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub mod library;
+#[cfg(feature = "markdown")]
+pub mod markdown;
 pub mod pdf;
+pub mod renderer;
+pub mod sandbox;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub mod service;
+mod session;
+pub mod source;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+mod state;
+#[cfg(feature = "subprocess")]
+pub mod subprocess;
+mod sys;
+pub mod telemetry;
+#[cfg(any(feature = "tera", feature = "handlebars", feature = "askama"))]
+pub mod template;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub mod wkhtmltox;
+pub use assets::*;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub use batch::*;
+pub use cancellation::*;
+pub use diagnostics::*;
 pub use error::*;
 pub use image::*;
+pub use inline::*;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub use isolated::*;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub use library::*;
 pub use pdf::*;
+pub use renderer::*;
+pub use sandbox::*;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub use service::*;
+pub use source::*;
+#[cfg(feature = "subprocess")]
+pub use subprocess::*;
+#[cfg(any(feature = "tera", feature = "handlebars", feature = "askama"))]
+pub use template::*;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub use wkhtmltox::*;
 //pub use pdf::Orientation;
 
-#[cfg(test)]
+#[cfg(all(test, any(feature = "native", feature = "dynamic")))]
 mod tests {
     use super::*;
     use ImageFormat::Png;
@@ -23,6 +82,32 @@ mod tests {
             assert!(res.is_ok(), "{}", res.unwrap_err());
         }
 
+        {
+            // Test that NUL bytes are reported instead of panicking
+            let res = pdf_app.builder().build_from_html("basic \0 html");
+            assert!(matches!(res, Err(Error::InvalidInput(_))), "{:?}", res);
+            let res = pdf_app.builder().title("a\0b").build_from_html("html");
+            assert!(matches!(res, Err(Error::InvalidInput(_))), "{:?}", res);
+        }
+
+        {
+            // Test that a batch continues past a failed job
+            let builder = pdf_app.builder();
+            let jobs = vec![
+                BatchJob::new("bad", &builder, Source::Html("basic \0 html".into())),
+                BatchJob::new(
+                    "good",
+                    &builder,
+                    Source::Html("basic <b>from</b> html".into()),
+                ),
+            ];
+            let results: Vec<_> = pdf_app.render_batch(jobs).collect();
+            assert_eq!(results[0].id, "bad");
+            assert!(matches!(results[0].result, Err(Error::InvalidInput(_))));
+            assert_eq!(results[1].id, "good");
+            assert!(results[1].result.is_ok(), "{:?}", results[1].result);
+        }
+
         {
             // Test building PDF from URL
             let res = pdf_app
@@ -33,6 +118,17 @@ mod tests {
 
         let image_app = ImageApplication::new().expect("Failed to init image Application");
 
+        {
+            // Test that PDF and image conversions share the same backend
+            let pdf = pdf_app
+                .builder()
+                .build_from_html("basic <b>from</b> html")
+                .expect("failed to build pdf");
+            let res = image_app.builder().format(Png).build_from_html("blocked");
+            assert!(matches!(res, Err(Error::Blocked)), "{:?}", res);
+            drop(pdf);
+        }
+
         {
             // Test building image from file
             let res = image_app
@@ -60,18 +156,41 @@ mod tests {
             assert!(res.is_ok(), "{}", res.unwrap_err());
         }
 
-        /*{ // Pending https://github.com/wkhtmltopdf/wkhtmltopdf/issues/4714
+        {
             // Test cropping options
             let res = image_app
                 .builder()
-                .format("png")
+                .format(Png)
                 .screen_width(1280)
                 .crop_left(20)
                 .crop_top(20)
                 .crop_width(800)
                 .crop_height(600)
-                .build_from_url("https://www.rust-lang.org/en-US/".parse().unwrap());
+                .build_from_url(&"https://www.rust-lang.org/en-US/".parse().unwrap());
+            assert!(res.is_ok(), "{}", res.unwrap_err());
+        }
+
+        {
+            // Test viewport capture
+            let res = image_app
+                .builder()
+                .format(Png)
+                .capture_mode(CaptureMode::Viewport {
+                    width: 1024,
+                    height: 768,
+                })
+                .build_from_url(&"https://www.rust-lang.org/en-US/".parse().unwrap());
             assert!(res.is_ok(), "{}", res.unwrap_err());
-        }*/
+        }
+
+        {
+            // Test element capture
+            let res = image_app
+                .builder()
+                .format(Png)
+                .capture_mode(CaptureMode::Element("#target".into()))
+                .build_from_html(r#"<p>before</p><div id="target">capture <b>me</b></div>"#);
+            assert!(res.is_ok(), "{}", res.unwrap_err());
+        }
     }
 }
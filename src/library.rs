@@ -0,0 +1,164 @@
+//! Information about the libwkhtmltox library in use
+//!
+//! With the `dynamic` feature, libwkhtmltox is loaded at runtime instead of being linked at build time.
+//!   It is loaded from the path given to [`load_library`](fn.load_library.html), or else from the
+//!   path in the `WKHTMLTOX_LIB` environment variable, or else by searching the platform's library path
+//!   the first time it is needed. If it can't be found, initialization returns `Error::LibraryNotLoaded`.
+//!
+//! ## Example
+//! ```no_run
+//! use wkhtmltopdf::*;
+//!
+//! # #[cfg(feature = "dynamic")]
+//! load_library("/opt/wkhtmltox/lib/libwkhtmltox.so").expect("failed to load libwkhtmltox");
+//! let version = version().expect("failed to get version");
+//! println!("using wkhtmltopdf {} (patched qt: {})", version, extended_qt().unwrap());
+//! ```
+use std::ffi::CStr;
+#[cfg(feature = "dynamic")]
+use std::ffi::OsStr;
+use std::fmt;
+
+use crate::error::*;
+use crate::sys::{self, pdf::*};
+
+/// Version of libwkhtmltox, e.g. 0.12.6
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    // Parse the leading `major.minor.patch` of a version string like "0.12.5 (with patched qt)"
+    //   where missing components are treated as 0
+    fn parse(version: &str) -> Version {
+        let mut parts = version
+            .trim()
+            .split(|c: char| !c.is_ascii_digit())
+            .take_while(|part| !part.is_empty())
+            .map(|part| part.parse().unwrap_or(0));
+        Version {
+            major: parts.next().unwrap_or(0),
+            minor: parts.next().unwrap_or(0),
+            patch: parts.next().unwrap_or(0),
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// The version of libwkhtmltox
+///
+/// This doesn't require wkhtmltopdf to be initialized.
+pub fn version() -> Result<Version> {
+    sys::load()?;
+    let version = unsafe { CStr::from_ptr(wkhtmltopdf_version()) };
+    Ok(Version::parse(&version.to_string_lossy()))
+}
+
+/// Whether libwkhtmltox was built against wkhtmltopdf's patched version of Qt
+///
+/// Several features (e.g. outlines, headers and footers, and tables of contents)
+///   are only supported with the patched Qt.
+pub fn extended_qt() -> Result<bool> {
+    sys::load()?;
+    Ok(unsafe { wkhtmltopdf_extended_qt() } != 0)
+}
+
+/// Optional features supported by the libwkhtmltox in use
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether libwkhtmltox was built against wkhtmltopdf's patched Qt
+    ///
+    /// Outlines, headers and footers, tables of contents, links, forms, disabling smart shrinking,
+    ///   and PDFs generated from more than one object all require the patched Qt.
+    pub extended_qt: bool,
+}
+
+impl Capabilities {
+    /// Detect the capabilities of libwkhtmltox
+    pub fn detect() -> Result<Capabilities> {
+        Ok(Capabilities {
+            extended_qt: extended_qt()?,
+        })
+    }
+
+    // Returns `Error::Unsupported` if a PDF setting would be silently ignored by this libwkhtmltox
+    pub(crate) fn check(&self, name: &str, value: &str) -> Result<()> {
+        if !self.extended_qt && requires_extended_qt(name, value) {
+            return Err(Error::Unsupported(name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+// Whether a PDF global or object setting only takes effect with patched Qt
+fn requires_extended_qt(name: &str, value: &str) -> bool {
+    match name {
+        "outline" | "useLocalLinks" | "useExternalLinks" | "produceForms" | "includeInOutline"
+        | "isTableOfContent" => value == "true",
+        "outlineDepth" | "dumpOutline" => !value.is_empty(),
+        "web.enableIntelligentShrinking" => value == "false",
+        _ if name.starts_with("header.") || name.starts_with("footer.") => {
+            !value.is_empty() && value != "false"
+        }
+        _ => name.starts_with("toc."),
+    }
+}
+
+/// Load libwkhtmltox from `path` (`dynamic` feature)
+///
+/// This must be called before wkhtmltopdf or wkhtmltoimage is initialized to take effect,
+///   and may only be called once per process. Returns `Error::IllegalInit` if libwkhtmltox
+///   has already been loaded, or `Error::LibraryNotLoaded` if it can't be loaded from `path`.
+#[cfg(feature = "dynamic")]
+pub fn load_library<P: AsRef<OsStr>>(path: P) -> Result<()> {
+    sys::load_from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_version_strings() {
+        let version = |major, minor, patch| Version {
+            major,
+            minor,
+            patch,
+        };
+        assert_eq!(Version::parse("0.12.6"), version(0, 12, 6));
+        assert_eq!(
+            Version::parse("0.12.5 (with patched qt)"),
+            version(0, 12, 5)
+        );
+        assert_eq!(Version::parse("0.12.6.1"), version(0, 12, 6));
+        assert_eq!(Version::parse("0.13"), version(0, 13, 0));
+        assert!(version(0, 12, 6) > version(0, 12, 5));
+        assert_eq!(version(0, 12, 6).to_string(), "0.12.6");
+    }
+
+    #[test]
+    fn rejects_settings_that_need_patched_qt() {
+        let unpatched = Capabilities { extended_qt: false };
+        assert!(unpatched.check("documentTitle", "Report").is_ok());
+        assert!(unpatched.check("outline", "false").is_ok());
+        assert!(unpatched.check("header.line", "false").is_ok());
+        match unpatched.check("outline", "true") {
+            Err(Error::Unsupported(name)) => assert_eq!(name, "outline"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(unpatched.check("footer.center", "[page]").is_err());
+        assert!(unpatched.check("toc.captionText", "Contents").is_err());
+
+        let patched = Capabilities { extended_qt: true };
+        assert!(patched.check("outline", "true").is_ok());
+        assert!(patched.check("footer.center", "[page]").is_ok());
+    }
+}
@@ -0,0 +1,102 @@
+//! Render Markdown documents (`markdown` feature)
+//!
+//! `build_from_markdown` on either builder converts CommonMark (with tables, footnotes, strikethrough,
+//!   and task lists) to an HTML document styled with [`DEFAULT_STYLESHEET`](constant.DEFAULT_STYLESHEET.html)
+//!   or the builder's `markdown_stylesheet`, and renders it like `build_from_html`.
+//!
+//! Headings become `<h1>` to `<h6>` elements, which wkhtmltopdf uses to generate the outline of a PDF.
+//!   `PdfBuilder::build_from_markdown` enables the outline when wkhtmltopdf was built with patched Qt,
+//!   unless it was configured with [`PdfBuilder::outline`](../pdf/struct.PdfBuilder.html#method.outline).
+//!
+//! ## Example
+//! ```no_run
+//! # #[cfg(any(feature = "native", feature = "dynamic"))] {
+//! use wkhtmltopdf::*;
+//!
+//! let pdf_app = PdfApplication::new().expect("Failed to init PDF application");
+//! let pdfout = pdf_app.builder()
+//!     .markdown_stylesheet("body { font-family: serif }")
+//!     .build_from_markdown("# Release notes\n\n| Version | Date |\n|---|---|\n| 0.3.0 | 2024-01-01 |")
+//!     .expect("failed to build pdf");
+//! # }
+//! ```
+use pulldown_cmark::{html, Options, Parser};
+
+/// The stylesheet used for Markdown documents unless the builder sets a `markdown_stylesheet`
+pub const DEFAULT_STYLESHEET: &str = r#"
+body { font-family: sans-serif; font-size: 11pt; line-height: 1.5; color: #222; }
+h1, h2, h3, h4, h5, h6 { line-height: 1.25; margin: 1.2em 0 0.5em; page-break-after: avoid; }
+pre, code { font-family: monospace; font-size: 0.9em; background: #f4f4f4; }
+pre { padding: 0.75em; white-space: pre-wrap; page-break-inside: avoid; }
+code { padding: 0.1em 0.25em; }
+pre code { padding: 0; }
+table { border-collapse: collapse; margin: 1em 0; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; }
+th { background: #f4f4f4; }
+tr { page-break-inside: avoid; }
+blockquote { margin: 1em 0; padding-left: 1em; border-left: 3px solid #ccc; color: #555; }
+img { max-width: 100%; }
+.footnote-definition { font-size: 0.9em; }
+.footnote-definition p { display: inline; }
+"#;
+
+// Converts `markdown` to a complete HTML document styled with `stylesheet`
+#[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+pub(crate) fn to_html(markdown: &str, stylesheet: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_HEADING_ATTRIBUTES;
+    let mut body = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut body, Parser::new_ext(markdown, options));
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        // The stylesheet may not end the style element early
+        stylesheet.replace("</", "<\\/"),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_commonmark_extensions() {
+        let markdown = "# Notes {#notes}\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\nSee[^1].\n\n[^1]: A footnote.\n\n```rust\nfn main() {}\n```\n";
+        let html = to_html(markdown, "h1 { color: teal }");
+        assert!(html.contains("<style>h1 { color: teal }</style>"));
+        assert!(html.contains(r#"<h1 id="notes">Notes</h1>"#));
+        assert!(html.contains("<table>"));
+        assert!(html.contains(r#"class="footnote-definition""#));
+        assert!(html.contains(r#"<pre><code class="language-rust">fn main() {}"#));
+    }
+
+    #[cfg(any(feature = "native", feature = "dynamic"))]
+    #[test]
+    fn outlines_headings_with_patched_qt() {
+        use crate::library::Capabilities;
+        use crate::pdf::PdfBuilder;
+
+        let outline = |builder: &PdfBuilder| {
+            let builder = builder.markdown_builder().unwrap();
+            let (gs, _) = builder.settings();
+            gs.iter()
+                .find(|(name, _)| *name == "outline")
+                .map(|(_, value)| value.to_string())
+        };
+        let mut builder = PdfBuilder::new();
+        builder.set_capabilities(Capabilities { extended_qt: true });
+        assert_eq!(outline(&builder).as_deref(), Some("true"));
+        builder.outline(None);
+        assert_eq!(outline(&builder).as_deref(), Some("false"));
+
+        let mut builder = PdfBuilder::new();
+        builder.set_capabilities(Capabilities { extended_qt: false });
+        assert_eq!(outline(&builder), None);
+        // Left for `global_settings` to reject with `Error::Unsupported`
+        builder.outline(Some(2));
+        assert_eq!(outline(&builder).as_deref(), Some("true"));
+    }
+}
@@ -9,31 +9,20 @@
 use lazy_static::lazy_static;
 use log::{debug, error, warn};
 use std::collections::HashMap;
-use std::ffi::{CStr, CString};
+use std::ffi::CStr;
 use std::marker::PhantomData;
 use std::os::raw::{c_char, c_int};
 use std::sync::{mpsc, Arc, Mutex};
 use std::{ptr, slice};
-use wkhtmltox_sys::pdf::*;
 
 use super::{Error, PdfOutput, Result};
-
-enum WkhtmltopdfState {
-    // Wkhtmltopdf has not yet been initialized
-    New,
-    // Wkhtmltopdf backend is available for PDF generation
-    Ready,
-    // Wkhtmltopdf backend is busy, so attempts to init a `PdfGlobalSettings` instance will return `Error::Blocked`
-    Busy,
-    // Once dropped, wkthmltopdf cannot be used again for the life of this process
-    Dropped,
-}
+use crate::diagnostics::Diagnostics;
+use crate::error::classify_failure;
+use crate::state::{self, Backend};
+use crate::sys::{self, c_string, lock, lock_or_recover, pdf::*};
+use crate::telemetry;
 
 lazy_static! {
-    // Globally count wkhtmltopdf handles so we can safely init/deinit the underlying wkhtmltopdf singleton
-    static ref WKHTMLTOPDF_STATE: Mutex<WkhtmltopdfState> = Mutex::new(WkhtmltopdfState::New);
-    static ref WKHTMLTOPDF_INIT_THREAD: usize = thread_id::get();
-
     // Globally track callbacks since wkhtmltopdf doesn't allow injecting any userdata
     // The HashMap key is the converter's raw pointer cast as usize, so we can have unique callbacks per converter
     static ref FINISHED_CALLBACKS: Mutex<HashMap<usize, Box<dyn FnMut(i32) + 'static + Send>>> = Mutex::new(HashMap::new());
@@ -72,7 +61,7 @@ pub struct PdfObjectSettings {
 /// Safe wrapper for working with the wkhtmltopdf converter
 pub struct PdfConverter {
     converter: *mut wkhtmltopdf_converter,
-    // PdfGlobalSettings::drop also manages wkhtmktopdf_deinit, take ownership to delay drop
+    // PdfGlobalSettings::drop also releases the backend for other conversions, take ownership to delay drop
     _global: PdfGlobalSettings,
     warning_callback: Arc<Mutex<Option<Box<dyn FnMut(String) + 'static + Send>>>>,
 }
@@ -86,24 +75,18 @@ pub struct PdfConverter {
 ///
 /// Subsequent attempts to initialize wkhtmltopdf will return `Error:IllegalInit`
 pub fn pdf_init() -> Result<PdfGuard> {
-    let mut wk_state = WKHTMLTOPDF_STATE.lock().unwrap();
-    match *wk_state {
-        WkhtmltopdfState::New => {
-            debug!("wkhtmltopdf_init graphics=0");
-            let success = unsafe { wkhtmltopdf_init(0) == 1 };
-            if success {
-                *wk_state = WkhtmltopdfState::Ready;
-                // first eval of the lazy static - effectively stores the thread id
-                let _ = *WKHTMLTOPDF_INIT_THREAD;
-            } else {
-                error!("failed to initialize wkhtmltopdf");
-            }
-            Ok(PdfGuard {
-                _private: PhantomData,
-            })
+    sys::load()?;
+    state::init(Backend::Pdf, || {
+        debug!("wkhtmltopdf_init graphics=0");
+        let success = unsafe { wkhtmltopdf_init(0) == 1 };
+        if !success {
+            error!("failed to initialize wkhtmltopdf");
         }
-        _ => Err(Error::IllegalInit),
-    }
+        success
+    })?;
+    Ok(PdfGuard {
+        _private: PhantomData,
+    })
 }
 
 impl PdfGlobalSettings {
@@ -111,37 +94,20 @@ impl PdfGlobalSettings {
     ///
     /// This may only be called after `pdf_init` has successfully initialized wkhtmltopdf
     pub fn new() -> Result<PdfGlobalSettings> {
-        if *WKHTMLTOPDF_INIT_THREAD != thread_id::get() {
-            // A lot of QT functionality expects to run from the same thread that it was first initialized on
-            return Err(Error::ThreadMismatch(
-                *WKHTMLTOPDF_INIT_THREAD,
-                thread_id::get(),
-            ));
-        }
-
-        let mut wk_state = WKHTMLTOPDF_STATE.lock().unwrap();
-        match *wk_state {
-            WkhtmltopdfState::New => Err(Error::NotInitialized),
-            WkhtmltopdfState::Dropped => Err(Error::NotInitialized),
-            WkhtmltopdfState::Busy => Err(Error::Blocked),
-            WkhtmltopdfState::Ready => {
-                debug!("wkhtmltopdf_create_global_settings");
-                let gs = unsafe { wkhtmltopdf_create_global_settings() };
-                // TODO: is it possible to delay setting Busy until convert is called?
-                *wk_state = WkhtmltopdfState::Busy;
-                Ok(PdfGlobalSettings {
-                    global_settings: gs,
-                    needs_delete: true,
-                })
-            }
-        }
+        state::acquire(Backend::Pdf)?;
+        debug!("wkhtmltopdf_create_global_settings");
+        let gs = unsafe { wkhtmltopdf_create_global_settings() };
+        // TODO: is it possible to delay acquiring the backend until convert is called?
+        Ok(PdfGlobalSettings {
+            global_settings: gs,
+            needs_delete: true,
+        })
     }
 
     // Unsafe as it may cause undefined behavior (generally segfault) if name or value are not valid
     pub unsafe fn set(&mut self, name: &str, value: &str) -> Result<()> {
-        let c_name = CString::new(name).expect("setting name may not contain interior null bytes");
-        let c_value =
-            CString::new(value).expect("setting value may not contain interior null bytes");
+        let c_name = c_string("setting name", name)?;
+        let c_value = c_string("setting value", value)?;
 
         debug!("wkhtmltopdf_set_global_setting {}='{}'", name, value);
         match wkhtmltopdf_set_global_setting(
@@ -149,9 +115,8 @@ impl PdfGlobalSettings {
             c_name.as_ptr(),
             c_value.as_ptr(),
         ) {
-            0 => Err(Error::GlobalSettingFailure(name.into(), value.into())),
             1 => Ok(()),
-            _ => unreachable!("wkhtmltopdf_set_global_setting returned invalid value"),
+            _ => Err(Error::GlobalSettingFailure(name.into(), value.into())),
         }
     }
 
@@ -174,11 +139,9 @@ impl PdfConverter {
     /// Adds a page object to the PDF by URL or local path to the page
     ///
     /// This method will set/override the `page` object setting.
-    pub fn add_page_object(&mut self, mut pdf_object: PdfObjectSettings, page: &str) {
+    pub fn add_page_object(&mut self, mut pdf_object: PdfObjectSettings, page: &str) -> Result<()> {
         unsafe {
-            pdf_object
-                .set("page", page)
-                .expect("Failed to set 'page' setting");
+            pdf_object.set("page", page)?;
         }
 
         debug!("wkhtmltopdf_add_object data=NULL");
@@ -186,6 +149,7 @@ impl PdfConverter {
             wkhtmltopdf_add_object(self.converter, pdf_object.object_settings, ptr::null());
         };
         pdf_object.needs_delete = false;
+        Ok(())
     }
 
     /// Adds a page object to the PDF using provided HTML data
@@ -193,14 +157,17 @@ impl PdfConverter {
     /// In general, this will result in ignoring the 'page' setting if added to this `pdf_object`.
     ///   The exception is when `html` is an empty string, but `app_page_object` should be
     ///   the preferred way to set the `page` setting.
-    pub fn add_html_object(&mut self, mut pdf_object: PdfObjectSettings, html: &str) {
-        let c_html = CString::new(html).expect("null byte found");
+    ///
+    /// Returns `Error::InvalidInput` if `html` contains a NUL byte.
+    pub fn add_html_object(&mut self, mut pdf_object: PdfObjectSettings, html: &str) -> Result<()> {
+        let c_html = c_string("HTML", html)?;
 
         debug!("wkhtmltopdf_add_object data=&html");
         unsafe {
             wkhtmltopdf_add_object(self.converter, pdf_object.object_settings, c_html.as_ptr());
         };
         pdf_object.needs_delete = false;
+        Ok(())
     }
 
     /// Call the warning function when a warning is issued
@@ -208,8 +175,7 @@ impl PdfConverter {
         &mut self,
         on_warning: Option<Box<dyn FnMut(String) + 'static + Send>>,
     ) {
-        let mut warning_callback = self.warning_callback.lock().expect("failed acquiring lock");
-        *warning_callback = on_warning;
+        *lock_or_recover(&self.warning_callback) = on_warning;
     }
 
     /// Performs the HTML to PDF conversion
@@ -218,7 +184,7 @@ impl PdfConverter {
     ///   so the `PdfConverter` will be owned by `PdfOutput` so that
     ///   it is not dropped until the `PdfOutput` is dropped.
     pub fn convert<'a>(self) -> Result<PdfOutput<'a>> {
-        let rx = self.setup_callbacks();
+        let rx = self.setup_callbacks()?;
         debug!("wkhtmltopdf_convert");
         let success = unsafe { wkhtmltopdf_convert(self.converter) == 1 };
         self.remove_callbacks();
@@ -230,14 +196,22 @@ impl PdfConverter {
                 let bytes = wkhtmltopdf_get_output(self.converter, &mut buf_ptr) as usize;
                 let pdf_slice = slice::from_raw_parts(buf_ptr, bytes);
                 Ok(PdfOutput {
-                    data: pdf_slice,
-                    _converter: self,
+                    data: pdf_slice.into(),
+                    pos: 0,
+                    _converter: Some(self),
+                    diagnostics: Diagnostics::default(),
                 })
             }
         } else {
-            match rx.recv().expect("sender disconnected") {
-                Ok(_) => unreachable!("failed without errors"),
-                Err(err) => Err(err),
+            match rx.recv() {
+                Ok(Err(err)) => Err(err),
+                Ok(Ok(())) => Err(Error::ConversionFailed(
+                    "wkhtmltopdf failed without reporting an error".into(),
+                )),
+                // The finished callback was never called
+                Err(_) => Err(Error::ConversionFailed(
+                    "wkhtmltopdf did not report the result of the conversion".into(),
+                )),
             }
         }
     }
@@ -245,49 +219,48 @@ impl PdfConverter {
     fn remove_callbacks(&self) {
         let id = self.converter as usize;
 
-        let _ = WARNING_CALLBACKS.lock().unwrap().remove(&id);
-        let _ = ERROR_CALLBACKS.lock().unwrap().remove(&id);
-        let _ = FINISHED_CALLBACKS.lock().unwrap().remove(&id);
+        let _ = lock_or_recover(&WARNING_CALLBACKS).remove(&id);
+        let _ = lock_or_recover(&ERROR_CALLBACKS).remove(&id);
+        let _ = lock_or_recover(&FINISHED_CALLBACKS).remove(&id);
     }
 
-    fn setup_callbacks(&self) -> mpsc::Receiver<Result<()>> {
+    fn setup_callbacks(&self) -> Result<mpsc::Receiver<Result<()>>> {
         let (tx, rx) = mpsc::channel();
         let errors = Arc::new(Mutex::new(Vec::new()));
 
         let tx_finished = tx;
         let errors_finished = errors.clone();
         let on_finished = move |i| {
-            let errors = errors_finished.lock().unwrap();
+            let errors = lock_or_recover(&errors_finished);
 
             let res = match i {
                 1 => Ok(()),
-                _ => Err(Error::ConversionFailed(errors.join(", "))),
+                _ => Err(classify_failure(errors.iter())
+                    .unwrap_or_else(|| Error::ConversionFailed(errors.join(", ")))),
             };
             let _ = tx_finished.send(res);
         };
 
         let on_error = move |err| {
-            let mut errors = errors.lock().unwrap();
+            let mut errors = lock_or_recover(&errors);
             errors.push(err);
         };
 
         let warning_cb = self.warning_callback.clone();
         let on_warning = move |warn| {
-            let mut cb = warning_cb.lock().unwrap();
-            if cb.is_some() {
-                let cb = cb.as_mut().unwrap();
+            if let Some(cb) = lock_or_recover(&warning_cb).as_mut() {
                 cb(warn);
             }
         };
 
-        // Insert into our lazy static callbacks
+        // Insert into our lazy static callbacks, locking all of them first so none are left behind on error
         {
             let id = self.converter as usize;
-            let mut finished_callbacks = FINISHED_CALLBACKS.lock().unwrap();
+            let mut finished_callbacks = lock(&FINISHED_CALLBACKS, "finished callbacks")?;
+            let mut error_callbacks = lock(&ERROR_CALLBACKS, "error callbacks")?;
+            let mut warning_callbacks = lock(&WARNING_CALLBACKS, "warning callbacks")?;
             finished_callbacks.insert(id, Box::new(on_finished));
-            let mut error_callbacks = ERROR_CALLBACKS.lock().unwrap();
             error_callbacks.insert(id, Box::new(on_error));
-            let mut warning_callbacks = WARNING_CALLBACKS.lock().unwrap();
             warning_callbacks.insert(id, Box::new(on_warning));
         }
 
@@ -300,11 +273,11 @@ impl PdfConverter {
             wkhtmltopdf_set_warning_callback(self.converter, Some(warning_callback));
             // debug!("wkhtmltopdf_set_progress_changed_callback");
             // wkhtmltopdf_set_progress_changed_callback(self.converter, Some(progress_changed_callback));
-            // debug!("wkhtmltopdf_set_phase_changed_callback");
-            // wkhtmltopdf_set_phase_changed_callback(self.converter, Some(phase_changed_callback));
+            debug!("wkhtmltopdf_set_phase_changed_callback");
+            wkhtmltopdf_set_phase_changed_callback(self.converter, Some(phase_changed_callback));
         }
 
-        rx
+        Ok(rx)
     }
 }
 
@@ -318,9 +291,8 @@ impl PdfObjectSettings {
     }
 
     pub unsafe fn set(&mut self, name: &str, value: &str) -> Result<()> {
-        let c_name = CString::new(name).expect("setting name may not contain interior null bytes");
-        let c_value =
-            CString::new(value).expect("setting value may not contain interior null bytes");
+        let c_name = c_string("setting name", name)?;
+        let c_value = c_string("setting value", value)?;
 
         debug!("wkhtmltopdf_set_object_setting {}='{}'", name, value);
         match wkhtmltopdf_set_object_setting(
@@ -328,9 +300,8 @@ impl PdfObjectSettings {
             c_name.as_ptr(),
             c_value.as_ptr(),
         ) {
-            0 => Err(Error::ObjectSettingFailure(name.into(), value.into())),
             1 => Ok(()),
-            _ => unreachable!("wkhtmltopdf_set_object_setting returned invalid value"),
+            _ => Err(Error::ObjectSettingFailure(name.into(), value.into())),
         }
     }
 }
@@ -343,6 +314,8 @@ impl Drop for PdfGlobalSettings {
                 wkhtmltopdf_destroy_global_settings(self.global_settings);
             }
         }
+        debug!("wkhtmltopdf ready again");
+        state::release();
     }
 }
 
@@ -364,21 +337,12 @@ impl Drop for PdfObjectSettings {
     }
 }
 
-// TODO: is it possible to revert to ready after convert finishes?
-impl<'a> Drop for PdfOutput<'a> {
-    fn drop(&mut self) {
-        let mut wk_state = WKHTMLTOPDF_STATE.lock().unwrap();
-        debug!("wkhtmltopdf ready again");
-        *wk_state = WkhtmltopdfState::Ready;
-    }
-}
-
 impl Drop for PdfGuard {
     fn drop(&mut self) {
-        let mut wk_state = WKHTMLTOPDF_STATE.lock().unwrap();
-        debug!("wkhtmltopdf_deinit");
-        let success = unsafe { wkhtmltopdf_deinit() == 1 };
-        *wk_state = WkhtmltopdfState::Dropped;
+        let success = state::deinit(Backend::Pdf, || {
+            debug!("wkhtmltopdf_deinit");
+            unsafe { wkhtmltopdf_deinit() == 1 }
+        });
         if !success {
             warn!("Failed to deinitialize wkhtmltopdf")
         }
@@ -389,7 +353,7 @@ unsafe extern "C" fn finished_callback(converter: *mut wkhtmltopdf_converter, va
     let id = converter as usize;
     {
         // call and remove this converter's FINISHED_CALLBACK
-        let mut callbacks = FINISHED_CALLBACKS.lock().unwrap();
+        let mut callbacks = lock_or_recover(&FINISHED_CALLBACKS);
         if let Some(mut cb) = callbacks.remove(&id) {
             cb(val as i32);
         }
@@ -398,7 +362,7 @@ unsafe extern "C" fn finished_callback(converter: *mut wkhtmltopdf_converter, va
 
 unsafe extern "C" fn error_callback(converter: *mut wkhtmltopdf_converter, msg_ptr: *const c_char) {
     let cstr = CStr::from_ptr(msg_ptr);
-    let mut callbacks = ERROR_CALLBACKS.lock().unwrap();
+    let mut callbacks = lock_or_recover(&ERROR_CALLBACKS);
     let id = converter as usize;
     let msg = cstr.to_string_lossy().into_owned();
     match callbacks.get_mut(&id) {
@@ -412,7 +376,7 @@ unsafe extern "C" fn warning_callback(
     msg_ptr: *const c_char,
 ) {
     let cstr = CStr::from_ptr(msg_ptr);
-    let mut callbacks = WARNING_CALLBACKS.lock().unwrap();
+    let mut callbacks = lock_or_recover(&WARNING_CALLBACKS);
     let id = converter as usize;
     let msg = cstr.to_string_lossy().into_owned();
     match callbacks.get_mut(&id) {
@@ -425,8 +389,10 @@ unsafe extern "C" fn warning_callback(
 //     println!("Progress: {:3}", val);
 // }
 
-// unsafe extern fn phase_changed_callback(converter: *mut wkhtmltopdf_converter) {
-//     let phase = wkhtmltopdf_current_phase(converter);
-//     let desc = wkhtmltopdf_phase_description(converter, phase);
-// 	println!("Phase: {}", CStr::from_ptr(desc).to_string_lossy());
-// }
+unsafe extern "C" fn phase_changed_callback(converter: *mut wkhtmltopdf_converter) {
+    let phase = wkhtmltopdf_current_phase(converter);
+    let desc = wkhtmltopdf_phase_description(converter, phase);
+    if !desc.is_null() {
+        telemetry::phase("pdf", phase, &CStr::from_ptr(desc).to_string_lossy());
+    }
+}
@@ -0,0 +1,503 @@
+//! Concatenate separately rendered PDFs in pure Rust (`merge` feature)
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::HashSet;
+use std::io;
+
+use crate::error::*;
+
+// Page attributes that may be inherited from the page tree, which is replaced when merging
+const INHERITED: &[&[u8]] = &[b"MediaBox", b"CropBox", b"Resources", b"Rotate"];
+
+// Guards against cycles in malformed page trees, outlines, and name trees
+const MAX_DEPTH: usize = 64;
+
+/// Concatenate `parts` into a single PDF
+///
+/// Every page keeps the size and orientation it had in its part, so parts rendered with different
+///   settings (e.g. a landscape cover followed by pages with different margins) can be combined,
+///   which a single wkhtmltopdf conversion can't do. The merged outline has a bookmark for each part,
+///   titled with the part's document title (or "Part N" if it has none), with the part's own
+///   outline nested under it. Links within a part keep working.
+///
+/// Returns `Error::InvalidInput` if there are no parts, or if a part can't be parsed or is encrypted.
+///
+/// ## Example
+/// ```no_run
+/// # #[cfg(any(feature = "native", feature = "dynamic"))] {
+/// use std::io::Read;
+/// use wkhtmltopdf::*;
+///
+/// let pdf_app = PdfApplication::new().expect("Failed to init PDF application");
+/// let render = |builder: &mut PdfBuilder, html: &str| {
+///     let mut pdf = Vec::new();
+///     builder.build_from_html(html)?.read_to_end(&mut pdf)?;
+///     Ok::<_, Error>(pdf)
+/// };
+/// let cover = render(
+///     pdf_app.builder().orientation(Orientation::Landscape).title("Cover"),
+///     "<h1>Statement</h1>",
+/// ).expect("failed to render cover");
+/// let statement = render(
+///     pdf_app.builder().margin(Size::Millimeters(20)).title("Transactions"),
+///     "<table>...</table>",
+/// ).expect("failed to render statement");
+///
+/// let pdf = pdf::merge(&[cover, statement]).expect("failed to merge pdfs");
+/// std::fs::write("statement.pdf", pdf).unwrap();
+/// # }
+/// ```
+pub fn merge<P: AsRef<[u8]>>(parts: &[P]) -> Result<Vec<u8>> {
+    if parts.is_empty() {
+        return Err(Error::InvalidInput("no PDFs to merge".into()));
+    }
+
+    let mut merged = Document::with_version("1.4");
+    let pages_id = merged.new_object_id();
+    let outlines_id = merged.new_object_id();
+    let mut kids = Vec::new();
+    let mut bookmarks = Vec::new();
+    let mut outline_count = 0;
+    let mut dests = Dictionary::new();
+
+    for (index, part) in parts.iter().enumerate() {
+        let number = index + 1;
+        let mut doc = Document::load_mem(part.as_ref()).map_err(|err| {
+            Error::InvalidInput(format!("part {} is not a valid PDF: {}", number, err))
+        })?;
+        if doc.is_encrypted() {
+            return Err(Error::InvalidInput(format!("part {} is encrypted", number)));
+        }
+        // Move the part's objects after those already merged, so their ids don't collide
+        doc.renumber_objects_with(merged.max_id + 1);
+        merged.max_id = doc.max_id;
+        if doc.version > merged.version {
+            merged.version = doc.version.clone();
+        }
+
+        let catalog = doc
+            .catalog()
+            .map_err(|err| {
+                Error::InvalidInput(format!("part {} is not a valid PDF: {}", number, err))
+            })?
+            .clone();
+        let catalog_id = doc.trailer.get(b"Root").and_then(Object::as_reference).ok();
+        let title = document_title(&doc)
+            .unwrap_or_else(|| Object::string_literal(format!("Part {}", number)));
+
+        // Reparent the pages, copying the attributes they inherited from the part's page tree
+        let pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+        for &page_id in &pages {
+            let inherited = inherited_attributes(&doc, page_id);
+            if let Ok(page) = doc.get_dictionary_mut(page_id) {
+                for (key, value) in inherited {
+                    page.set(key, value);
+                }
+                page.set("Parent", pages_id);
+            }
+        }
+        kids.extend(pages.iter().map(|&id| Object::Reference(id)));
+
+        // Named destinations are global to a document, so prefix them with the part to keep them unique
+        let prefix = format!("part{}-", number).into_bytes();
+        let named = named_destinations(&doc, &catalog);
+        if !named.is_empty() {
+            for object in doc.objects.values_mut() {
+                rename_destinations(object, &prefix);
+            }
+            for (name, dest) in named {
+                dests.set([&prefix[..], &name[..]].concat(), dest);
+            }
+        }
+
+        // Nest the part's outline under a bookmark for the part
+        let bookmark_id = merged.new_object_id();
+        let mut bookmark = Dictionary::new();
+        bookmark.set("Title", title);
+        bookmark.set("Parent", outlines_id);
+        if let Some(&first_page) = pages.first() {
+            bookmark.set(
+                "Dest",
+                vec![Object::Reference(first_page), Object::Name(b"Fit".to_vec())],
+            );
+        }
+        let outline = catalog
+            .get(b"Outlines")
+            .ok()
+            .and_then(|outline| doc.dereference(outline).ok())
+            .and_then(|(id, outline)| Some((id, outline.as_dict().ok()?.clone())));
+        let mut outline_root = None;
+        if let Some((id, outline)) = outline {
+            outline_root = id;
+            let items = top_level_items(&doc, &outline);
+            if let (Some(&first), Some(&last)) = (items.first(), items.last()) {
+                for &item in &items {
+                    if let Ok(item) = doc.get_dictionary_mut(item) {
+                        item.set("Parent", bookmark_id);
+                    }
+                }
+                let count = match outline.get(b"Count").and_then(Object::as_i64) {
+                    Ok(count) if count != 0 => count.abs(),
+                    _ => items.len() as i64,
+                };
+                bookmark.set("First", first);
+                bookmark.set("Last", last);
+                bookmark.set("Count", count);
+                outline_count += count;
+            }
+        }
+        merged
+            .objects
+            .insert(bookmark_id, Object::Dictionary(bookmark));
+        bookmarks.push(bookmark_id);
+
+        // Everything else is copied as is, except the objects replaced by the merged document
+        for (id, object) in doc.objects {
+            let replaced = Some(id) == catalog_id
+                || Some(id) == outline_root
+                || object.as_dict().is_ok_and(|dict| dict.has_type(b"Pages"));
+            if !replaced {
+                merged.objects.insert(id, object);
+            }
+        }
+    }
+
+    for (index, &id) in bookmarks.iter().enumerate() {
+        if let Ok(bookmark) = merged.get_dictionary_mut(id) {
+            if index > 0 {
+                bookmark.set("Prev", bookmarks[index - 1]);
+            }
+            if let Some(&next) = bookmarks.get(index + 1) {
+                bookmark.set("Next", next);
+            }
+        }
+    }
+
+    let mut pages = Dictionary::new();
+    pages.set("Type", "Pages");
+    pages.set("Count", kids.len() as i64);
+    pages.set("Kids", kids);
+    merged.objects.insert(pages_id, Object::Dictionary(pages));
+
+    let mut outlines = Dictionary::new();
+    outlines.set("Type", "Outlines");
+    outlines.set("First", bookmarks[0]);
+    outlines.set("Last", bookmarks[bookmarks.len() - 1]);
+    outlines.set("Count", bookmarks.len() as i64 + outline_count);
+    merged
+        .objects
+        .insert(outlines_id, Object::Dictionary(outlines));
+
+    let mut catalog = Dictionary::new();
+    catalog.set("Type", "Catalog");
+    catalog.set("Pages", pages_id);
+    catalog.set("Outlines", outlines_id);
+    catalog.set("PageMode", "UseOutlines");
+    if !dests.is_empty() {
+        catalog.set("Dests", merged.add_object(dests));
+    }
+    let catalog_id = merged.add_object(catalog);
+    merged.trailer.set("Root", catalog_id);
+
+    let mut pdf = Vec::new();
+    merged
+        .save_to(&mut pdf)
+        .map_err(|err| io::Error::other(err.to_string()))?;
+    Ok(pdf)
+}
+
+// The title in the part's document information, if it has one
+fn document_title(doc: &Document) -> Option<Object> {
+    let info = doc.trailer.get(b"Info").ok()?;
+    let (_, info) = doc.dereference(info).ok()?;
+    let title = info.as_dict().ok()?.get(b"Title").ok()?;
+    match doc.dereference(title).ok()? {
+        (_, Object::String(text, _)) if text.is_empty() => None,
+        (_, title) => Some(title.clone()),
+    }
+}
+
+// The inheritable attributes that `page` doesn't set itself, from its nearest ancestors
+fn inherited_attributes(doc: &Document, page_id: ObjectId) -> Vec<(&'static [u8], Object)> {
+    let mut attributes: Vec<(&'static [u8], Object)> = Vec::new();
+    let page = match doc.get_dictionary(page_id) {
+        Ok(page) => page,
+        Err(_) => return attributes,
+    };
+    let mut parent = page.get(b"Parent").and_then(Object::as_reference).ok();
+    for _ in 0..MAX_DEPTH {
+        let node = match parent.map(|id| doc.get_dictionary(id)) {
+            Some(Ok(node)) => node,
+            _ => break,
+        };
+        for &key in INHERITED {
+            let found = page.has(key) || attributes.iter().any(|(name, _)| *name == key);
+            if let (false, Ok(value)) = (found, node.get(key)) {
+                attributes.push((key, value.clone()));
+            }
+        }
+        parent = node.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+    attributes
+}
+
+// The top-level items of an outline, following the `Next` links from its `First` item
+fn top_level_items(doc: &Document, outline: &Dictionary) -> Vec<ObjectId> {
+    let mut items = Vec::new();
+    let mut seen = HashSet::new();
+    let mut next = outline.get(b"First").and_then(Object::as_reference).ok();
+    while let Some(id) = next.filter(|&id| seen.insert(id)) {
+        items.push(id);
+        next = doc
+            .get_dictionary(id)
+            .and_then(|item| item.get(b"Next"))
+            .and_then(Object::as_reference)
+            .ok();
+    }
+    items
+}
+
+// The part's named destinations, from both its `Dests` dictionary and its `Dests` name tree
+fn named_destinations(doc: &Document, catalog: &Dictionary) -> Vec<(Vec<u8>, Object)> {
+    let mut named = Vec::new();
+    let dictionary = |object: &Object| -> Option<Dictionary> {
+        Some(doc.dereference(object).ok()?.1.as_dict().ok()?.clone())
+    };
+    if let Some(dests) = catalog.get(b"Dests").ok().and_then(dictionary) {
+        named.extend(
+            dests
+                .iter()
+                .map(|(name, dest)| (name.clone(), dest.clone())),
+        );
+    }
+    let tree = catalog
+        .get(b"Names")
+        .ok()
+        .and_then(dictionary)
+        .and_then(|names| names.get(b"Dests").ok().and_then(dictionary));
+    if let Some(tree) = tree {
+        name_tree(doc, &tree, &mut named, 0);
+    }
+    named
+}
+
+fn name_tree(doc: &Document, node: &Dictionary, named: &mut Vec<(Vec<u8>, Object)>, depth: usize) {
+    if depth > MAX_DEPTH {
+        return;
+    }
+    if let Ok(names) = node.get(b"Names").and_then(Object::as_array) {
+        for pair in names.chunks(2) {
+            if let [Object::String(name, _), dest] = pair {
+                named.push((name.clone(), dest.clone()));
+            }
+        }
+    }
+    if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+        for kid in kids {
+            if let Ok((_, Object::Dictionary(kid))) = doc.dereference(kid) {
+                name_tree(doc, kid, named, depth + 1);
+            }
+        }
+    }
+}
+
+// Prefix the names of destinations referenced by links, actions, and outline items
+//
+// Names from the name tree are strings, which are replaced with names since all of them
+//   are moved to the merged `Dests` dictionary.
+fn rename_destinations(object: &mut Object, prefix: &[u8]) {
+    match object {
+        Object::Array(items) => {
+            for item in items {
+                rename_destinations(item, prefix);
+            }
+        }
+        Object::Dictionary(dict) => rename_in_dictionary(dict, prefix),
+        Object::Stream(stream) => rename_in_dictionary(&mut stream.dict, prefix),
+        _ => {}
+    }
+}
+
+fn rename_in_dictionary(dict: &mut Dictionary, prefix: &[u8]) {
+    let goto = dict
+        .get(b"S")
+        .and_then(Object::as_name)
+        .is_ok_and(|action| action == b"GoTo");
+    for (key, value) in dict.iter_mut() {
+        let is_destination = key == b"Dest" || (goto && key == b"D");
+        let renamed = match value {
+            Object::Name(name) | Object::String(name, _) if is_destination => {
+                Some([prefix, &name[..]].concat())
+            }
+            _ => None,
+        };
+        match renamed {
+            Some(name) => *value = Object::Name(name),
+            None => rename_destinations(value, prefix),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Stream, StringFormat};
+
+    // A PDF with a page for each media box, and optionally a title and an outline
+    //   with a single item linking to the named destination "top"
+    fn part(title: Option<&str>, boxes: &[[i64; 4]], inherit_box: bool, outline: bool) -> Vec<u8> {
+        let mut doc = Document::with_version("1.4");
+        let pages_id = doc.new_object_id();
+        let mut kids = Vec::new();
+        for media_box in boxes {
+            let contents = doc.add_object(Stream::new(Dictionary::new(), b"".to_vec()));
+            let mut page = Dictionary::new();
+            page.set("Type", "Page");
+            page.set("Parent", pages_id);
+            page.set("Contents", contents);
+            if !inherit_box {
+                page.set(
+                    "MediaBox",
+                    media_box.iter().map(|&n| n.into()).collect::<Vec<Object>>(),
+                );
+            }
+            let mut link = Dictionary::new();
+            link.set("Type", "Annot");
+            link.set("Subtype", "Link");
+            link.set("Dest", Object::Name(b"top".to_vec()));
+            page.set("Annots", vec![Object::Dictionary(link)]);
+            kids.push(Object::Reference(doc.add_object(page)));
+        }
+        let mut pages = Dictionary::new();
+        pages.set("Type", "Pages");
+        pages.set("Count", kids.len() as i64);
+        pages.set("Kids", kids.clone());
+        if inherit_box {
+            pages.set(
+                "MediaBox",
+                boxes[0].iter().map(|&n| n.into()).collect::<Vec<Object>>(),
+            );
+        }
+        doc.objects.insert(pages_id, Object::Dictionary(pages));
+
+        let mut catalog = Dictionary::new();
+        catalog.set("Type", "Catalog");
+        catalog.set("Pages", pages_id);
+        let mut dests = Dictionary::new();
+        dests.set("top", vec![kids[0].clone(), "Fit".into()]);
+        catalog.set("Dests", dests);
+        if outline {
+            let outlines_id = doc.new_object_id();
+            let mut item = Dictionary::new();
+            item.set("Title", Object::string_literal("Section"));
+            item.set("Parent", outlines_id);
+            item.set(
+                "Dest",
+                Object::String(b"top".to_vec(), StringFormat::Literal),
+            );
+            let item_id = doc.add_object(item);
+            let mut outlines = Dictionary::new();
+            outlines.set("Type", "Outlines");
+            outlines.set("First", item_id);
+            outlines.set("Last", item_id);
+            outlines.set("Count", 1);
+            doc.objects
+                .insert(outlines_id, Object::Dictionary(outlines));
+            catalog.set("Outlines", outlines_id);
+        }
+        let catalog_id = doc.add_object(catalog);
+        doc.trailer.set("Root", catalog_id);
+        if let Some(title) = title {
+            let mut info = Dictionary::new();
+            info.set("Title", Object::string_literal(title));
+            let info_id = doc.add_object(info);
+            doc.trailer.set("Info", info_id);
+        }
+
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+        pdf
+    }
+
+    fn get<'a>(doc: &'a Document, dict: &Dictionary, key: &[u8]) -> &'a Dictionary {
+        let id = dict.get(key).and_then(Object::as_reference).unwrap();
+        doc.get_dictionary(id).unwrap()
+    }
+
+    #[test]
+    fn merges_pages_and_outlines() {
+        let cover = part(Some("Cover"), &[[0, 0, 842, 595]], false, true);
+        let statement = part(None, &[[0, 0, 595, 842], [0, 0, 612, 792]], true, false);
+        let merged = Document::load_mem(&merge(&[cover, statement]).unwrap()).unwrap();
+
+        let media_boxes: Vec<Vec<i64>> = merged
+            .get_pages()
+            .values()
+            .map(|&id| {
+                let page = merged.get_dictionary(id).unwrap();
+                let media_box = page.get(b"MediaBox").and_then(Object::as_array).unwrap();
+                media_box.iter().map(|n| n.as_i64().unwrap()).collect()
+            })
+            .collect();
+        // The statement's pages inherited the first media box from its page tree
+        assert_eq!(
+            media_boxes,
+            vec![
+                vec![0, 0, 842, 595],
+                vec![0, 0, 595, 842],
+                vec![0, 0, 595, 842]
+            ]
+        );
+
+        let catalog = merged.catalog().unwrap();
+        let outlines = get(&merged, catalog, b"Outlines");
+        let first = get(&merged, outlines, b"First");
+        assert_eq!(
+            first.get(b"Title").and_then(Object::as_str).unwrap(),
+            b"Cover"
+        );
+        let section = get(&merged, first, b"First");
+        assert_eq!(
+            section.get(b"Title").and_then(Object::as_str).unwrap(),
+            b"Section"
+        );
+        assert_eq!(
+            section.get(b"Parent").unwrap(),
+            outlines.get(b"First").unwrap()
+        );
+        assert_eq!(
+            section.get(b"Dest").unwrap(),
+            &Object::Name(b"part1-top".to_vec())
+        );
+        let second = get(&merged, first, b"Next");
+        assert_eq!(
+            second.get(b"Title").and_then(Object::as_str).unwrap(),
+            b"Part 2"
+        );
+        assert!(!second.has(b"First"));
+        assert_eq!(outlines.get(b"Count").and_then(Object::as_i64).unwrap(), 3);
+
+        let dests = get(&merged, catalog, b"Dests");
+        assert!(dests.has(b"part1-top") && dests.has(b"part2-top"));
+        let page = merged.get_dictionary(merged.get_pages()[&2]).unwrap();
+        let link = page.get(b"Annots").and_then(Object::as_array).unwrap()[0]
+            .as_dict()
+            .unwrap();
+        assert_eq!(
+            link.get(b"Dest").unwrap(),
+            &Object::Name(b"part2-top".to_vec())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_parts() {
+        assert!(matches!(
+            merge::<Vec<u8>>(&[]),
+            Err(Error::InvalidInput(msg)) if msg == "no PDFs to merge"
+        ));
+        let valid = part(None, &[[0, 0, 595, 842]], false, false);
+        match merge(&[&valid[..], b"not a pdf"]) {
+            Err(Error::InvalidInput(msg)) => assert!(msg.starts_with("part 2 is not a valid PDF")),
+            other => panic!("unexpected result: {:?}", other.map(|pdf| pdf.len())),
+        }
+    }
+}
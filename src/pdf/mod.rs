@@ -7,6 +7,7 @@
 //!
 //! ## Example
 //! ```no_run
+//! # #[cfg(any(feature = "native", feature = "dynamic"))] {
 //! use wkhtmltopdf::*;
 //!
 //! let html = r#"<html><body><div>foo</div></body></html>"#;
@@ -19,6 +20,7 @@
 //!     .expect("failed to build pdf");
 //!
 //! pdfout.save("foo.pdf").expect("failed to save foo.pdf");
+//! # }
 //! ```
 //!
 //! Other examples can be seen in the documentation for
@@ -31,22 +33,72 @@
 //! Addtionally, the [`lowlevel`](lowlevel/index.html) module provides safe abstractions
 //!   that allow full configuration of wkhtmltopdf.
 
+use crate::assets::AssetBundle;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::batch::{BatchJob, BatchOptions, BatchResults};
+use crate::diagnostics::LoadErrorPolicy;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::diagnostics::{Diagnostics, FailedLoad};
 use crate::error::*;
+use crate::inline::{inline_resources, Resources};
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::isolated;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::library::Capabilities;
+#[cfg(all(feature = "markdown", any(feature = "native", feature = "dynamic")))]
+use crate::markdown;
+use crate::source::Source;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::source::{decode_html, resolve_path, Document, TempHtml};
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::sys::lock_or_recover;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::telemetry::Conversion;
+#[cfg(any(feature = "tera", feature = "handlebars", feature = "askama"))]
+use crate::template::PageTemplates;
+#[cfg(all(
+    any(feature = "tera", feature = "handlebars", feature = "askama"),
+    any(feature = "native", feature = "dynamic")
+))]
+use crate::template::TemplateEngine;
+#[cfg(any(feature = "native", feature = "dynamic"))]
 pub mod lowlevel;
+#[cfg(feature = "merge")]
+mod merge;
+use crate::cancellation::{CancellationToken, Limits};
+use crate::sandbox::{file_access_settings, Sandbox};
+use crate::session::Session;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use log::debug;
+#[cfg(any(feature = "native", feature = "dynamic"))]
 use lowlevel::*;
+#[cfg(feature = "merge")]
+pub use merge::merge;
 use std::borrow::Cow;
 use std::collections::HashMap;
+#[cfg(any(feature = "native", feature = "dynamic"))]
 use std::fs::File;
+#[cfg(any(feature = "native", feature = "dynamic"))]
 use std::io::{self, Read};
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use std::mem;
 use std::path::Path;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+#[cfg(any(feature = "native", feature = "dynamic"))]
 use url::Url;
 
 /// Generated PDF output
+#[cfg(any(feature = "native", feature = "dynamic"))]
 pub struct PdfOutput<'a> {
-    // slice of the data owned by the wkhtmltopdf_converter
-    data: &'a [u8],
-    // Don't drop the converter until data lifetime ends
-    _converter: PdfConverter,
+    // slice of the data owned by the wkhtmltopdf_converter, or data rendered by an isolated worker
+    data: Cow<'a, [u8]>,
+    // position of the next read within data
+    pos: usize,
+    // Don't drop the converter until data lifetime ends (there is none for an isolated worker's output)
+    _converter: Option<PdfConverter>,
+    diagnostics: Diagnostics,
 }
 
 /// Physical size of the paper
@@ -76,6 +128,18 @@ impl PageSize {
             Custom(_,_) => "Custom"
         }.into()
     }
+
+    /// The named paper size called `name` (ignoring case), e.g. "A4" or "letter"
+    #[cfg(feature = "server")]
+    #[rustfmt::skip]
+    pub(crate) fn from_name(name: &str) -> Option<PageSize> {
+        use self::PageSize::*;
+        [
+            A1, A2, A3, A4, A5, A6, A7, A8, A9,
+            B0, B1, B2, B3, B4, B5, B6, B7, B8, B9, B10,
+            C5E, Comm10E, DLE, Executive, Folio, Ledger, Legal, Letter, Tabloid,
+        ].iter().copied().find(|size| size.value().eq_ignore_ascii_case(name))
+    }
 }
 
 /// Unit-aware sizes
@@ -161,10 +225,13 @@ impl From<(Size, Size, Size, Size)> for Margin {
 ///
 /// This is effective a wrapper around `PdfGuard` that provides
 /// a method for instantiating one a builder
+#[cfg(any(feature = "native", feature = "dynamic"))]
 pub struct PdfApplication {
     _guard: PdfGuard,
+    capabilities: Capabilities,
 }
 
+#[cfg(any(feature = "native", feature = "dynamic"))]
 impl PdfApplication {
     /// Initializes Wkhtmltopdf
     ///
@@ -172,9 +239,24 @@ impl PdfApplication {
     /// Wkhtmltopdf may only be initialized once per process, and
     /// and all PDF generation must happen from the same thread that initialized wkhtmltopdf.
     ///
+    /// Wkhtmltopdf and wkhtmltoimage share the same Qt instance, so they must be initialized
+    /// on the same thread and only one PDF or image conversion may run at a time.
+    /// Use [`Wkhtmltox`](struct.Wkhtmltox.html) to initialize both together.
+    ///
     /// Subsequent attempts to initialize wkhtmltopdf will return `Error:IllegalInit`.
     pub fn new() -> Result<PdfApplication> {
-        pdf_init().map(|guard| PdfApplication { _guard: guard })
+        let guard = pdf_init()?;
+        Ok(PdfApplication {
+            _guard: guard,
+            capabilities: Capabilities::detect()?,
+        })
+    }
+
+    /// The optional features supported by this wkhtmltopdf
+    ///
+    /// Building a PDF with settings that aren't supported returns `Error::Unsupported`.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
     }
 
     /// Instantiate a `PdfBuilder`
@@ -183,21 +265,81 @@ impl PdfApplication {
     /// [basic limitation of wkhtmltopdf](https://github.com/wkhtmltopdf/wkhtmltopdf/issues/1711).
     /// Parallel execution is currently only possible by spawning multiple processes.
     pub fn builder(&self) -> PdfBuilder {
-        PdfBuilder {
-            gs: HashMap::new(),
-            os: HashMap::new(),
-        }
+        let mut builder = PdfBuilder::new();
+        builder.set_capabilities(self.capabilities);
+        builder
+    }
+
+    /// Render each of `jobs` in turn, continuing past the ones that fail
+    ///
+    /// Returns an iterator that renders the next job as each result is consumed
+    ///   (see the [`batch`](batch/index.html) module).
+    pub fn render_batch<Id, I>(&self, jobs: I) -> BatchResults<'_, Id, I::IntoIter>
+    where
+        Id: Send + 'static,
+        I: IntoIterator<Item = BatchJob<Id>>,
+    {
+        self.render_batch_with(jobs, &BatchOptions::new())
+    }
+
+    /// Render `jobs` as configured by `options`, e.g. in parallel by worker processes
+    pub fn render_batch_with<Id, I>(
+        &self,
+        jobs: I,
+        options: &BatchOptions,
+    ) -> BatchResults<'_, Id, I::IntoIter>
+    where
+        Id: Send + 'static,
+        I: IntoIterator<Item = BatchJob<Id>>,
+    {
+        BatchResults::new(self, jobs.into_iter(), options)
     }
 }
 
+// Settings as (name, value) pairs
+pub(crate) type Settings<'a> = Vec<(&'static str, &'a str)>;
+
 /// High-level builder for generating PDFs (initialized from `PdfApplication`)
 #[derive(Clone)]
 pub struct PdfBuilder {
     gs: HashMap<&'static str, Cow<'static, str>>,
     os: HashMap<&'static str, Cow<'static, str>>,
+    limits: Limits,
+    sandbox: Option<Sandbox>,
+    assets: Option<AssetBundle>,
+    resources: Option<Resources>,
+    #[cfg(feature = "markdown")]
+    markdown_stylesheet: Option<String>,
+    root_dir: Option<String>,
+    // Files written for wkhtmltopdf to load, e.g. rendered header templates
+    generated_files: Vec<String>,
+    #[cfg(any(feature = "tera", feature = "handlebars", feature = "askama"))]
+    templates: PageTemplates,
+    // Detected by the application the builder was instantiated from, so it isn't probed per conversion
+    #[cfg(any(feature = "native", feature = "dynamic"))]
+    capabilities: Option<Capabilities>,
 }
 
 impl PdfBuilder {
+    pub(crate) fn new() -> PdfBuilder {
+        PdfBuilder {
+            gs: HashMap::new(),
+            os: HashMap::new(),
+            limits: Limits::default(),
+            sandbox: None,
+            assets: None,
+            resources: None,
+            #[cfg(feature = "markdown")]
+            markdown_stylesheet: None,
+            root_dir: None,
+            generated_files: Vec::new(),
+            #[cfg(any(feature = "tera", feature = "handlebars", feature = "askama"))]
+            templates: PageTemplates::default(),
+            #[cfg(any(feature = "native", feature = "dynamic"))]
+            capabilities: None,
+        }
+    }
+
     /// The paper size of the output document (default A4)
     pub fn page_size(&mut self, page_size: PageSize) -> &mut PdfBuilder {
         match page_size {
@@ -256,6 +398,9 @@ impl PdfBuilder {
 
     /// Enabled generating an outline (table of contents) in the sidebar with a specified depth (default 4)
     ///
+    /// Outlines require wkhtmltopdf built with patched Qt, so building returns `Error::Unsupported` without it
+    ///   (see [`PdfApplication::capabilities`](struct.PdfApplication.html#method.capabilities)).
+    ///
     /// Note: despite being a documented in wkhtmltopdf, the outline depth is not currently configurable
     ///   due to [this upstream issue](https://github.com/wkhtmltopdf/wkhtmltopdf/issues/3055).
     ///   However, it can enable and disable the outline, and when the upstream issue is resolved,
@@ -274,6 +419,104 @@ impl PdfBuilder {
         self
     }
 
+    /// Maximum time to spend on a conversion before returning `Error::Timeout` (default none)
+    ///
+    /// Since wkhtmltopdf can't abort a conversion, in-process conversions (e.g. `build_from_html`)
+    ///   with a timeout are run by an isolated worker that is killed when the timeout is reached.
+    ///   This requires calling [`init_isolated_worker`](fn.init_isolated_worker.html) at the start
+    ///   of `main`, otherwise they return `Error::WorkerUnavailable`. See the
+    ///   [`cancellation`](cancellation/index.html) module for how each backend enforces the timeout.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut PdfBuilder {
+        self.limits.timeout = Some(timeout);
+        self
+    }
+
+    /// Return `Error::Cancelled` if `token` is cancelled before the conversion finishes
+    ///
+    /// In-process conversions with a token are run by an isolated worker, as described for `timeout`.
+    pub fn cancellation_token(&mut self, token: CancellationToken) -> &mut PdfBuilder {
+        self.limits.token = Some(token);
+        self
+    }
+
+    /// How to handle the page failing to load (default `LoadErrorPolicy::Abort`)
+    ///
+    /// Pages that are skipped or ignored are reported in the output's
+    ///   [`diagnostics`](diagnostics/index.html) instead of failing the conversion.
+    pub fn load_error_policy(&mut self, policy: LoadErrorPolicy) -> &mut PdfBuilder {
+        self.os
+            .insert("load.loadErrorHandling", policy.value().into());
+        self
+    }
+
+    /// How to handle media (e.g. images) failing to load (default `LoadErrorPolicy::Ignore`)
+    ///
+    /// Media that is skipped or ignored is reported in the output's diagnostics.
+    pub fn media_load_error_policy(&mut self, policy: LoadErrorPolicy) -> &mut PdfBuilder {
+        self.os
+            .insert("load.mediaLoadErrorHandling", policy.value().into());
+        self
+    }
+
+    /// Restrict the network and local file access of the page (default unrestricted)
+    ///
+    /// See the [`sandbox`](sandbox/index.html) module for how the sandbox is enforced.
+    pub fn sandbox(&mut self, sandbox: &Sandbox) -> &mut PdfBuilder {
+        self.sandbox = Some(sandbox.clone());
+        self
+    }
+
+    /// Serve `assets` to HTML sources, resolving their relative links (default none)
+    ///
+    /// See the [`assets`](assets/index.html) module for how the assets are served.
+    pub fn assets(&mut self, assets: &AssetBundle) -> &mut PdfBuilder {
+        self.assets = Some(assets.clone());
+        self
+    }
+
+    /// Embed the resources referenced by HTML sources from `resources` before rendering (default none)
+    ///
+    /// See the [`inline`](inline/index.html) module for which references are embedded.
+    pub fn inline_resources(&mut self, resources: &Resources) -> &mut PdfBuilder {
+        self.resources = Some(resources.clone());
+        self
+    }
+
+    /// The stylesheet of documents built with `build_from_markdown` (default `markdown::DEFAULT_STYLESHEET`)
+    #[cfg(feature = "markdown")]
+    pub fn markdown_stylesheet<S: Into<String>>(&mut self, css: S) -> &mut PdfBuilder {
+        self.markdown_stylesheet = Some(css.into());
+        self
+    }
+
+    /// Only build from local files under `root` (default unrestricted)
+    ///
+    /// Relative paths given to `build_from_path` are resolved against `root`, and paths that resolve
+    ///   outside of it (e.g. via `..` or symlinks) return `Error::PathNotAllowed`. The page may load
+    ///   local files (e.g. its stylesheets and images) from under `root`, but no other local files.
+    pub fn root_dir<P: AsRef<Path>>(&mut self, root: P) -> &mut PdfBuilder {
+        self.root_dir = Some(root.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    /// Render the template `name` as the header of each page in `build_from_template` (default none)
+    ///
+    /// The template is rendered with the same engine and context as the page.
+    #[cfg(any(feature = "tera", feature = "handlebars", feature = "askama"))]
+    pub fn header_template<S: Into<String>>(&mut self, name: S) -> &mut PdfBuilder {
+        self.templates.header = Some(name.into());
+        self
+    }
+
+    /// Render the template `name` as the footer of each page in `build_from_template` (default none)
+    ///
+    /// The template is rendered with the same engine and context as the page.
+    #[cfg(any(feature = "tera", feature = "handlebars", feature = "askama"))]
+    pub fn footer_template<S: Into<String>>(&mut self, name: S) -> &mut PdfBuilder {
+        self.templates.footer = Some(name.into());
+        self
+    }
+
     /// Set a global setting not explicitly supported by the PdfBuilder
     ///
     /// # Safety
@@ -304,6 +547,59 @@ impl PdfBuilder {
         self
     }
 
+    // The global and object settings passed to wkhtmltopdf, including those of the sandbox
+    pub(crate) fn settings(&self) -> (Settings<'_>, Settings<'_>) {
+        let gs = self.gs.iter().map(|(name, val)| (*name, val.as_ref()));
+        let mut os: Settings = self
+            .os
+            .iter()
+            .map(|(name, val)| (*name, val.as_ref()))
+            .collect();
+        os.extend(file_access_settings(
+            self.sandbox.as_ref(),
+            self.root_dir.as_deref(),
+            &self.generated_files,
+        ));
+        (gs.collect(), os)
+    }
+
+    pub(crate) fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    // Starts the servers used by a conversion of `source`, which is only served assets if it is HTML
+    pub(crate) fn start_session(&self, source: &Source) -> Result<Session> {
+        let assets = match source {
+            Source::Html(_) => self.assets.as_ref(),
+            _ => None,
+        };
+        Ok(Session::start(self.sandbox.as_ref(), assets)?)
+    }
+
+    // Checks that a `Path` source exists and is within the root directory,
+    //   and embeds the resources of an `Html` source
+    pub(crate) fn resolve_source(&self, source: &Source) -> Result<Source> {
+        match source {
+            Source::Html(html) => Ok(Source::Html(self.prepare_html(html)?.into_owned())),
+            source => source.resolve(
+                self.root_dir.as_deref(),
+                "page",
+                Error::ObjectSettingFailure,
+            ),
+        }
+    }
+
+    // Embeds the resources referenced by `html` if `inline_resources` was set
+    pub(crate) fn prepare_html<'a>(&self, html: &'a str) -> Result<Cow<'a, str>> {
+        match self.resources {
+            Some(ref resources) => Ok(Cow::Owned(inline_resources(html, resources)?)),
+            None => Ok(Cow::Borrowed(html)),
+        }
+    }
+}
+
+#[cfg(any(feature = "native", feature = "dynamic"))]
+impl PdfBuilder {
     /// Build a PDF using a URL as the source input
     ///
     /// ## Example
@@ -318,11 +614,7 @@ impl PdfBuilder {
     /// This method should be safe if using only safe builder methods, or if usage
     /// of `unsafe` methods (e.g. adding custom settings) is properly handled by wkhtmltopdf
     pub fn build_from_url<'a, 'b>(&'a mut self, url: Url) -> Result<PdfOutput<'b>> {
-        let global = self.global_settings()?;
-        let object = self.object_settings()?;
-        let mut converter = global.create_converter();
-        converter.add_page_object(object, url.as_str());
-        converter.convert()
+        self.build("url", url.as_str(), None)
     }
 
     /// Build a PDF using the provided HTML from a local file
@@ -339,11 +631,13 @@ impl PdfBuilder {
     /// This method should be safe if using only safe builder methods, or if usage
     /// of `unsafe` methods (e.g. adding custom settings) is properly handled by wkhtmltopdf
     pub fn build_from_path<'a, 'b, P: AsRef<Path>>(&'a mut self, path: P) -> Result<PdfOutput<'b>> {
-        let global = self.global_settings()?;
-        let object = self.object_settings()?;
-        let mut converter = global.create_converter();
-        converter.add_page_object(object, &path.as_ref().to_string_lossy());
-        converter.convert()
+        let path = resolve_path(
+            self.root_dir.as_deref(),
+            path.as_ref(),
+            "page",
+            Error::ObjectSettingFailure,
+        )?;
+        self.build("path", &path.to_string_lossy(), None)
     }
 
     /// Build a PDF using the provided HTML source input
@@ -361,33 +655,275 @@ impl PdfBuilder {
     /// This method should be safe if using only safe builder methods, or if usage
     /// of `unsafe` methods (e.g. adding custom settings) is properly handled by wkhtmltopdf
     pub fn build_from_html<'a, 'b, S: AsRef<str>>(&'a mut self, html: S) -> Result<PdfOutput<'b>> {
-        let global = self.global_settings()?;
-        let object = self.object_settings()?;
-        let mut converter = global.create_converter();
-        converter.add_html_object(object, html.as_ref());
-        converter.convert()
+        let html = self.prepare_html(html.as_ref())?;
+        self.build("html", "-", Some(&html))
+    }
+
+    /// Build a PDF from HTML read from `reader`
+    ///
+    /// UTF-8 (or UTF-16 with a byte order mark) is built like `build_from_html`. HTML in other encodings
+    ///   is loaded from a temporary file, and decoded with the charset declared by its `<meta>` tags
+    ///   (which is also set as `web.defaultEncoding`). HTML containing NUL bytes returns `Error::InvalidInput`.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// # use wkhtmltopdf::PdfApplication;
+    /// let mut pdf_app = PdfApplication::new().expect("Failed to init PDF application");
+    /// let file = std::fs::File::open("/path/to/static/index.html").unwrap();
+    /// let mut pdfout = pdf_app.builder()
+    ///        .build_from_reader(file)
+    ///        .expect("failed to build pdf");
+    /// ```
+    pub fn build_from_reader<'a, 'b, R: Read>(
+        &'a mut self,
+        mut reader: R,
+    ) -> Result<PdfOutput<'b>> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let (data, charset) = match decode_html(data)? {
+            Document::Html(html) => return self.build_from_html(html),
+            Document::Encoded { data, charset } => (data, charset),
+        };
+        let file = TempHtml::write("input", data)?;
+        let path = file.path().to_string_lossy().into_owned();
+        let mut builder = self.clone();
+        if let Some(charset) = charset {
+            builder.os.insert("web.defaultEncoding", charset.into());
+        }
+        builder.generated_files.push(path.clone());
+        // Not `build_from_path`, since the file is outside of any root directory
+        builder.build("path", &path, None)
+    }
+
+    /// Build a PDF from any `Source`
+    ///
+    /// This dispatches to the matching `build_from_url`, `build_from_path`, or `build_from_html` method.
+    pub fn build_from_source<'b>(&mut self, source: &Source) -> Result<PdfOutput<'b>> {
+        match source {
+            Source::Url(url) => self.build_from_url(url.clone()),
+            Source::Path(path) => self.build_from_path(path),
+            Source::Html(html) => self.build_from_html(html),
+        }
+    }
+
+    /// Build a PDF from a Markdown document
+    ///
+    /// The document is converted to HTML styled with the `markdown_stylesheet`
+    ///   (see the [`markdown`](markdown/index.html) module).
+    ///
+    /// Unless `outline` was called, the PDF has an outline of the document's headings when wkhtmltopdf
+    ///   was built with patched Qt, and none otherwise. Calling `outline(Some(depth))` without patched Qt
+    ///   returns `Error::Unsupported`, like any other conversion.
+    #[cfg(feature = "markdown")]
+    pub fn build_from_markdown<'a, 'b, S: AsRef<str>>(
+        &'a mut self,
+        markdown: S,
+    ) -> Result<PdfOutput<'b>> {
+        let stylesheet = self
+            .markdown_stylesheet
+            .as_deref()
+            .unwrap_or(markdown::DEFAULT_STYLESHEET);
+        let html = markdown::to_html(markdown.as_ref(), stylesheet);
+        self.markdown_builder()?.build_from_html(html)
+    }
+
+    // A copy of this builder that outlines the document's headings if the outline wasn't configured
+    //   and the capabilities support it
+    #[cfg(feature = "markdown")]
+    pub(crate) fn markdown_builder(&self) -> Result<PdfBuilder> {
+        let mut builder = self.clone();
+        if !self.gs.contains_key("outline") && self.detected_capabilities()?.extended_qt {
+            builder.outline(Some(4));
+        }
+        Ok(builder)
+    }
+
+    /// Build a PDF from the template `name`, rendered by `engine` with `context`
+    ///
+    /// Header and footer templates set with `header_template` and `footer_template` are rendered with
+    ///   the same context. Returns `Error::Template` if a template fails to render.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// # #[cfg(feature = "handlebars")] {
+    /// # use wkhtmltopdf::PdfApplication;
+    /// # use std::collections::HashMap;
+    /// let mut handlebars = handlebars::Handlebars::new();
+    /// handlebars.register_template_string("report", "<h1>{{title}}</h1>").unwrap();
+    /// let context = HashMap::from([("title", "Quarterly report")]);
+    ///
+    /// let mut pdf_app = PdfApplication::new().expect("Failed to init PDF application");
+    /// let mut pdfout = pdf_app.builder()
+    ///        .build_from_template(&handlebars, "report", &context)
+    ///        .expect("failed to build pdf");
+    /// # }
+    /// ```
+    #[cfg(any(feature = "tera", feature = "handlebars", feature = "askama"))]
+    pub fn build_from_template<'b, E, C>(
+        &mut self,
+        engine: &E,
+        name: &str,
+        context: &C,
+    ) -> Result<PdfOutput<'b>>
+    where
+        E: TemplateEngine<C>,
+        C: ?Sized,
+    {
+        let html = engine.render_html(name, context)?;
+        let mut builder = self.clone();
+        // Kept until the conversion finishes, since wkhtmltopdf loads headers and footers from files
+        let mut files = Vec::new();
+        let templates = [
+            ("header", "header.htmlUrl", &self.templates.header),
+            ("footer", "footer.htmlUrl", &self.templates.footer),
+        ];
+        for (part, setting, template) in templates {
+            let template = match template {
+                Some(template) => template,
+                None => continue,
+            };
+            if !engine.renders_by_name() {
+                return Err(Error::Template {
+                    name: template.clone(),
+                    source: format!(
+                        "{} templates require an engine that renders templates by name",
+                        part
+                    )
+                    .into(),
+                });
+            }
+            let file = TempHtml::write(part, &engine.render_html(template, context)?)?;
+            let path = file.path().to_string_lossy().into_owned();
+            builder.os.insert(setting, path.clone().into());
+            builder.generated_files.push(path);
+            files.push(file);
+        }
+        builder.build_from_html(html)
     }
 
     /// Use the relevant settings to construct a low-level instance of `PdfGlobalSettings`
+    ///
+    /// Returns `Error::Unsupported` if a setting requires features this wkhtmltopdf wasn't built with.
     pub fn global_settings(&self) -> Result<PdfGlobalSettings> {
+        let (gs, _) = self.settings();
+        let capabilities = self.detected_capabilities()?;
+        for (name, val) in &gs {
+            capabilities.check(name, val)?;
+        }
         let mut global = PdfGlobalSettings::new()?;
-        for (ref name, ref val) in &self.gs {
-            unsafe { global.set(name, &val) }?;
+        for (name, val) in gs {
+            unsafe { global.set(name, val) }?;
         }
         Ok(global)
     }
 
     /// Use the relevant settings to construct a low-level instance of `PdfObjectSettings`
+    ///
+    /// Returns `Error::Unsupported` if a setting requires features this wkhtmltopdf wasn't built with.
     pub fn object_settings(&self) -> Result<PdfObjectSettings> {
+        let (_, os) = self.settings();
+        let capabilities = self.detected_capabilities()?;
+        for (name, val) in &os {
+            capabilities.check(name, val)?;
+        }
         let mut object = PdfObjectSettings::new();
-        for (ref name, ref val) in &self.os {
-            unsafe { object.set(name, &val) }?;
+        for (name, val) in os {
+            unsafe { object.set(name, val) }?;
         }
         Ok(object)
     }
+
+    // Use the capabilities detected by the application that renders this builder's conversions
+    pub(crate) fn set_capabilities(&mut self, capabilities: Capabilities) -> &mut PdfBuilder {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    // The capabilities set by the application, which are only detected if the builder wasn't
+    //   instantiated by one (e.g. by the default `Renderer::pdf_builder`)
+    fn detected_capabilities(&self) -> Result<Capabilities> {
+        match self.capabilities {
+            Some(capabilities) => Ok(capabilities),
+            None => Capabilities::detect(),
+        }
+    }
+
+    // Builds a PDF with `build_page`, instrumented as a conversion of `input` ("url", "path", or "html")
+    fn build<'b>(
+        &self,
+        input: &'static str,
+        page: &str,
+        html: Option<&str>,
+    ) -> Result<PdfOutput<'b>> {
+        let (gs, os) = self.settings();
+        Conversion::start("pdf", input, gs.len() + os.len()).run(
+            || match self.limits.is_set() {
+                true => self.build_isolated(input, page, html),
+                false => self.build_page(page, html),
+            },
+            |output| output.data.len(),
+        )
+    }
+
+    // Builds a PDF in an isolated worker, which can be killed when the builder's limits are reached
+    fn build_isolated<'b>(
+        &self,
+        input: &str,
+        page: &str,
+        html: Option<&str>,
+    ) -> Result<PdfOutput<'b>> {
+        let source = Source::from_input(input, page, html)?;
+        let (data, diagnostics) = isolated::render_limited_pdf(self, source)?;
+        Ok(PdfOutput {
+            data: Cow::Owned(data),
+            pos: 0,
+            _converter: None,
+            diagnostics,
+        })
+    }
+
+    // Builds a PDF from a single page (or `html` if it is "-"), routing its requests through a proxy
+    //   if it is sandboxed and serving its assets if it is HTML
+    fn build_page<'b>(&self, page: &str, html: Option<&str>) -> Result<PdfOutput<'b>> {
+        let session = Session::start(self.sandbox.as_ref(), html.and(self.assets.as_ref()))?;
+        let global = self.global_settings()?;
+        let mut object = self.object_settings()?;
+        if let Some(proxy) = session.proxy_url() {
+            unsafe { object.set("load.proxy", &proxy) }?;
+        }
+        let mut converter = global.create_converter();
+        match html {
+            Some(html) => converter.add_html_object(object, &session.html(html))?,
+            None => converter.add_page_object(object, page)?,
+        }
+        let mut output = self.convert(converter)?;
+        output.diagnostics.blocked_requests = session.finish();
+        Ok(output)
+    }
+
+    // Runs the conversion, collecting the loads skipped or ignored by the builder's `LoadErrorPolicy`
+    fn convert<'b>(&self, mut converter: PdfConverter) -> Result<PdfOutput<'b>> {
+        let failed_loads = Arc::new(Mutex::new(Vec::new()));
+        let collected = failed_loads.clone();
+        converter.set_warning_callback(Some(Box::new(move |warn: String| {
+            match FailedLoad::from_warning(&warn) {
+                Some(failed) => lock_or_recover(&collected).push(failed),
+                None => debug!("wkhtmltopdf warning: {}", warn),
+            }
+        })));
+        let mut output = converter.convert()?;
+        output.diagnostics.failed_loads = mem::take(&mut *lock_or_recover(&failed_loads));
+        Ok(output)
+    }
 }
 
+#[cfg(any(feature = "native", feature = "dynamic"))]
 impl<'a> PdfOutput<'a> {
+    /// Problems reported during the conversion, e.g. requests blocked by the builder's `Sandbox`
+    pub fn diagnostics(&self) -> &Diagnostics {
+        &self.diagnostics
+    }
+
     // Helper to save the PDF output to a local file
     pub fn save<P: AsRef<Path>>(&mut self, path: P) -> io::Result<File> {
         let mut file = File::create(path)?;
@@ -396,12 +932,16 @@ impl<'a> PdfOutput<'a> {
     }
 }
 
+#[cfg(any(feature = "native", feature = "dynamic"))]
 impl<'a> Read for PdfOutput<'a> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.data.read(buf)
+        let n = (&self.data[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
     }
 }
 
+#[cfg(any(feature = "native", feature = "dynamic"))]
 impl<'a> std::fmt::Debug for PdfOutput<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         self.data.fmt(f)
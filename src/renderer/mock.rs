@@ -0,0 +1,214 @@
+//! A mock `Renderer` for testing code that generates PDFs and images
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+use super::Renderer;
+use crate::cancellation::Limits;
+use crate::error::*;
+use crate::image::ImageBuilder;
+use crate::pdf::PdfBuilder;
+use crate::source::Source;
+
+/// The kind of output requested from a `MockRenderer`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RenderKind {
+    Pdf,
+    Image,
+}
+
+/// A conversion recorded by `MockRenderer`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderCall {
+    /// Whether a PDF or image was requested
+    pub kind: RenderKind,
+    /// Global settings that would be passed to wkhtmltopdf or wkhtmltoimage
+    ///
+    /// For images, this includes the crop region and settings implied by the capture mode.
+    pub global_settings: BTreeMap<String, String>,
+    /// Object settings that would be passed to wkhtmltopdf (always empty for images)
+    pub object_settings: BTreeMap<String, String>,
+    /// The input document
+    pub source: Source,
+}
+
+/// A `Renderer` for tests that records conversions instead of performing them
+///
+/// Each conversion returns the next queued response (see `push_output` and `push_error`),
+///   or an empty output once the queue is exhausted. Conversions are still recorded if their
+///   builder's cancellation token was already cancelled, but return `Error::Cancelled`
+///   without consuming a response.
+#[derive(Default)]
+pub struct MockRenderer {
+    calls: Mutex<Vec<RenderCall>>,
+    responses: Mutex<VecDeque<Result<Vec<u8>>>>,
+}
+
+impl MockRenderer {
+    /// Instantiate a `MockRenderer` with no queued responses
+    pub fn new() -> MockRenderer {
+        MockRenderer::default()
+    }
+
+    /// Queue output to be returned by a future conversion
+    pub fn push_output(&self, output: Vec<u8>) -> &MockRenderer {
+        self.responses.lock().unwrap().push_back(Ok(output));
+        self
+    }
+
+    /// Queue an error to be returned by a future conversion
+    pub fn push_error(&self, err: Error) -> &MockRenderer {
+        self.responses.lock().unwrap().push_back(Err(err));
+        self
+    }
+
+    /// All conversions requested so far, in order
+    pub fn calls(&self) -> Vec<RenderCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, call: RenderCall, limits: &Limits) -> Result<Vec<u8>> {
+        self.calls.lock().unwrap().push(call);
+        limits.start().check()?;
+        self.responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| Ok(Vec::new()))
+    }
+}
+
+impl Renderer for MockRenderer {
+    fn render_pdf(&self, builder: &PdfBuilder, source: &Source) -> Result<Vec<u8>> {
+        let (gs, os) = builder.settings();
+        self.record(
+            RenderCall {
+                kind: RenderKind::Pdf,
+                global_settings: to_map(gs),
+                object_settings: to_map(os),
+                source: source.clone(),
+            },
+            builder.limits(),
+        )
+    }
+
+    fn render_image(&self, builder: &ImageBuilder, source: &Source) -> Result<Vec<u8>> {
+        self.record(
+            RenderCall {
+                kind: RenderKind::Image,
+                global_settings: to_map(builder.settings(true)),
+                object_settings: BTreeMap::new(),
+                source: source.clone(),
+            },
+            builder.limits(),
+        )
+    }
+}
+
+fn to_map<I, V>(settings: I) -> BTreeMap<String, String>
+where
+    I: IntoIterator<Item = (&'static str, V)>,
+    V: AsRef<str>,
+{
+    settings
+        .into_iter()
+        .map(|(name, val)| (name.to_string(), val.as_ref().to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::CancellationToken;
+    use crate::diagnostics::LoadErrorPolicy;
+    use crate::image::{CaptureMode, ImageFormat};
+    use crate::pdf::Orientation;
+
+    #[test]
+    fn records_pdf_settings_and_source() {
+        let mock = MockRenderer::new();
+        let mut builder = mock.pdf_builder();
+        builder
+            .orientation(Orientation::Landscape)
+            .title("Report")
+            .media_load_error_policy(LoadErrorPolicy::Skip);
+        unsafe {
+            builder.object_setting("load.debugJavascript", "true");
+        }
+
+        let source = Source::Html("<h1>Report</h1>".into());
+        let output = mock.render_pdf(&builder, &source).unwrap();
+        assert!(output.is_empty());
+
+        let calls = mock.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].kind, RenderKind::Pdf);
+        assert_eq!(calls[0].global_settings["orientation"], "Landscape");
+        assert_eq!(calls[0].global_settings["documentTitle"], "Report");
+        assert_eq!(calls[0].object_settings["load.debugJavascript"], "true");
+        assert_eq!(
+            calls[0].object_settings["load.mediaLoadErrorHandling"],
+            "skip"
+        );
+        assert_eq!(calls[0].source, source);
+    }
+
+    #[test]
+    fn records_image_capture_settings() {
+        let mock = MockRenderer::new();
+        let mut builder = mock.image_builder();
+        builder
+            .format(ImageFormat::Png)
+            .capture_mode(CaptureMode::Viewport {
+                width: 1200,
+                height: 630,
+            });
+
+        let url = "https://example.com/".parse().unwrap();
+        mock.render_image(&builder, &Source::Url(url)).unwrap();
+
+        let call = &mock.calls()[0];
+        assert_eq!(call.kind, RenderKind::Image);
+        assert_eq!(call.global_settings["fmt"], "png");
+        assert_eq!(call.global_settings["screenWidth"], "1200");
+        assert_eq!(call.global_settings["crop.height"], "630");
+        assert!(call.object_settings.is_empty());
+    }
+
+    #[test]
+    fn returns_queued_responses_in_order() {
+        let mock = MockRenderer::new();
+        mock.push_output(b"first".to_vec())
+            .push_error(Error::ConversionFailed("boom".into()));
+        let builder = mock.pdf_builder();
+        let source = Source::Html(String::new());
+
+        assert_eq!(mock.render_pdf(&builder, &source).unwrap(), b"first");
+        match mock.render_pdf(&builder, &source) {
+            Err(Error::ConversionFailed(msg)) => assert_eq!(msg, "boom"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(mock.render_pdf(&builder, &source).unwrap().is_empty());
+        assert_eq!(mock.calls().len(), 3);
+    }
+
+    #[test]
+    fn cancelled_calls_keep_queued_responses() {
+        let mock = MockRenderer::new();
+        mock.push_output(b"kept".to_vec());
+        let token = CancellationToken::new();
+        let mut builder = mock.pdf_builder();
+        builder.cancellation_token(token.clone());
+        let source = Source::Html(String::new());
+
+        token.cancel();
+        assert!(matches!(
+            mock.render_pdf(&builder, &source),
+            Err(Error::Cancelled)
+        ));
+        assert_eq!(mock.calls().len(), 1);
+        assert_eq!(
+            mock.render_pdf(&mock.pdf_builder(), &source).unwrap(),
+            b"kept"
+        );
+    }
+}
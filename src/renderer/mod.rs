@@ -0,0 +1,119 @@
+//! Abstraction over the backends that can render PDFs and images
+//!
+//! Application code written against the `Renderer` trait can use a real backend
+//!   (e.g. [`Wkhtmltox`](../struct.Wkhtmltox.html), [`RenderHandle`](../struct.RenderHandle.html),
+//!   [`IsolatedRenderer`](../struct.IsolatedRenderer.html), or [`SubprocessRenderer`](../struct.SubprocessRenderer.html))
+//!   in production and a [`MockRenderer`](struct.MockRenderer.html) in tests, which don't
+//!   require libwkhtmltox and aren't limited to initializing wkhtmltopdf once per process.
+//!
+//! ## Example
+//! ```
+//! use wkhtmltopdf::*;
+//!
+//! fn render_invoice<R: Renderer>(renderer: &R, number: u32) -> Result<Vec<u8>> {
+//!     let mut builder = renderer.pdf_builder();
+//!     builder.title(&format!("Invoice {}", number));
+//!     renderer.render_pdf(&builder, &Source::Html(format!("<h1>Invoice {}</h1>", number)))
+//! }
+//!
+//! let mock = MockRenderer::new();
+//! mock.push_output(b"%PDF-1.4".to_vec());
+//! assert_eq!(render_invoice(&mock, 42).unwrap(), b"%PDF-1.4");
+//!
+//! let calls = mock.calls();
+//! assert_eq!(calls[0].global_settings["documentTitle"], "Invoice 42");
+//! ```
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use std::io::Read;
+
+use crate::error::*;
+use crate::image::ImageBuilder;
+use crate::pdf::PdfBuilder;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::service::RenderHandle;
+use crate::source::Source;
+#[cfg(any(feature = "native", feature = "dynamic"))]
+use crate::wkhtmltox::Wkhtmltox;
+
+mod mock;
+pub use mock::*;
+
+/// A backend capable of rendering PDFs and images
+pub trait Renderer {
+    /// Instantiate a `PdfBuilder` for use with `render_pdf`
+    fn pdf_builder(&self) -> PdfBuilder {
+        PdfBuilder::new()
+    }
+
+    /// Instantiate an `ImageBuilder` for use with `render_image`
+    fn image_builder(&self) -> ImageBuilder {
+        ImageBuilder::new()
+    }
+
+    /// Render a PDF from `source` using the settings of `builder`
+    fn render_pdf(&self, builder: &PdfBuilder, source: &Source) -> Result<Vec<u8>>;
+
+    /// Render an image from `source` using the settings of `builder`
+    fn render_image(&self, builder: &ImageBuilder, source: &Source) -> Result<Vec<u8>>;
+}
+
+#[cfg(any(feature = "native", feature = "dynamic"))]
+impl Renderer for Wkhtmltox {
+    fn pdf_builder(&self) -> PdfBuilder {
+        Wkhtmltox::pdf_builder(self)
+    }
+
+    fn image_builder(&self) -> ImageBuilder {
+        Wkhtmltox::image_builder(self)
+    }
+
+    fn render_pdf(&self, builder: &PdfBuilder, source: &Source) -> Result<Vec<u8>> {
+        let mut output = builder
+            .clone()
+            .set_capabilities(self.capabilities())
+            .build_from_source(source)?;
+        read_all(&mut output)
+    }
+
+    fn render_image(&self, builder: &ImageBuilder, source: &Source) -> Result<Vec<u8>> {
+        let mut output = builder.clone().build_from_source(source)?;
+        read_all(&mut output)
+    }
+}
+
+#[cfg(any(feature = "native", feature = "dynamic"))]
+impl Renderer for RenderHandle {
+    fn render_pdf(&self, builder: &PdfBuilder, source: &Source) -> Result<Vec<u8>> {
+        RenderHandle::render_pdf(self, builder, source)
+    }
+
+    fn render_image(&self, builder: &ImageBuilder, source: &Source) -> Result<Vec<u8>> {
+        RenderHandle::render_image(self, builder, source)
+    }
+}
+
+impl<R: Renderer + ?Sized> Renderer for &R {
+    fn pdf_builder(&self) -> PdfBuilder {
+        (**self).pdf_builder()
+    }
+
+    fn image_builder(&self) -> ImageBuilder {
+        (**self).image_builder()
+    }
+
+    fn render_pdf(&self, builder: &PdfBuilder, source: &Source) -> Result<Vec<u8>> {
+        (**self).render_pdf(builder, source)
+    }
+
+    fn render_image(&self, builder: &ImageBuilder, source: &Source) -> Result<Vec<u8>> {
+        (**self).render_image(builder, source)
+    }
+}
+
+/// Read a generated output into an owned buffer
+#[cfg(any(feature = "native", feature = "dynamic"))]
+pub(crate) fn read_all<R: Read>(output: &mut R) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    output.read_to_end(&mut buf)?;
+    Ok(buf)
+}
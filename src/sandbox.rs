@@ -0,0 +1,413 @@
+//! Restrict the network and file access of the page being rendered
+//!
+//! Rendering untrusted HTML lets the page make requests to arbitrary hosts (e.g. internal services)
+//!   and read local files. A [`Sandbox`](struct.Sandbox.html) attached to a builder blocks both by default:
+//!
+//! - Network requests are routed through a proxy on the loopback interface that runs for the duration
+//!   of the conversion, and only reaches hosts or schemes explicitly allowed by the sandbox. Blocked requests
+//!   fail to load and are reported in the output's [`Diagnostics`](struct.Diagnostics.html).
+//! - Local file access is disabled with `load.blockLocalFileAccess`, except for the paths allowed by the sandbox.
+//!
+//! Only requests that wkhtmltopdf sends through its proxy are checked, and blocked requests are
+//!   only reported by the in-process backends (other backends log them instead).
+//!
+//! ## Example
+//! ```no_run
+//! # #[cfg(any(feature = "native", feature = "dynamic"))] {
+//! use wkhtmltopdf::*;
+//!
+//! let mut sandbox = Sandbox::new();
+//! sandbox.allow_host("cdn.example.com").allow_path("/srv/assets");
+//!
+//! let pdf_app = PdfApplication::new().expect("Failed to init PDF application");
+//! let pdfout = pdf_app.builder()
+//!     .sandbox(&sandbox)
+//!     .build_from_html(r#"<img src="http://169.254.169.254/latest/meta-data">"#)
+//!     .expect("failed to build pdf");
+//! for blocked in &pdfout.diagnostics().blocked_requests {
+//!     println!("{}", blocked);
+//! }
+//! # }
+//! ```
+use log::{debug, warn};
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use url::{Position, Url};
+
+use crate::diagnostics::BlockedRequest;
+use crate::http::{read_head, respond, Head, LoopbackServer};
+use crate::sys::lock_or_recover;
+
+/// A policy restricting the network and local file access of a page
+///
+/// A new sandbox blocks all network requests and local file access.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    hosts: Vec<String>,
+    schemes: Vec<String>,
+    block_local_files: bool,
+    allowed_paths: Vec<String>,
+    // Loopback servers run by the crate during a conversion, e.g. an `AssetServer`
+    servers: Vec<SocketAddr>,
+}
+
+impl Sandbox {
+    /// Instantiate a sandbox that blocks all network requests and local file access
+    pub fn new() -> Sandbox {
+        Sandbox {
+            hosts: Vec::new(),
+            schemes: Vec::new(),
+            block_local_files: true,
+            allowed_paths: Vec::new(),
+            servers: Vec::new(),
+        }
+    }
+
+    /// Allow requests to `host`, e.g. "cdn.example.com"
+    ///
+    /// A leading `*.` allows any subdomain, e.g. "*.example.com" allows "static.example.com"
+    ///   (but not "example.com").
+    pub fn allow_host<S: Into<String>>(&mut self, host: S) -> &mut Sandbox {
+        self.hosts.push(host.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Allow requests to any host using `scheme`, e.g. "https"
+    pub fn allow_scheme<S: Into<String>>(&mut self, scheme: S) -> &mut Sandbox {
+        self.schemes.push(scheme.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Block the page from reading local files, except for allowed paths (default true)
+    pub fn block_local_files(&mut self, block_local_files: bool) -> &mut Sandbox {
+        self.block_local_files = block_local_files;
+        self
+    }
+
+    /// Allow the page to read the file at `path`, or any file within it if it is a directory
+    pub fn allow_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Sandbox {
+        self.allowed_paths
+            .push(path.as_ref().to_string_lossy().into_owned());
+        self
+    }
+
+    // Whether a request to `host` using `scheme` may be made
+    pub(crate) fn allows(&self, scheme: &str, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.schemes.iter().any(|allowed| allowed == scheme)
+            || self
+                .hosts
+                .iter()
+                .any(|allowed| match allowed.strip_prefix("*.") {
+                    Some(domain) => host
+                        .strip_suffix(domain)
+                        .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                    None => *allowed == host,
+                })
+    }
+
+    // Allow requests to a server run by the crate itself
+    pub(crate) fn allow_server(&mut self, addr: SocketAddr) -> &mut Sandbox {
+        self.servers.push(addr);
+        self
+    }
+
+    // Whether `host:port` is a server allowed with `allow_server`
+    pub(crate) fn allows_server(&self, host: &str, port: u16) -> bool {
+        self.servers
+            .iter()
+            .any(|addr| addr.port() == port && addr.ip().to_string() == host)
+    }
+}
+
+// The load settings that restrict local file access to the paths allowed by `sandbox` and to `root_dir`,
+//   as well as to the `generated` files written by the crate for wkhtmltopdf (e.g. rendered headers)
+//
+// The proxy can't be included since it only runs during a conversion (see `SandboxProxy`).
+pub(crate) fn file_access_settings<'a>(
+    sandbox: Option<&'a Sandbox>,
+    root_dir: Option<&'a str>,
+    generated: &'a [String],
+) -> Vec<(&'static str, &'a str)> {
+    if sandbox.is_none() && root_dir.is_none() {
+        return Vec::new();
+    }
+    let block = root_dir.is_some() || sandbox.is_some_and(|sandbox| sandbox.block_local_files);
+    let mut settings = vec![(
+        "load.blockLocalFileAccess",
+        if block { "true" } else { "false" },
+    )];
+    if block {
+        // The equivalent of `wkhtmltopdf --allow`
+        let allowed = sandbox
+            .into_iter()
+            .flat_map(|sandbox| &sandbox.allowed_paths);
+        let allowed = allowed.chain(generated).map(String::as_str);
+        for path in allowed.chain(root_dir) {
+            settings.push(("load.allowed.append", path));
+        }
+    }
+    settings
+}
+
+impl Default for Sandbox {
+    fn default() -> Sandbox {
+        Sandbox::new()
+    }
+}
+
+/// An HTTP proxy enforcing a `Sandbox` for the duration of a conversion
+///
+/// The proxy stops when dropped.
+pub(crate) struct SandboxProxy {
+    server: LoopbackServer,
+    // Only returned by the in-process backends
+    #[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+    blocked: Arc<Mutex<Vec<BlockedRequest>>>,
+}
+
+impl SandboxProxy {
+    /// Start a proxy on an ephemeral loopback port
+    pub(crate) fn start(sandbox: &Sandbox) -> io::Result<SandboxProxy> {
+        let blocked = Arc::new(Mutex::new(Vec::new()));
+        let server = {
+            let sandbox = sandbox.clone();
+            let blocked = blocked.clone();
+            LoopbackServer::start("sandbox proxy", move |client| {
+                proxy(client, &sandbox, &blocked)
+            })?
+        };
+        Ok(SandboxProxy { server, blocked })
+    }
+
+    /// The value of the `load.proxy` setting that routes requests through this proxy
+    pub(crate) fn url(&self) -> String {
+        format!("http://{}", self.server.addr())
+    }
+
+    /// Stop the proxy, returning the requests it blocked
+    #[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+    pub(crate) fn finish(self) -> Vec<BlockedRequest> {
+        std::mem::take(&mut *lock_or_recover(&self.blocked))
+    }
+}
+
+// Handle one proxy connection: a `CONNECT host:port` tunnel for HTTPS, or an absolute-form HTTP request
+fn proxy(
+    mut client: TcpStream,
+    sandbox: &Sandbox,
+    blocked: &Mutex<Vec<BlockedRequest>>,
+) -> io::Result<()> {
+    let (head, body) = read_head(&mut client)?;
+    let Head {
+        method,
+        target,
+        version,
+        headers,
+    } = match head {
+        Some(head) => head,
+        None => return respond(&mut client, "400 Bad Request"),
+    };
+    let connect = method.eq_ignore_ascii_case("CONNECT");
+    let url = if connect {
+        Url::parse(&format!("https://{}", target))
+    } else {
+        Url::parse(&target)
+    };
+    let url = match url {
+        Ok(url) => url,
+        Err(_) => return respond(&mut client, "400 Bad Request"),
+    };
+    let allowed = match url.host_str() {
+        Some(host) => {
+            let host = host.trim_matches(|c| c == '[' || c == ']');
+            sandbox.allows(url.scheme(), host)
+                || url
+                    .port_or_known_default()
+                    .is_some_and(|port| sandbox.allows_server(host, port))
+        }
+        None => false,
+    };
+    if !allowed {
+        let url = if connect {
+            format!("https://{}", target)
+        } else {
+            url.to_string()
+        };
+        warn!("sandbox blocked request to {}", url);
+        lock_or_recover(blocked).push(BlockedRequest { url });
+        return respond(&mut client, "403 Forbidden");
+    }
+
+    let mut upstream = match url
+        .socket_addrs(|| None)
+        .and_then(|addrs| TcpStream::connect(&*addrs))
+    {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            debug!("sandbox proxy failed to connect to {}: {}", url, err);
+            return respond(&mut client, "502 Bad Gateway");
+        }
+    };
+    if connect {
+        client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")?;
+    } else {
+        // Forward the request in origin-form, closing the connection after a single response
+        //   so that later requests on it are checked by the sandbox too
+        let mut forwarded = format!(
+            "{} {} {}\r\n",
+            method,
+            &url[Position::BeforePath..],
+            version
+        );
+        for line in headers.iter().filter(|line| !line.is_empty()) {
+            let name = line.split(':').next().unwrap_or_default().trim();
+            if !name.eq_ignore_ascii_case("connection")
+                && !name.eq_ignore_ascii_case("proxy-connection")
+            {
+                forwarded.push_str(line);
+                forwarded.push_str("\r\n");
+            }
+        }
+        forwarded.push_str("Connection: close\r\n\r\n");
+        upstream.write_all(forwarded.as_bytes())?;
+    }
+    upstream.write_all(&body)?;
+    tunnel(client, upstream)
+}
+
+// Copy bytes in both directions until either side closes its connection
+fn tunnel(client: TcpStream, upstream: TcpStream) -> io::Result<()> {
+    let (mut client_read, mut upstream_write) = (client.try_clone()?, upstream.try_clone()?);
+    let upload = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut upstream_write);
+        let _ = upstream_write.shutdown(Shutdown::Write);
+    });
+    let (mut upstream_read, mut client_write) = (upstream, client);
+    let _ = io::copy(&mut upstream_read, &mut client_write);
+    let _ = client_write.shutdown(Shutdown::Both);
+    let _ = upload.join();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn allows_listed_hosts_and_schemes() {
+        let mut sandbox = Sandbox::new();
+        assert!(!sandbox.allows("http", "example.com"));
+
+        sandbox.allow_host("Example.com").allow_host("*.cdn.net");
+        assert!(sandbox.allows("http", "example.com"));
+        assert!(sandbox.allows("https", "EXAMPLE.COM"));
+        assert!(!sandbox.allows("http", "www.example.com"));
+        assert!(sandbox.allows("https", "static.cdn.net"));
+        assert!(!sandbox.allows("https", "cdn.net"));
+        assert!(!sandbox.allows("https", "evilcdn.net"));
+
+        sandbox.allow_scheme("https");
+        assert!(sandbox.allows("https", "169.254.169.254"));
+        assert!(!sandbox.allows("http", "169.254.169.254"));
+    }
+
+    #[test]
+    fn blocks_local_files_except_allowed_paths() {
+        let mut sandbox = Sandbox::new();
+        sandbox.allow_path("/srv/assets");
+        assert_eq!(
+            file_access_settings(Some(&sandbox), None, &[]),
+            [
+                ("load.blockLocalFileAccess", "true"),
+                ("load.allowed.append", "/srv/assets"),
+            ]
+        );
+        sandbox.block_local_files(false);
+        assert_eq!(
+            file_access_settings(Some(&sandbox), None, &[]),
+            [("load.blockLocalFileAccess", "false")]
+        );
+
+        // A root directory always blocks local files outside of it
+        assert_eq!(
+            file_access_settings(Some(&sandbox), Some("/srv/site"), &[]),
+            [
+                ("load.blockLocalFileAccess", "true"),
+                ("load.allowed.append", "/srv/assets"),
+                ("load.allowed.append", "/srv/site"),
+            ]
+        );
+        assert!(file_access_settings(None, None, &[]).is_empty());
+
+        // Files generated by the crate are allowed whenever local files are blocked
+        let generated = ["/tmp/header.html".to_string()];
+        assert_eq!(
+            file_access_settings(None, Some("/srv/site"), &generated),
+            [
+                ("load.blockLocalFileAccess", "true"),
+                ("load.allowed.append", "/tmp/header.html"),
+                ("load.allowed.append", "/srv/site"),
+            ]
+        );
+    }
+
+    #[test]
+    fn proxy_reports_blocked_requests() {
+        // An upstream server that answers a single request
+        let upstream = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut conn, _) = upstream.accept().unwrap();
+            let (head, _) = read_head(&mut conn).unwrap();
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .unwrap();
+            let head = head.unwrap();
+            format!(
+                "{} {} {}\r\n{}",
+                head.method,
+                head.target,
+                head.version,
+                head.headers.join("\r\n")
+            )
+        });
+
+        let mut sandbox = Sandbox::new();
+        sandbox.allow_host("127.0.0.1");
+        let proxy = SandboxProxy::start(&sandbox).unwrap();
+        let request = |target: &str| {
+            let mut conn = TcpStream::connect(proxy.server.addr()).unwrap();
+            write!(
+                conn,
+                "GET {} HTTP/1.1\r\nProxy-Connection: keep-alive\r\n\r\n",
+                target
+            )
+            .unwrap();
+            let mut response = String::new();
+            conn.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = request(&format!("http://{}/style.css", upstream_addr));
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("ok"));
+        assert_eq!(
+            server.join().unwrap(),
+            "GET /style.css HTTP/1.1\r\nConnection: close"
+        );
+
+        let response = request("http://internal.example/secrets");
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+        assert_eq!(
+            proxy.finish(),
+            [BlockedRequest {
+                url: "http://internal.example/secrets".into()
+            }]
+        );
+    }
+}
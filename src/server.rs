@@ -0,0 +1,1016 @@
+//! Render PDFs and images for HTTP clients (`server` feature)
+//!
+//! A [`Server`](struct.Server.html) accepts conversion requests over HTTP and renders them with
+//!   any [`Renderer`](../renderer/trait.Renderer.html). The `wkhtmltopdf-server` binary serves a
+//!   [`RenderHandle`](../struct.RenderHandle.html), so every conversion runs on the dedicated
+//!   thread of a [`RenderService`](../service/index.html).
+//!
+//! | Request         | Response                                                         |
+//! |-----------------|------------------------------------------------------------------|
+//! | `POST /pdf`     | The rendered PDF (`application/pdf`)                             |
+//! | `POST /image`   | The rendered image (`image/png` unless `format` is set)          |
+//! | `GET /health`   | `ok` while the server is accepting connections                   |
+//! | `GET /metrics`  | Request and render counters in the Prometheus text format        |
+//!
+//! The body of a render request is a JSON object with either the `html` to render or the `url`
+//!   of the page to render (only `http` and `https` URLs are accepted), and optionally `settings`
+//!   for the builder:
+//!
+//! ```json
+//! {
+//!     "html": "<h1>Invoice 42</h1>",
+//!     "settings": { "title": "Invoice 42", "page_size": "Letter", "margin_mm": 15 }
+//! }
+//! ```
+//!
+//! PDF settings are `page_size` (e.g. "A4"), `page_width_mm` and `page_height_mm` (a custom page size),
+//!   `orientation` ("portrait" or "landscape"), `margin_mm` (either a number, or an object with
+//!   `top`, `right`, `bottom`, and `left`), `dpi`, `image_quality`, `title`, `outline`, `timeout_ms`,
+//!   `load_error_policy`, and `media_load_error_policy` ("abort", "skip", or "ignore").
+//!
+//! Image settings are `format` ("png", "jpg", "bmp", or "svg"), `screen_width`, `screen_height`,
+//!   `smart_width`, `zoom`, `crop_left`, `crop_top`, `crop_width`, `crop_height`, `capture`
+//!   (`"full_page"`, `{"viewport": {"width": 1024, "height": 768}}`, or `{"element": "#chart"}`),
+//!   `image_quality`, `transparent`, `timeout_ms`, `load_error_policy`, and `media_load_error_policy`.
+//!   A `timeout_ms` longer than the server's `ServerOptions::timeout` is capped to it.
+//!
+//! ## Security
+//!
+//! Without a sandbox, the HTML of a request may load any file readable by the server, and both `html`
+//!   and `url` requests make the server fetch any URL it can reach, including internal services and
+//!   cloud metadata endpoints. Servers reachable by untrusted clients should set a
+//!   [`Sandbox`](../sandbox/struct.Sandbox.html) with `ServerOptions::sandbox`, which the
+//!   `wkhtmltopdf-server` binary does unless it is run with `--no-sandbox`.
+//!
+//! Unknown fields are rejected with `400 Bad Request`, as are conversions that fail because of
+//!   the request (e.g. HTML containing a NUL byte). Pages that fail to load are reported with
+//!   `502 Bad Gateway`, timeouts with `504 Gateway Timeout`, and other failures with
+//!   `500 Internal Server Error`, each with the error message as a plain text body.
+//!   Connections past `ServerOptions::max_connections` are answered with `503 Service Unavailable`.
+//!
+//! ## Example
+//! ```
+//! use wkhtmltopdf::server::{Server, ServerOptions};
+//! use wkhtmltopdf::*;
+//!
+//! let renderer = MockRenderer::new();
+//! renderer.push_output(b"%PDF-1.4".to_vec());
+//!
+//! let mut options = ServerOptions::new();
+//! options.max_body_size(1024 * 1024);
+//! let server = Server::bind(renderer, "127.0.0.1:0", &options).expect("failed to start server");
+//! println!("listening on http://{}/", server.addr());
+//! ```
+use log::{debug, warn};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::diagnostics::LoadErrorPolicy;
+use crate::error::*;
+use crate::http::{read_head, respond_with, Head, LoopbackServer};
+use crate::image::{CaptureMode, ImageBuilder, ImageFormat};
+use crate::pdf::{Orientation, PageSize, PdfBuilder, Size};
+use crate::renderer::Renderer;
+use crate::sandbox::Sandbox;
+use crate::source::Source;
+
+// How long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Settings shared by every request to a `Server`
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    max_body_size: usize,
+    max_connections: usize,
+    timeout: Option<Duration>,
+    sandbox: Option<Sandbox>,
+}
+
+impl ServerOptions {
+    /// Instantiate the default options
+    pub fn new() -> ServerOptions {
+        ServerOptions::default()
+    }
+
+    /// Largest request body accepted, in bytes (default 10 MiB)
+    ///
+    /// Larger requests are rejected with `413 Payload Too Large` before their body is read.
+    pub fn max_body_size(&mut self, max_body_size: usize) -> &mut ServerOptions {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Most connections handled at once, including those waiting for a conversion (default 64)
+    ///
+    /// Conversions by a `RenderHandle` run one at a time, so further connections are answered with
+    ///   `503 Service Unavailable` instead of each holding a thread and its request body while they wait.
+    pub fn max_connections(&mut self, max_connections: usize) -> &mut ServerOptions {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Timeout for every conversion (default none)
+    ///
+    /// Requests may set a shorter `timeout_ms`, but not a longer one.
+    ///
+    /// With an in-process renderer (e.g. a `RenderHandle`), conversions with a timeout are run by
+    ///   an isolated worker that is killed when the timeout is reached, so a hung page can't stall
+    ///   the server. This requires calling `init_isolated_worker` at the start of `main`.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut ServerOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Restrict the network and local file access of every rendered page (default unrestricted)
+    ///
+    /// Without a sandbox, the HTML of a request may load any file readable by the server, and requests
+    ///   can make the server fetch URLs on hosts only it can reach (e.g. internal services).
+    ///   The page of a `url` request must also be allowed by the sandbox.
+    pub fn sandbox(&mut self, sandbox: &Sandbox) -> &mut ServerOptions {
+        self.sandbox = Some(sandbox.clone());
+        self
+    }
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions {
+        ServerOptions {
+            max_body_size: 10 * 1024 * 1024,
+            max_connections: 64,
+            timeout: None,
+            sandbox: None,
+        }
+    }
+}
+
+/// An HTTP server rendering PDFs and images, handling up to `max_connections` connections at once,
+///   each on its own thread
+///
+/// The server stops accepting connections when dropped.
+pub struct Server {
+    http: LoopbackServer,
+}
+
+impl Server {
+    /// Start serving conversions rendered by `renderer` on `addr`, e.g. "0.0.0.0:8080"
+    pub fn bind<R, A>(renderer: R, addr: A, options: &ServerOptions) -> io::Result<Server>
+    where
+        R: Renderer + Send + Sync + 'static,
+        A: ToSocketAddrs,
+    {
+        let state = Arc::new(State {
+            renderer,
+            options: options.clone(),
+            metrics: Metrics::default(),
+        });
+        let busy = state.clone();
+        let http = LoopbackServer::bind(
+            "render server",
+            addr,
+            options.max_connections,
+            move |client| reject(client, &busy),
+            move |client| serve(client, &state),
+        )?;
+        Ok(Server { http })
+    }
+
+    /// The address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.http.addr()
+    }
+
+    /// Block the current thread while the server is running
+    pub fn wait(self) {
+        self.http.wait()
+    }
+}
+
+struct State<R> {
+    renderer: R,
+    options: ServerOptions,
+    metrics: Metrics,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: Vec<u8>) -> Response {
+        Response {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+
+    fn text<S: Into<String>>(status: &'static str, message: S) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: message.into().into_bytes(),
+        }
+    }
+}
+
+// Handle one request, closing the connection after responding
+fn serve<R: Renderer>(mut client: TcpStream, state: &State<R>) -> io::Result<()> {
+    client.set_read_timeout(Some(READ_TIMEOUT))?;
+    let (head, body) = read_head(&mut client)?;
+    let (route, response) = match head {
+        Some(head) => route(&mut client, state, &head, body)?,
+        None => (
+            "other",
+            Response::text("400 Bad Request", "malformed request"),
+        ),
+    };
+    state.metrics.record_request(route, response.status);
+    respond_with(
+        &mut client,
+        response.status,
+        response.content_type,
+        &response.body,
+    )
+}
+
+// Answer a connection past `max_connections` without reading its request
+fn reject<R>(mut client: TcpStream, state: &State<R>) {
+    let status = "503 Service Unavailable";
+    state.metrics.record_request("other", status);
+    // This runs on the thread accepting connections, so a slow client must not hold it up for long
+    let _ = client.set_write_timeout(Some(Duration::from_millis(100)));
+    let _ = client.set_read_timeout(Some(Duration::from_millis(100)));
+    let _ = respond_with(
+        &mut client,
+        status,
+        "text/plain; charset=utf-8",
+        b"too many connections",
+    );
+    // Closing with an unread request would reset the connection before the client reads the response
+    let _ = client.shutdown(Shutdown::Write);
+    let _ = io::copy(&mut (&client).take(64 * 1024), &mut io::sink());
+}
+
+// The route that handled the request (for metrics) and its response
+fn route<R: Renderer>(
+    client: &mut TcpStream,
+    state: &State<R>,
+    head: &Head,
+    body: Vec<u8>,
+) -> io::Result<(&'static str, Response)> {
+    let path = head.target.split(['?', '#']).next().unwrap_or_default();
+    let (route, method) = match path {
+        "/pdf" => ("/pdf", "POST"),
+        "/image" => ("/image", "POST"),
+        "/health" => ("/health", "GET"),
+        "/metrics" => ("/metrics", "GET"),
+        _ => return Ok(("other", Response::text("404 Not Found", "not found"))),
+    };
+    if head.method != method {
+        let message = format!("{} only accepts {}", route, method);
+        return Ok((route, Response::text("405 Method Not Allowed", message)));
+    }
+    let response = match route {
+        "/health" => Response::text("200 OK", "ok"),
+        "/metrics" => Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: state.metrics.render().into_bytes(),
+        },
+        _ => match read_body(client, head, body, state.options.max_body_size)? {
+            Ok(body) if route == "/pdf" => render_pdf(state, &body),
+            Ok(body) => render_image(state, &body),
+            Err(response) => response,
+        },
+    };
+    Ok((route, response))
+}
+
+// Read the request body, or the response rejecting it
+fn read_body(
+    client: &mut TcpStream,
+    head: &Head,
+    mut body: Vec<u8>,
+    max_body_size: usize,
+) -> io::Result<std::result::Result<Vec<u8>, Response>> {
+    if head.header("Transfer-Encoding").is_some() {
+        let message = "chunked requests are not supported, send a Content-Length";
+        return Ok(Err(Response::text("411 Length Required", message)));
+    }
+    let length = match head.header("Content-Length").map(str::parse::<usize>) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => {
+            return Ok(Err(Response::text(
+                "400 Bad Request",
+                "invalid Content-Length",
+            )));
+        }
+        None => {
+            return Ok(Err(Response::text(
+                "411 Length Required",
+                "missing Content-Length",
+            )))
+        }
+    };
+    if length > max_body_size {
+        let message = format!("request body is larger than {} bytes", max_body_size);
+        return Ok(Err(Response::text("413 Payload Too Large", message)));
+    }
+    if body.len() < length {
+        client
+            .take((length - body.len()) as u64)
+            .read_to_end(&mut body)?;
+        if body.len() < length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    body.truncate(length);
+    Ok(Ok(body))
+}
+
+// The timeout requested with `timeout_ms`, capped by the server's timeout so clients can't lift it
+fn timeout(options: &ServerOptions, timeout_ms: Option<u64>) -> Option<Duration> {
+    match (options.timeout, timeout_ms.map(Duration::from_millis)) {
+        (Some(limit), Some(requested)) => Some(limit.min(requested)),
+        (limit, requested) => limit.or(requested),
+    }
+}
+
+fn render_pdf<R: Renderer>(state: &State<R>, body: &[u8]) -> Response {
+    let request = match parse::<PdfSettings>(body) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let mut builder = state.renderer.pdf_builder();
+    if let Some(timeout) = timeout(&state.options, request.settings.timeout_ms) {
+        builder.timeout(timeout);
+    }
+    if let Some(ref sandbox) = state.options.sandbox {
+        builder.sandbox(sandbox);
+    }
+    if let Err(message) = request.settings.apply(&mut builder) {
+        return Response::text("400 Bad Request", message);
+    }
+    let result = state.metrics.time("pdf", || {
+        state.renderer.render_pdf(&builder, &request.source)
+    });
+    match result {
+        Ok(pdf) => Response::ok("application/pdf", pdf),
+        Err(err) => failure(&err),
+    }
+}
+
+fn render_image<R: Renderer>(state: &State<R>, body: &[u8]) -> Response {
+    let request = match parse::<ImageSettings>(body) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let mut builder = state.renderer.image_builder();
+    if let Some(timeout) = timeout(&state.options, request.settings.timeout_ms) {
+        builder.timeout(timeout);
+    }
+    if let Some(ref sandbox) = state.options.sandbox {
+        builder.sandbox(sandbox);
+    }
+    let format = request.settings.format.unwrap_or(Format::Png);
+    request.settings.apply(&mut builder);
+    let result = state.metrics.time("image", || {
+        state.renderer.render_image(&builder, &request.source)
+    });
+    match result {
+        Ok(image) => Response::ok(format.content_type(), image),
+        Err(err) => failure(&err),
+    }
+}
+
+// The response for a failed conversion
+fn failure(err: &Error) -> Response {
+    let status = match err {
+        Error::InvalidInput(_) | Error::PathNotAllowed(_) | Error::ElementNotFound(_) => {
+            "400 Bad Request"
+        }
+        Error::LoadFailed { .. } | Error::NetworkError { .. } => "502 Bad Gateway",
+        Error::ServiceStopped => "503 Service Unavailable",
+        Error::Timeout(_) => "504 Gateway Timeout",
+        _ => "500 Internal Server Error",
+    };
+    let message = describe_sources(err);
+    if status.starts_with('5') {
+        warn!("render server: conversion failed: {}", message);
+    }
+    Response::text(status, message)
+}
+
+// The body of a render request, with settings of type `S`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(bound = "S: Deserialize<'de> + Default")]
+struct RawRequest<S> {
+    html: Option<String>,
+    url: Option<String>,
+    #[serde(default)]
+    settings: S,
+}
+
+struct Request<S> {
+    source: Source,
+    settings: S,
+}
+
+fn parse<S>(body: &[u8]) -> std::result::Result<Request<S>, Response>
+where
+    S: for<'de> Deserialize<'de> + Default,
+{
+    let bad_request = |message: String| Response::text("400 Bad Request", message);
+    let raw: RawRequest<S> = serde_json::from_slice(body)
+        .map_err(|err| bad_request(format!("invalid request: {}", err)))?;
+    let source = match (raw.html, raw.url) {
+        (Some(html), None) => Source::Html(html),
+        (None, Some(url)) => {
+            let url = Url::parse(&url)
+                .map_err(|err| bad_request(format!("invalid url '{}': {}", url, err)))?;
+            if url.scheme() != "http" && url.scheme() != "https" {
+                return Err(bad_request(format!(
+                    "unsupported url scheme '{}'",
+                    url.scheme()
+                )));
+            }
+            Source::Url(url)
+        }
+        _ => {
+            return Err(bad_request(
+                "expected exactly one of 'html' or 'url'".into(),
+            ))
+        }
+    };
+    Ok(Request {
+        source,
+        settings: raw.settings,
+    })
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Policy {
+    Abort,
+    Skip,
+    Ignore,
+}
+
+impl From<Policy> for LoadErrorPolicy {
+    fn from(policy: Policy) -> LoadErrorPolicy {
+        match policy {
+            Policy::Abort => LoadErrorPolicy::Abort,
+            Policy::Skip => LoadErrorPolicy::Skip,
+            Policy::Ignore => LoadErrorPolicy::Ignore,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PageOrientation {
+    Portrait,
+    Landscape,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(untagged)]
+enum MarginMm {
+    All(u32),
+    Sides {
+        top: u32,
+        right: u32,
+        bottom: u32,
+        left: u32,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PdfSettings {
+    page_size: Option<String>,
+    page_width_mm: Option<u32>,
+    page_height_mm: Option<u32>,
+    orientation: Option<PageOrientation>,
+    margin_mm: Option<MarginMm>,
+    dpi: Option<u32>,
+    image_quality: Option<u32>,
+    title: Option<String>,
+    outline: Option<bool>,
+    timeout_ms: Option<u64>,
+    load_error_policy: Option<Policy>,
+    media_load_error_policy: Option<Policy>,
+}
+
+impl PdfSettings {
+    // Apply the settings to `builder`, or describe why they are invalid
+    fn apply(&self, builder: &mut PdfBuilder) -> std::result::Result<(), String> {
+        match (&self.page_size, self.page_width_mm, self.page_height_mm) {
+            (None, None, None) => {}
+            (Some(name), None, None) => match PageSize::from_name(name) {
+                Some(size) => {
+                    builder.page_size(size);
+                }
+                None => return Err(format!("unknown page_size '{}'", name)),
+            },
+            (None, Some(width), Some(height)) => {
+                builder.page_size(PageSize::Custom(
+                    Size::Millimeters(width),
+                    Size::Millimeters(height),
+                ));
+            }
+            _ => {
+                return Err(
+                    "expected either page_size, or both page_width_mm and page_height_mm".into(),
+                )
+            }
+        }
+        if let Some(orientation) = self.orientation {
+            builder.orientation(match orientation {
+                PageOrientation::Portrait => Orientation::Portrait,
+                PageOrientation::Landscape => Orientation::Landscape,
+            });
+        }
+        match self.margin_mm {
+            Some(MarginMm::All(margin)) => {
+                builder.margin(Size::Millimeters(margin));
+            }
+            Some(MarginMm::Sides {
+                top,
+                right,
+                bottom,
+                left,
+            }) => {
+                builder.margin((
+                    Size::Millimeters(top),
+                    Size::Millimeters(right),
+                    Size::Millimeters(bottom),
+                    Size::Millimeters(left),
+                ));
+            }
+            None => {}
+        }
+        if let Some(dpi) = self.dpi {
+            builder.dpi(dpi);
+        }
+        if let Some(image_quality) = self.image_quality {
+            builder.image_quality(image_quality);
+        }
+        if let Some(ref title) = self.title {
+            builder.title(title);
+        }
+        if let Some(outline) = self.outline {
+            builder.outline(if outline { Some(4) } else { None });
+        }
+        if let Some(policy) = self.load_error_policy {
+            builder.load_error_policy(policy.into());
+        }
+        if let Some(policy) = self.media_load_error_policy {
+            builder.media_load_error_policy(policy.into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    Png,
+    Jpg,
+    Bmp,
+    Svg,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Png => "image/png",
+            Format::Jpg => "image/jpeg",
+            Format::Bmp => "image/bmp",
+            Format::Svg => "image/svg+xml",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Capture {
+    FullPage,
+    Viewport { width: u32, height: u32 },
+    Element(String),
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ImageSettings {
+    format: Option<Format>,
+    screen_width: Option<u32>,
+    screen_height: Option<u32>,
+    smart_width: Option<bool>,
+    zoom: Option<f32>,
+    crop_left: Option<u32>,
+    crop_top: Option<u32>,
+    crop_width: Option<u32>,
+    crop_height: Option<u32>,
+    capture: Option<Capture>,
+    image_quality: Option<u32>,
+    transparent: Option<bool>,
+    timeout_ms: Option<u64>,
+    load_error_policy: Option<Policy>,
+    media_load_error_policy: Option<Policy>,
+}
+
+impl ImageSettings {
+    fn apply(&self, builder: &mut ImageBuilder) {
+        builder.format(match self.format.unwrap_or(Format::Png) {
+            Format::Png => ImageFormat::Png,
+            Format::Jpg => ImageFormat::Jpg,
+            Format::Bmp => ImageFormat::Bmp,
+            Format::Svg => ImageFormat::Svg,
+        });
+        if let Some(screen_width) = self.screen_width {
+            builder.screen_width(screen_width);
+        }
+        if let Some(screen_height) = self.screen_height {
+            builder.screen_height(screen_height);
+        }
+        if let Some(smart_width) = self.smart_width {
+            builder.smart_width(smart_width);
+        }
+        if let Some(zoom) = self.zoom {
+            builder.zoom(zoom);
+        }
+        if let Some(crop_left) = self.crop_left {
+            builder.crop_left(crop_left);
+        }
+        if let Some(crop_top) = self.crop_top {
+            builder.crop_top(crop_top);
+        }
+        if let Some(crop_width) = self.crop_width {
+            builder.crop_width(crop_width);
+        }
+        if let Some(crop_height) = self.crop_height {
+            builder.crop_height(crop_height);
+        }
+        if let Some(ref capture) = self.capture {
+            builder.capture_mode(match capture.clone() {
+                Capture::FullPage => CaptureMode::FullPage,
+                Capture::Viewport { width, height } => CaptureMode::Viewport { width, height },
+                Capture::Element(selector) => CaptureMode::Element(selector),
+            });
+        }
+        if let Some(image_quality) = self.image_quality {
+            builder.image_quality(image_quality);
+        }
+        if let Some(transparent) = self.transparent {
+            builder.transparent(transparent);
+        }
+        if let Some(policy) = self.load_error_policy {
+            builder.load_error_policy(policy.into());
+        }
+        if let Some(policy) = self.media_load_error_policy {
+            builder.media_load_error_policy(policy.into());
+        }
+    }
+}
+
+// Counters exposed by `GET /metrics`
+#[derive(Default)]
+struct Metrics {
+    // Keyed by route and status code
+    requests: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    // Keyed by the kind of output
+    renders: Mutex<BTreeMap<&'static str, RenderStats>>,
+    in_progress: AtomicUsize,
+}
+
+#[derive(Default)]
+struct RenderStats {
+    count: u64,
+    failures: u64,
+    seconds: f64,
+    bytes: u64,
+}
+
+impl Metrics {
+    fn record_request(&self, route: &'static str, status: &'static str) {
+        let code = status.split(' ').next().unwrap_or(status);
+        let mut requests = self.requests.lock().unwrap_or_else(|err| err.into_inner());
+        *requests.entry((route, code)).or_default() += 1;
+    }
+
+    // Run a conversion, recording how long it took and what it produced
+    fn time<F>(&self, kind: &'static str, render: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        self.in_progress.fetch_add(1, Ordering::SeqCst);
+        let start = Instant::now();
+        let result = render();
+        let elapsed = start.elapsed();
+        self.in_progress.fetch_sub(1, Ordering::SeqCst);
+        debug!("render server: {} conversion took {:?}", kind, elapsed);
+
+        let mut renders = self.renders.lock().unwrap_or_else(|err| err.into_inner());
+        let stats = renders.entry(kind).or_default();
+        stats.count += 1;
+        stats.seconds += elapsed.as_secs_f64();
+        match result {
+            Ok(ref output) => stats.bytes += output.len() as u64,
+            Err(_) => stats.failures += 1,
+        }
+        result
+    }
+
+    // The metrics in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP wkhtmltopdf_requests_total HTTP requests handled, by route and status"
+        );
+        let _ = writeln!(out, "# TYPE wkhtmltopdf_requests_total counter");
+        for ((route, status), count) in self
+            .requests
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+        {
+            let _ = writeln!(
+                out,
+                "wkhtmltopdf_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                route, status, count
+            );
+        }
+
+        let renders = self.renders.lock().unwrap_or_else(|err| err.into_inner());
+        let _ = writeln!(
+            out,
+            "# HELP wkhtmltopdf_render_duration_seconds Time spent on conversions"
+        );
+        let _ = writeln!(out, "# TYPE wkhtmltopdf_render_duration_seconds summary");
+        for (kind, stats) in renders.iter() {
+            let _ = writeln!(
+                out,
+                "wkhtmltopdf_render_duration_seconds_sum{{kind=\"{}\"}} {}",
+                kind, stats.seconds
+            );
+            let _ = writeln!(
+                out,
+                "wkhtmltopdf_render_duration_seconds_count{{kind=\"{}\"}} {}",
+                kind, stats.count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP wkhtmltopdf_render_failures_total Conversions that failed"
+        );
+        let _ = writeln!(out, "# TYPE wkhtmltopdf_render_failures_total counter");
+        for (kind, stats) in renders.iter() {
+            let _ = writeln!(
+                out,
+                "wkhtmltopdf_render_failures_total{{kind=\"{}\"}} {}",
+                kind, stats.failures
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP wkhtmltopdf_rendered_bytes_total Bytes of output produced by conversions"
+        );
+        let _ = writeln!(out, "# TYPE wkhtmltopdf_rendered_bytes_total counter");
+        for (kind, stats) in renders.iter() {
+            let _ = writeln!(
+                out,
+                "wkhtmltopdf_rendered_bytes_total{{kind=\"{}\"}} {}",
+                kind, stats.bytes
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP wkhtmltopdf_renders_in_progress Conversions currently running"
+        );
+        let _ = writeln!(out, "# TYPE wkhtmltopdf_renders_in_progress gauge");
+        let _ = writeln!(
+            out,
+            "wkhtmltopdf_renders_in_progress {}",
+            self.in_progress.load(Ordering::SeqCst)
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::{MockRenderer, RenderKind};
+    use std::io::Write;
+
+    // A renderer shared between the server and the test
+    struct Shared(Arc<MockRenderer>);
+
+    impl Renderer for Shared {
+        fn render_pdf(&self, builder: &PdfBuilder, source: &Source) -> Result<Vec<u8>> {
+            self.0.render_pdf(builder, source)
+        }
+
+        fn render_image(&self, builder: &ImageBuilder, source: &Source) -> Result<Vec<u8>> {
+            self.0.render_image(builder, source)
+        }
+    }
+
+    fn start(options: &ServerOptions) -> (Server, Arc<MockRenderer>) {
+        let mock = Arc::new(MockRenderer::new());
+        let server = Server::bind(Shared(mock.clone()), "127.0.0.1:0", options).unwrap();
+        (server, mock)
+    }
+
+    // Send a request, returning the status line and body of the response
+    fn request(server: &Server, method: &str, path: &str, body: Option<&str>) -> (String, Vec<u8>) {
+        let mut conn = TcpStream::connect(server.addr()).unwrap();
+        write!(conn, "{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path).unwrap();
+        if let Some(body) = body {
+            write!(conn, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        } else {
+            write!(conn, "\r\n").unwrap();
+        }
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).unwrap();
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..end]).into_owned();
+        let status = head.lines().next().unwrap().to_string();
+        (status, response[end + 4..].to_vec())
+    }
+
+    #[test]
+    fn renders_pdfs_with_typed_settings() {
+        let (server, mock) = start(&ServerOptions::new());
+        mock.push_output(b"%PDF-1.4".to_vec());
+        let (status, body) = request(
+            &server,
+            "POST",
+            "/pdf",
+            Some(
+                r#"{"html": "<h1>Invoice</h1>", "settings": {"title": "Invoice", "page_size": "letter", "orientation": "landscape", "margin_mm": {"top": 1, "right": 2, "bottom": 3, "left": 4}, "load_error_policy": "skip"}}"#,
+            ),
+        );
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, b"%PDF-1.4");
+
+        let calls = mock.calls();
+        assert_eq!(calls[0].kind, RenderKind::Pdf);
+        assert_eq!(calls[0].source, Source::Html("<h1>Invoice</h1>".into()));
+        assert_eq!(calls[0].global_settings["documentTitle"], "Invoice");
+        assert_eq!(calls[0].global_settings["size.pageSize"], "Letter");
+        assert_eq!(calls[0].global_settings["orientation"], "Landscape");
+        assert_eq!(calls[0].global_settings["margin.left"], "4mm");
+        assert_eq!(calls[0].object_settings["load.loadErrorHandling"], "skip");
+    }
+
+    #[test]
+    fn renders_images_from_urls() {
+        let (server, mock) = start(&ServerOptions::new());
+        mock.push_output(vec![0xff, 0xd8]);
+        let (status, body) = request(
+            &server,
+            "POST",
+            "/image",
+            Some(
+                r#"{"url": "https://example.com/", "settings": {"format": "jpg", "capture": {"viewport": {"width": 800, "height": 600}}}}"#,
+            ),
+        );
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, vec![0xff, 0xd8]);
+
+        let calls = mock.calls();
+        assert_eq!(calls[0].kind, RenderKind::Image);
+        assert_eq!(
+            calls[0].source,
+            Source::Url("https://example.com/".parse().unwrap())
+        );
+        assert_eq!(calls[0].global_settings["fmt"], "jpg");
+        assert_eq!(calls[0].global_settings["screenWidth"], "800");
+        assert_eq!(calls[0].global_settings["crop.height"], "600");
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let (server, mock) = start(ServerOptions::new().max_body_size(64));
+        let bad = |path: &str, body: &str| request(&server, "POST", path, Some(body)).0;
+        assert_eq!(bad("/pdf", "not json"), "HTTP/1.1 400 Bad Request");
+        assert_eq!(
+            bad("/pdf", r#"{"html": "a", "url": "http://b/"}"#),
+            "HTTP/1.1 400 Bad Request"
+        );
+        assert_eq!(
+            bad("/pdf", r#"{"url": "file:///etc/passwd"}"#),
+            "HTTP/1.1 400 Bad Request"
+        );
+        assert_eq!(
+            bad("/pdf", r#"{"html": "a", "settings": {"titel": "b"}}"#),
+            "HTTP/1.1 400 Bad Request"
+        );
+        assert_eq!(
+            bad("/pdf", r#"{"html": "a", "settings": {"page_size": "A0"}}"#),
+            "HTTP/1.1 400 Bad Request"
+        );
+        assert_eq!(
+            bad("/image", &format!(r#"{{"html": "{}"}}"#, "a".repeat(64))),
+            "HTTP/1.1 413 Payload Too Large"
+        );
+        assert_eq!(
+            request(&server, "POST", "/pdf", None).0,
+            "HTTP/1.1 411 Length Required"
+        );
+        assert_eq!(
+            request(&server, "GET", "/pdf", None).0,
+            "HTTP/1.1 405 Method Not Allowed"
+        );
+        assert_eq!(
+            request(&server, "GET", "/nope", None).0,
+            "HTTP/1.1 404 Not Found"
+        );
+        assert!(mock.calls().is_empty());
+    }
+
+    #[test]
+    fn rejects_connections_past_the_limit() {
+        let (server, _) = start(ServerOptions::new().max_connections(1));
+        // Holds the only connection until it is dropped, since the server waits for its request
+        let idle = TcpStream::connect(server.addr()).unwrap();
+        let (status, body) = request(&server, "GET", "/health", None);
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(body, b"too many connections");
+
+        drop(idle);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while request(&server, "GET", "/health", None).0 != "HTTP/1.1 200 OK" {
+            assert!(Instant::now() < deadline, "connection was never released");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn caps_requested_timeouts() {
+        let mut options = ServerOptions::new();
+        assert_eq!(timeout(&options, None), None);
+        assert_eq!(
+            timeout(&options, Some(500)),
+            Some(Duration::from_millis(500))
+        );
+
+        options.timeout(Duration::from_secs(1));
+        assert_eq!(timeout(&options, None), Some(Duration::from_secs(1)));
+        assert_eq!(
+            timeout(&options, Some(500)),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(
+            timeout(&options, Some(60_000)),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn reports_failures_and_metrics() {
+        let (server, mock) = start(&ServerOptions::new());
+        mock.push_error(Error::NetworkError {
+            url: "http://bad/".into(),
+            reason: "Host bad not found".into(),
+        });
+        let (status, body) = request(&server, "POST", "/pdf", Some(r#"{"url": "http://bad/"}"#));
+        assert_eq!(status, "HTTP/1.1 502 Bad Gateway");
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "Network error loading 'http://bad/': Host bad not found"
+        );
+        mock.push_output(b"%PDF".to_vec());
+        request(&server, "POST", "/pdf", Some(r#"{"html": "ok"}"#));
+
+        let (status, body) = request(&server, "GET", "/health", None);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, b"ok");
+
+        let (status, body) = request(&server, "GET", "/metrics", None);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let metrics = String::from_utf8(body).unwrap();
+        assert!(metrics.contains("wkhtmltopdf_requests_total{route=\"/pdf\",status=\"200\"} 1\n"));
+        assert!(metrics.contains("wkhtmltopdf_requests_total{route=\"/pdf\",status=\"502\"} 1\n"));
+        assert!(
+            metrics.contains("wkhtmltopdf_requests_total{route=\"/health\",status=\"200\"} 1\n")
+        );
+        assert!(metrics.contains("wkhtmltopdf_render_duration_seconds_count{kind=\"pdf\"} 2\n"));
+        assert!(metrics.contains("wkhtmltopdf_render_failures_total{kind=\"pdf\"} 1\n"));
+        assert!(metrics.contains("wkhtmltopdf_rendered_bytes_total{kind=\"pdf\"} 4\n"));
+        assert!(metrics.contains("wkhtmltopdf_renders_in_progress 0\n"));
+    }
+}
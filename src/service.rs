@@ -0,0 +1,200 @@
+//! Render from any thread using a dedicated wkhtmltopdf thread
+//!
+//! Wkhtmltopdf can only be initialized once per process, and all conversions must happen
+//!   on the thread that initialized it. `RenderService` starts a thread that owns a
+//!   [`Wkhtmltox`](struct.Wkhtmltox.html) instance, and hands out cloneable `RenderHandle`s that
+//!   queue conversions to that thread and block until the result is available.
+//!
+//! ## Example
+//! ```no_run
+//! use wkhtmltopdf::*;
+//! use std::thread;
+//!
+//! let service = RenderService::start().expect("Failed to start render service");
+//! let workers: Vec<_> = (0..4)
+//!     .map(|i| {
+//!         let handle = service.handle();
+//!         thread::spawn(move || {
+//!             let mut builder = handle.pdf_builder();
+//!             builder.title(&format!("Invoice {}", i));
+//!             let html = format!("<h1>Invoice {}</h1>", i);
+//!             handle.render_pdf(&builder, &Source::Html(html))
+//!         })
+//!     })
+//!     .collect();
+//!
+//! for worker in workers {
+//!     let pdf = worker.join().unwrap().expect("failed to render pdf");
+//!     println!("rendered {} bytes", pdf.len());
+//! }
+//! ```
+use log::debug;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::cancellation::Limits;
+use crate::error::*;
+use crate::image::ImageBuilder;
+use crate::pdf::PdfBuilder;
+use crate::renderer::read_all;
+use crate::source::Source;
+use crate::wkhtmltox::Wkhtmltox;
+
+type Reply = mpsc::Sender<Result<Vec<u8>>>;
+
+enum Job {
+    Pdf(PdfBuilder, Source, Reply),
+    Image(ImageBuilder, Source, Reply),
+    Shutdown,
+}
+
+/// A thread dedicated to wkhtmltopdf and wkhtmltoimage conversions
+///
+/// Wkhtmltopdf and wkhtmltoimage are initialized when the service starts, and deinitialized
+///   when it is dropped. Since wkhtmltopdf may only be initialized once per process,
+///   no other `Wkhtmltox`, `PdfApplication`, or `ImageApplication` may be created alongside it.
+///
+/// Dropping the service waits for queued conversions to finish, after which any remaining
+///   `RenderHandle`s return `Error::ServiceStopped`.
+pub struct RenderService {
+    handle: RenderHandle,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// A cloneable handle for queueing conversions to a `RenderService`
+///
+/// Handles are `Send` and `Sync`, so they may be shared with any thread.
+#[derive(Clone)]
+pub struct RenderHandle {
+    tx: mpsc::Sender<Job>,
+}
+
+impl RenderService {
+    /// Start the render thread and initialize wkhtmltopdf and wkhtmltoimage on it
+    ///
+    /// Returns `Error::IllegalInit` if wkhtmltopdf or wkhtmltoimage was already initialized in this process.
+    pub fn start() -> Result<RenderService> {
+        let (tx, rx) = mpsc::channel();
+        let (init_tx, init_rx) = mpsc::channel();
+
+        let thread = thread::Builder::new()
+            .name("wkhtmltox".into())
+            .spawn(move || match Wkhtmltox::new() {
+                Ok(wk) => {
+                    let _ = init_tx.send(Ok(()));
+                    run(&wk, rx);
+                }
+                Err(err) => {
+                    let _ = init_tx.send(Err(err));
+                }
+            })?;
+
+        init_rx.recv().map_err(|_| Error::ServiceStopped)??;
+        Ok(RenderService {
+            handle: RenderHandle { tx },
+            thread: Some(thread),
+        })
+    }
+
+    /// Get a handle for queueing conversions to this service
+    pub fn handle(&self) -> RenderHandle {
+        self.handle.clone()
+    }
+}
+
+impl RenderHandle {
+    /// Instantiate a `PdfBuilder` for use with `render_pdf`
+    pub fn pdf_builder(&self) -> PdfBuilder {
+        PdfBuilder::new()
+    }
+
+    /// Instantiate an `ImageBuilder` for use with `render_image`
+    pub fn image_builder(&self) -> ImageBuilder {
+        ImageBuilder::new()
+    }
+
+    /// Queue a PDF conversion and block until it completes
+    ///
+    /// If the builder's timeout is reached or its cancellation token is cancelled, this returns
+    ///   immediately. The render thread runs such conversions in an isolated worker that is killed
+    ///   when the limit is reached, so it isn't stalled by a hung conversion
+    ///   (see the [`cancellation`](cancellation/index.html) module).
+    pub fn render_pdf(&self, builder: &PdfBuilder, source: &Source) -> Result<Vec<u8>> {
+        self.send(builder.limits(), |reply| {
+            Job::Pdf(builder.clone(), source.clone(), reply)
+        })
+    }
+
+    /// Queue an image conversion and block until it completes
+    ///
+    /// Timeouts and cancellation behave as they do for `render_pdf`.
+    pub fn render_image(&self, builder: &ImageBuilder, source: &Source) -> Result<Vec<u8>> {
+        self.send(builder.limits(), |reply| {
+            Job::Image(builder.clone(), source.clone(), reply)
+        })
+    }
+
+    fn send<F: FnOnce(Reply) -> Job>(&self, limits: &Limits, job: F) -> Result<Vec<u8>> {
+        let watch = limits.start();
+        watch.check()?;
+        let (reply, rx) = mpsc::channel();
+        self.tx
+            .send(job(reply))
+            .map_err(|_| Error::ServiceStopped)?;
+        if !watch.is_limited() {
+            return rx.recv().map_err(|_| Error::ServiceStopped)?;
+        }
+        loop {
+            match rx.recv_timeout(watch.poll_interval()) {
+                Ok(res) => return res,
+                Err(mpsc::RecvTimeoutError::Timeout) => watch.check()?,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err(Error::ServiceStopped),
+            }
+        }
+    }
+}
+
+impl Drop for RenderService {
+    fn drop(&mut self) {
+        let _ = self.handle.tx.send(Job::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Process jobs until shutdown; the caller deinitializes wkhtmltopdf when this returns
+fn run(wk: &Wkhtmltox, rx: mpsc::Receiver<Job>) {
+    for job in rx {
+        match job {
+            Job::Pdf(mut builder, source, reply) => {
+                debug!("render service: pdf job");
+                let res = builder
+                    .set_capabilities(wk.capabilities())
+                    .build_from_source(&source)
+                    .and_then(|mut out| read_all(&mut out));
+                let _ = reply.send(res);
+            }
+            Job::Image(mut builder, source, reply) => {
+                debug!("render service: image job");
+                let res = builder
+                    .build_from_source(&source)
+                    .and_then(|mut out| read_all(&mut out));
+                let _ = reply.send(res);
+            }
+            Job::Shutdown => break,
+        }
+    }
+    debug!("render service stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync + Clone>() {}
+        assert_send_sync::<RenderHandle>();
+    }
+}
@@ -0,0 +1,126 @@
+//! The loopback servers that run alongside a single conversion
+use std::borrow::Cow;
+use std::io;
+
+use crate::assets::{AssetBundle, AssetServer};
+use crate::diagnostics::BlockedRequest;
+use crate::sandbox::{Sandbox, SandboxProxy};
+use crate::source::Source;
+
+/// The sandbox proxy and asset server of a conversion, both stopped when dropped
+pub(crate) struct Session {
+    proxy: Option<SandboxProxy>,
+    assets: Option<AssetServer>,
+}
+
+impl Session {
+    /// Start the servers needed by a conversion with `sandbox` that is served `assets`
+    pub(crate) fn start(
+        sandbox: Option<&Sandbox>,
+        assets: Option<&AssetBundle>,
+    ) -> io::Result<Session> {
+        let assets = assets.map(AssetServer::start).transpose()?;
+        let proxy = match (sandbox, &assets) {
+            (Some(sandbox), Some(assets)) => {
+                // The page may always reach its own assets
+                let mut sandbox = sandbox.clone();
+                sandbox.allow_server(assets.addr());
+                Some(SandboxProxy::start(&sandbox)?)
+            }
+            (Some(sandbox), None) => Some(SandboxProxy::start(sandbox)?),
+            (None, _) => None,
+        };
+        Ok(Session { proxy, assets })
+    }
+
+    /// The value of the `load.proxy` setting, if requests have to go through the sandbox proxy
+    pub(crate) fn proxy_url(&self) -> Option<String> {
+        self.proxy.as_ref().map(SandboxProxy::url)
+    }
+
+    /// Point relative links in `html` to the asset server, if assets are served
+    pub(crate) fn html<'a>(&self, html: &'a str) -> Cow<'a, str> {
+        match self.assets {
+            Some(ref assets) => inject_base(html, &assets.base_url()),
+            None => Cow::Borrowed(html),
+        }
+    }
+
+    /// `source`, with relative links in HTML pointed to the asset server
+    pub(crate) fn prepare(&self, source: Source) -> Source {
+        match source {
+            Source::Html(html) => match self.html(&html) {
+                Cow::Owned(html) => Source::Html(html),
+                Cow::Borrowed(_) => Source::Html(html),
+            },
+            source => source,
+        }
+    }
+
+    /// Stop the servers, returning the requests blocked by the sandbox
+    #[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+    pub(crate) fn finish(self) -> Vec<BlockedRequest> {
+        match self.proxy {
+            Some(proxy) => proxy.finish(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// Add `<base href="base">` at the start of the document's head, unless it already has a base URL
+//
+// Since HTML doesn't have to include `<head>` or `<html>`, the tag is prepended if neither is found.
+fn inject_base<'a>(html: &'a str, base: &str) -> Cow<'a, str> {
+    // Lowercasing ASCII keeps the byte offsets of the original
+    let lower = html.to_ascii_lowercase();
+    if find_tag(&lower, "base").is_some() {
+        return Cow::Borrowed(html);
+    }
+    let tag = format!(r#"<base href="{}">"#, base);
+    let at = find_tag(&lower, "head")
+        .or_else(|| find_tag(&lower, "html"))
+        .and_then(|start| lower[start..].find('>').map(|end| start + end + 1))
+        .unwrap_or(0);
+    let mut injected = String::with_capacity(html.len() + tag.len());
+    injected.push_str(&html[..at]);
+    injected.push_str(&tag);
+    injected.push_str(&html[at..]);
+    Cow::Owned(injected)
+}
+
+// The offset of the first opening `name` tag in lowercase `html`
+fn find_tag(html: &str, name: &str) -> Option<usize> {
+    let open = format!("<{}", name);
+    html.match_indices(&open)
+        .map(|(start, _)| start)
+        .find(|start| {
+            html[start + open.len()..]
+                .chars()
+                .next()
+                .is_some_and(|c| c == '>' || c == '/' || c.is_ascii_whitespace())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn injects_base_url() {
+        let base = "http://127.0.0.1:8080/";
+        assert_eq!(
+            inject_base("<HTML><Head lang=en><title>t</title></head>", base),
+            r#"<HTML><Head lang=en><base href="http://127.0.0.1:8080/"><title>t</title></head>"#
+        );
+        assert_eq!(
+            inject_base("<html><header>x</header></html>", base),
+            r#"<html><base href="http://127.0.0.1:8080/"><header>x</header></html>"#
+        );
+        assert_eq!(
+            inject_base("<h1>Hi</h1>", base),
+            r#"<base href="http://127.0.0.1:8080/"><h1>Hi</h1>"#
+        );
+        let own_base = r#"<head><base href="https://example.com/"></head>"#;
+        assert_eq!(inject_base(own_base, base), own_base);
+    }
+}
@@ -0,0 +1,358 @@
+//! Input documents that can be converted by either builder
+use log::warn;
+use std::collections::hash_map::RandomState;
+use std::fs::{self, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::error::*;
+
+/// The input document for a conversion
+///
+/// This is an owned equivalent of the arguments to the `build_from_*` builder methods,
+///   useful when a conversion has to be described before it can be run (e.g. when
+///   queueing it to a [`RenderService`](struct.RenderService.html)).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// Load the document from a URL
+    Url(Url),
+    /// Load the document from a local HTML file
+    Path(PathBuf),
+    /// Use the provided HTML as the document
+    Html(String),
+}
+
+impl From<Url> for Source {
+    fn from(url: Url) -> Source {
+        Source::Url(url)
+    }
+}
+
+impl From<PathBuf> for Source {
+    fn from(path: PathBuf) -> Source {
+        Source::Path(path)
+    }
+}
+
+impl Source {
+    // Resolves a `Path` source with `resolve_path`, leaving other sources unchanged
+    pub(crate) fn resolve(
+        &self,
+        root_dir: Option<&str>,
+        setting: &str,
+        missing: fn(String, String) -> Error,
+    ) -> Result<Source> {
+        match self {
+            Source::Path(path) => Ok(Source::Path(resolve_path(
+                root_dir, path, setting, missing,
+            )?)),
+            source => Ok(source.clone()),
+        }
+    }
+
+    // The source of a builder's `input` of `kind` ("url", "path", or "html", which is read from `html`)
+    #[cfg(any(feature = "native", feature = "dynamic"))]
+    pub(crate) fn from_input(kind: &str, input: &str, html: Option<&str>) -> Result<Source> {
+        match (kind, html) {
+            (_, Some(html)) => Ok(Source::Html(html.to_string())),
+            ("url", None) => Url::parse(input)
+                .map(Source::Url)
+                .map_err(|err| Error::InvalidInput(format!("invalid URL '{}': {}", input, err))),
+            _ => Ok(Source::Path(input.into())),
+        }
+    }
+
+    // The kind of input, for instrumentation
+    #[cfg_attr(not(feature = "subprocess"), allow(dead_code))]
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Source::Url(_) => "url",
+            Source::Path(_) => "path",
+            Source::Html(_) => "html",
+        }
+    }
+}
+
+// Checks that `path` is a file, and that it is within `root_dir` (if any) after resolving symlinks and `..`
+//
+// Relative paths are resolved against `root_dir`. A missing file is reported with `missing` for `setting`,
+//   since wkhtmltopdf silently falls back to treating it as a URL:
+//   https://github.com/wkhtmltopdf/wkhtmltopdf/blob/5fb6a6e479409c0a270e56d852a5a9e7b2b7651b/src/lib/multipageloader.cc#L690
+pub(crate) fn resolve_path(
+    root_dir: Option<&str>,
+    path: &Path,
+    setting: &str,
+    missing: fn(String, String) -> Error,
+) -> Result<PathBuf> {
+    let not_found = || {
+        warn!("the file {} does not exist", path.to_string_lossy());
+        missing(setting.to_string(), path.to_string_lossy().to_string())
+    };
+    let root = match root_dir {
+        Some(root) => Path::new(root).canonicalize()?,
+        None if path.is_file() => return Ok(path.to_path_buf()),
+        None => return Err(not_found()),
+    };
+    let resolved = root.join(path).canonicalize().map_err(|_| not_found())?;
+    if !resolved.starts_with(&root) {
+        warn!(
+            "the file {} is outside of {}",
+            path.to_string_lossy(),
+            root.to_string_lossy()
+        );
+        return Err(Error::PathNotAllowed(path.to_path_buf()));
+    }
+    if !resolved.is_file() {
+        return Err(not_found());
+    }
+    Ok(resolved)
+}
+
+/// An HTML document read from bytes, e.g. by `build_from_reader`
+#[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+pub(crate) enum Document {
+    /// The document was UTF-8 (or UTF-16 with a byte order mark), so it can be passed as a string
+    Html(String),
+    /// The document is in another encoding, which wkhtmltopdf has to decode from a file
+    Encoded {
+        data: Vec<u8>,
+        charset: Option<String>,
+    },
+}
+
+// Decodes `data` as HTML, returning `Error::InvalidInput` if it contains NUL bytes
+//
+// wkhtmltopdf only accepts HTML strings as UTF-8, so documents in other encodings are returned
+//   with the charset they declare (if any) for wkhtmltopdf to decode them.
+#[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+pub(crate) fn decode_html(data: Vec<u8>) -> Result<Document> {
+    let utf16 = match data.get(..2) {
+        Some([0xFF, 0xFE]) => Some(u16::from_le_bytes as fn([u8; 2]) -> u16),
+        Some([0xFE, 0xFF]) => Some(u16::from_be_bytes as fn([u8; 2]) -> u16),
+        _ => None,
+    };
+    let html = match utf16 {
+        Some(from_bytes) => {
+            let units: Vec<u16> = data[2..]
+                .chunks(2)
+                .map(|unit| from_bytes([unit[0], *unit.get(1).unwrap_or(&0)]))
+                .collect();
+            String::from_utf16(&units)
+                .map_err(|_| Error::InvalidInput("HTML is not valid UTF-16".into()))?
+        }
+        None => {
+            let data = match data.strip_prefix(b"\xEF\xBB\xBF") {
+                Some(data) => data.to_vec(),
+                None => data,
+            };
+            if let Some(offset) = data.iter().position(|&b| b == 0) {
+                return Err(nul_byte(offset));
+            }
+            match String::from_utf8(data) {
+                Ok(html) => html,
+                Err(err) => {
+                    let data = err.into_bytes();
+                    let charset = declared_charset(&data);
+                    return Ok(Document::Encoded { data, charset });
+                }
+            }
+        }
+    };
+    match html.find('\0') {
+        Some(offset) => Err(nul_byte(offset)),
+        None => Ok(Document::Html(html)),
+    }
+}
+
+fn nul_byte(offset: usize) -> Error {
+    Error::InvalidInput(format!("HTML contains a NUL byte at offset {}", offset))
+}
+
+// The charset declared by a `<meta>` tag within the first 1024 bytes, like browsers' prescan
+fn declared_charset(data: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]).to_ascii_lowercase();
+    head.match_indices("<meta").find_map(|(start, _)| {
+        let tag = &head[start
+            ..head[start..]
+                .find('>')
+                .map_or(head.len(), |end| start + end)];
+        let value = &tag[tag.find("charset")? + "charset".len()..];
+        let value = value.trim_start().strip_prefix('=')?.trim_start();
+        let charset: String = value
+            .trim_start_matches(['"', '\''])
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || "-_.:".contains(*c))
+            .collect();
+        Some(charset).filter(|charset| !charset.is_empty())
+    })
+}
+
+/// A document written to a file that wkhtmltopdf can load
+///
+/// The file has a random name, is only readable by the current user, and is removed when dropped.
+#[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+pub(crate) struct TempHtml {
+    path: PathBuf,
+}
+
+#[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+impl TempHtml {
+    pub(crate) fn write<D: AsRef<[u8]>>(name: &str, data: D) -> Result<TempHtml> {
+        const ATTEMPTS: usize = 16;
+        for _ in 0..ATTEMPTS {
+            // The temp dir is shared with other users, so the name must not be predictable and
+            // `create_new` refuses to follow a symlink or reuse a file planted at that path
+            let path = std::env::temp_dir().join(format!(
+                "wkhtmltopdf-rs-{:016x}-{}.html",
+                random(),
+                name
+            ));
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = match options.open(&path) {
+                Ok(file) => file,
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err.into()),
+            };
+            // Removes the file if writing fails
+            let temp = TempHtml { path };
+            file.write_all(data.as_ref())?;
+            return Ok(temp);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "failed to create a unique temporary file",
+        )
+        .into())
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+// 64 random bits from the randomly seeded keys of std's `HashMap` hasher
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+impl Drop for TempHtml {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_paths_within_root_dir() {
+        let dir = std::env::temp_dir().join(format!("wkhtmltopdf-rs-root-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("site");
+        fs::create_dir_all(root.join("pages")).unwrap();
+        fs::write(root.join("pages/index.html"), "<h1>index</h1>").unwrap();
+        fs::write(dir.join("secret.html"), "<h1>secret</h1>").unwrap();
+        let root_dir = root.to_str();
+        let resolve =
+            |path: &str| resolve_path(root_dir, path.as_ref(), "page", Error::ObjectSettingFailure);
+
+        let index = root.canonicalize().unwrap().join("pages/index.html");
+        assert_eq!(resolve("pages/index.html").unwrap(), index);
+        assert_eq!(resolve(index.to_str().unwrap()).unwrap(), index);
+        assert!(matches!(
+            resolve("../secret.html"),
+            Err(Error::PathNotAllowed(_))
+        ));
+        assert!(matches!(
+            resolve(dir.join("secret.html").to_str().unwrap()),
+            Err(Error::PathNotAllowed(_))
+        ));
+        match resolve("pages/missing.html") {
+            Err(Error::ObjectSettingFailure(name, _)) => assert_eq!(name, "page"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("secret.html"), root.join("link.html")).unwrap();
+            assert!(matches!(
+                resolve("link.html"),
+                Err(Error::PathNotAllowed(_))
+            ));
+        }
+
+        assert!(matches!(
+            resolve_path(
+                None,
+                "missing.html".as_ref(),
+                "in",
+                Error::GlobalSettingFailure
+            ),
+            Err(Error::GlobalSettingFailure(..))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decodes_html() {
+        let html = |data: &[u8]| match decode_html(data.to_vec()) {
+            Ok(Document::Html(html)) => html,
+            _ => panic!("not decoded as HTML: {:?}", data),
+        };
+        assert_eq!(html("<p>caf\u{e9}</p>".as_bytes()), "<p>caf\u{e9}</p>");
+        assert_eq!(html(b"\xEF\xBB\xBF<p>"), "<p>");
+        assert_eq!(html(b"\xFF\xFE<\0p\0>\0"), "<p>");
+        assert_eq!(html(b"\xFE\xFF\0<\0p\0>"), "<p>");
+
+        let latin1 = b"<head><meta http-equiv=\"Content-Type\" content=\"text/html; charset=ISO-8859-1\"></head>caf\xE9";
+        match decode_html(latin1.to_vec()) {
+            Ok(Document::Encoded { data, charset }) => {
+                assert_eq!(data, &latin1[..]);
+                assert_eq!(charset.as_deref(), Some("iso-8859-1"));
+            }
+            _ => panic!("not detected as encoded"),
+        }
+        assert!(matches!(
+            decode_html(b"<meta charset='shift_jis'>\x82\xA0".to_vec()),
+            Ok(Document::Encoded { charset: Some(ref charset), .. }) if charset == "shift_jis"
+        ));
+        assert!(matches!(
+            decode_html(b"caf\xE9".to_vec()),
+            Ok(Document::Encoded { charset: None, .. })
+        ));
+
+        match decode_html(b"<p>\0</p>".to_vec()) {
+            Err(Error::InvalidInput(msg)) => assert!(msg.ends_with("offset 3"), "{}", msg),
+            _ => panic!("NUL byte not rejected"),
+        }
+    }
+
+    #[test]
+    fn removes_temporary_html() {
+        let file = TempHtml::write("header", "<p>header</p>").unwrap();
+        let path = file.path().to_path_buf();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "<p>header</p>");
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn creates_unique_private_files() {
+        let first = TempHtml::write("input", "a").unwrap();
+        let second = TempHtml::write("input", "b").unwrap();
+        assert_ne!(first.path(), second.path());
+        assert_eq!(fs::read_to_string(second.path()).unwrap(), "b");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(first.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
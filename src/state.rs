@@ -0,0 +1,122 @@
+//! Process-wide state shared by the wkhtmltopdf and wkhtmltoimage backends
+//!
+//! Both backends drive the same Qt application, so they share the rules that Qt imposes:
+//!
+//! - all conversions must happen on the thread that first initialized either backend
+//! - only one conversion (PDF or image) may be in progress at a time
+//! - once both backends have been deinitialized, Qt is torn down and cannot be initialized again
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::sys::{lock, lock_or_recover};
+
+/// The libwkhtmltox backends
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Backend {
+    Pdf,
+    Image,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum BackendState {
+    // Backend has not yet been initialized
+    New,
+    // Backend is available for conversions
+    Ready,
+    // Once dropped, the backend cannot be used again for the life of this process
+    Dropped,
+}
+
+struct State {
+    pdf: BackendState,
+    image: BackendState,
+    // Set while any conversion holds the backend, so new conversions return `Error::Blocked`
+    busy: bool,
+    // The thread that first initialized either backend
+    init_thread: Option<usize>,
+    // Set once Qt has been torn down by deinitializing every backend
+    torn_down: bool,
+}
+
+impl State {
+    fn backend(&mut self, backend: Backend) -> &mut BackendState {
+        match backend {
+            Backend::Pdf => &mut self.pdf,
+            Backend::Image => &mut self.image,
+        }
+    }
+
+    fn check_thread(&self) -> Result<()> {
+        match self.init_thread {
+            // A lot of QT functionality expects to run from the same thread that it was first initialized on
+            Some(init_thread) if init_thread != thread_id::get() => {
+                Err(Error::ThreadMismatch(init_thread, thread_id::get()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State {
+        pdf: BackendState::New,
+        image: BackendState::New,
+        busy: false,
+        init_thread: None,
+        torn_down: false,
+    });
+}
+
+/// Initialize `backend` using `init`, which returns whether the underlying initialization succeeded
+///
+/// Each backend may only be initialized once per process, and only from the thread
+///   that initialized the other backend (if any).
+pub(crate) fn init<F: FnOnce() -> bool>(backend: Backend, init: F) -> Result<()> {
+    let mut state = lock(&STATE, "backend state")?;
+    if state.torn_down || *state.backend(backend) != BackendState::New {
+        return Err(Error::IllegalInit);
+    }
+    state.check_thread()?;
+
+    if init() {
+        *state.backend(backend) = BackendState::Ready;
+        state.init_thread = Some(thread_id::get());
+    }
+    Ok(())
+}
+
+/// Mark `backend` as deinitialized using `deinit`
+///
+/// Once every initialized backend has been deinitialized, Qt is torn down for the rest of the process.
+pub(crate) fn deinit<F: FnOnce() -> bool>(backend: Backend, deinit: F) -> bool {
+    let mut state = lock_or_recover(&STATE);
+    *state.backend(backend) = BackendState::Dropped;
+    if state.pdf != BackendState::Ready && state.image != BackendState::Ready {
+        state.torn_down = true;
+    }
+    deinit()
+}
+
+/// Reserve the shared backend for a conversion
+///
+/// Returns `Error::Blocked` if another PDF or image conversion is already in progress.
+pub(crate) fn acquire(backend: Backend) -> Result<()> {
+    let mut state = lock(&STATE, "backend state")?;
+    state.check_thread()?;
+    let busy = state.busy;
+    match *state.backend(backend) {
+        BackendState::New | BackendState::Dropped => Err(Error::NotInitialized),
+        BackendState::Ready if busy => Err(Error::Blocked),
+        BackendState::Ready => {
+            state.busy = true;
+            Ok(())
+        }
+    }
+}
+
+/// Release the reservation made by `acquire`
+pub(crate) fn release() {
+    let mut state = lock_or_recover(&STATE);
+    state.busy = false;
+}
@@ -0,0 +1,609 @@
+//! Render by running the `wkhtmltopdf` and `wkhtmltoimage` executables
+//!
+//! `SubprocessRenderer` translates the settings of a `PdfBuilder` or `ImageBuilder` into
+//!   command line arguments, and runs the executable with the rendered output written to stdout.
+//!   This doesn't require libwkhtmltox or its headers, so it can be used on hosts that only
+//!   have the wkhtmltopdf binaries installed (build with `default-features = false`).
+//!
+//! Since every conversion runs in its own process, none of the limitations of libwkhtmltox apply:
+//!   a `SubprocessRenderer` may be used from any thread, and conversions may run in parallel.
+//!
+//! ## Example
+//! ```no_run
+//! use wkhtmltopdf::*;
+//!
+//! let renderer = SubprocessRenderer::new();
+//! let mut builder = renderer.pdf_builder();
+//! builder.orientation(Orientation::Landscape).title("Awesome Foo");
+//!
+//! let html = r#"<html><body><div>foo</div></body></html>"#;
+//! let pdf = renderer
+//!     .render_pdf(&builder, &Source::Html(html.into()))
+//!     .expect("failed to render pdf");
+//! std::fs::write("foo.pdf", pdf).expect("failed to save foo.pdf");
+//! ```
+use log::debug;
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+
+use crate::cancellation::Limits;
+use crate::error::*;
+use crate::image::ImageBuilder;
+use crate::pdf::PdfBuilder;
+use crate::renderer::Renderer;
+use crate::source::Source;
+use crate::telemetry::Conversion;
+
+/// Renders PDFs and images by running the wkhtmltopdf executables
+///
+/// By default, `wkhtmltopdf` and `wkhtmltoimage` are found on the `PATH`.
+#[derive(Debug, Clone)]
+pub struct SubprocessRenderer {
+    pdf_command: PathBuf,
+    image_command: PathBuf,
+}
+
+impl SubprocessRenderer {
+    /// Use the `wkhtmltopdf` and `wkhtmltoimage` executables found on the `PATH`
+    pub fn new() -> SubprocessRenderer {
+        SubprocessRenderer {
+            pdf_command: "wkhtmltopdf".into(),
+            image_command: "wkhtmltoimage".into(),
+        }
+    }
+
+    /// Path to the `wkhtmltopdf` executable
+    pub fn pdf_command<P: Into<PathBuf>>(&mut self, path: P) -> &mut SubprocessRenderer {
+        self.pdf_command = path.into();
+        self
+    }
+
+    /// Path to the `wkhtmltoimage` executable
+    pub fn image_command<P: Into<PathBuf>>(&mut self, path: P) -> &mut SubprocessRenderer {
+        self.image_command = path.into();
+        self
+    }
+}
+
+impl Default for SubprocessRenderer {
+    fn default() -> SubprocessRenderer {
+        SubprocessRenderer::new()
+    }
+}
+
+impl Renderer for SubprocessRenderer {
+    fn render_pdf(&self, builder: &PdfBuilder, source: &Source) -> Result<Vec<u8>> {
+        let (gs, os) = builder.settings();
+        Conversion::start("pdf", source.kind(), gs.len() + os.len())
+            .run(|| self.run_pdf(builder, source), Vec::len)
+    }
+
+    fn render_image(&self, builder: &ImageBuilder, source: &Source) -> Result<Vec<u8>> {
+        let settings = builder.settings(builder.is_svg()).len();
+        Conversion::start("image", source.kind(), settings)
+            .run(|| self.run_image(builder, source), Vec::len)
+    }
+}
+
+impl SubprocessRenderer {
+    fn run_pdf(&self, builder: &PdfBuilder, source: &Source) -> Result<Vec<u8>> {
+        let source = builder.resolve_source(source)?;
+        let mut args = pdf_args(builder)?;
+        // Blocked requests are logged by the proxy, since there's no way to return them with the output
+        let session = builder.start_session(&source)?;
+        let source = session.prepare(source);
+        if let Some(proxy) = session.proxy_url() {
+            args.extend(vec!["--proxy".into(), proxy.into()]);
+        }
+        let output = run(&self.pdf_command, args, &source, builder.limits())?;
+        for line in warnings(&output.stderr) {
+            debug!("wkhtmltopdf warning: {}", line);
+        }
+        Ok(output.stdout)
+    }
+
+    fn run_image(&self, builder: &ImageBuilder, source: &Source) -> Result<Vec<u8>> {
+        let source = builder.resolve_source(source)?;
+        let mut args = image_args(builder)?;
+        let session = builder.start_session(&source)?;
+        let source = session.prepare(source);
+        if let Some(proxy) = session.proxy_url() {
+            args.extend(vec!["--proxy".into(), proxy.into()]);
+        }
+        let output = run(&self.image_command, args, &source, builder.limits())?;
+        let mut element = None;
+        for line in warnings(&output.stderr) {
+            match builder.element_bounds(line) {
+                Some(bbox) => element = Some(bbox),
+                None => debug!("wkhtmltoimage warning: {}", line),
+            }
+        }
+        match builder.post_process(&output.stdout, element)? {
+            Some(data) => Ok(data),
+            None => Ok(output.stdout),
+        }
+    }
+}
+
+// The argument naming the page, which is "-" for HTML written to stdin
+fn source_arg(source: &Source) -> OsString {
+    match source {
+        Source::Url(url) => url.as_str().into(),
+        // Otherwise a relative path starting with "-" would be parsed as an option
+        Source::Path(path) if path.is_relative() => Path::new(".").join(path).into(),
+        Source::Path(path) => path.into(),
+        Source::Html(_) => "-".into(),
+    }
+}
+
+struct Output {
+    stdout: Vec<u8>,
+    stderr: String,
+}
+
+// Run `command` with the source passed as the final argument, reading the output from stdout
+//
+// The process is killed if it exceeds the timeout or is cancelled.
+fn run(
+    command: &Path,
+    mut args: Vec<OsString>,
+    source: &Source,
+    limits: &Limits,
+) -> Result<Output> {
+    let watch = limits.start();
+    watch.check()?;
+    args.push(source_arg(source));
+    let html = match source {
+        Source::Html(html) => Some(html.clone()),
+        _ => None,
+    };
+    args.push("-".into());
+
+    debug!("running {} {:?}", command.display(), args);
+    let mut child = Command::new(command)
+        .args(&args)
+        .stdin(if html.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Write stdin from another thread so a large page can't deadlock against a full stdout pipe
+    let writer = match (html, child.stdin.take()) {
+        (Some(html), Some(mut stdin)) => {
+            Some(thread::spawn(move || stdin.write_all(html.as_bytes())))
+        }
+        _ => None,
+    };
+    // Likewise, read stdout and stderr from other threads so neither pipe can fill up while waiting
+    let stdout = child
+        .stdout
+        .take()
+        .map(|pipe| thread::spawn(|| read_pipe(pipe)));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|pipe| thread::spawn(|| read_pipe(pipe)));
+
+    let status = if watch.is_limited() {
+        loop {
+            let err = match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => watch.check().err(),
+                Err(err) => Some(err.into()),
+            };
+            if let Some(err) = err {
+                debug!("killing {}: {}", command.display(), err);
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+            thread::sleep(watch.poll_interval());
+        }
+    } else {
+        child.wait()?
+    };
+    if let Some(writer) = writer {
+        // A broken pipe just means the process exited early, which is reported by its status
+        if let Ok(Err(err)) = writer.join() {
+            debug!("failed to write html to {}: {}", command.display(), err);
+        }
+    }
+
+    let stdout = join_pipe(stdout)?;
+    let stderr = String::from_utf8_lossy(&join_pipe(stderr)?).into_owned();
+    if !status.success() {
+        return Err(classify_failure(error_lines(&stderr))
+            .unwrap_or_else(|| Error::ConversionFailed(failure_message(status, &stderr))));
+    }
+    Ok(Output { stdout, stderr })
+}
+
+fn read_pipe<R: Read>(mut pipe: R) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    pipe.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn join_pipe(reader: Option<thread::JoinHandle<io::Result<Vec<u8>>>>) -> Result<Vec<u8>> {
+    match reader.map(thread::JoinHandle::join) {
+        Some(Ok(data)) => Ok(data?),
+        // Reading from a pipe doesn't panic, but a missing pipe is equivalent to an empty one
+        Some(Err(_)) | None => Ok(Vec::new()),
+    }
+}
+
+// Command line arguments equivalent to the settings of a `PdfBuilder`
+fn pdf_args(builder: &PdfBuilder) -> Result<Vec<OsString>> {
+    let (mut gs, mut os) = builder.settings();
+    gs.sort();
+    os.sort();
+
+    let mut args = Vec::new();
+    for (name, value) in gs {
+        let flags = setting_args(name, value)
+            .ok_or_else(|| Error::GlobalSettingFailure(name.to_string(), value.to_string()))?;
+        args.extend(flags);
+    }
+    // Page options given before the input apply to it, so object settings can follow global settings
+    for (name, value) in os {
+        let flags = setting_args(name, value)
+            .ok_or_else(|| Error::ObjectSettingFailure(name.to_string(), value.to_string()))?;
+        args.extend(flags);
+    }
+    Ok(args)
+}
+
+// Command line arguments equivalent to the settings of an `ImageBuilder`
+fn image_args(builder: &ImageBuilder) -> Result<Vec<OsString>> {
+    // Raster output is cropped in `post_process`, just like the native backend
+    let mut gs = builder.settings(builder.is_svg());
+    gs.sort();
+
+    let mut args = Vec::new();
+    for (name, value) in gs {
+        // `ImageBuilder::image_quality` uses the name of the equivalent PDF setting
+        let flags = match name {
+            "imageQuality" => Some(vec!["--quality".into(), value.as_ref().into()]),
+            _ => setting_args(name, &value),
+        }
+        .ok_or_else(|| Error::GlobalSettingFailure(name.to_string(), value.to_string()))?;
+        args.extend(flags);
+    }
+    Ok(args)
+}
+
+// Map a libwkhtmltox setting onto the equivalent command line flags
+//
+// Returns `None` if the setting (or its value) has no command line equivalent.
+fn setting_args(name: &str, value: &str) -> Option<Vec<OsString>> {
+    let flag = |flag: &str| Some(vec![flag.into(), value.into()]);
+    // Boolean settings map onto a pair of flags, where an empty flag is the default behavior
+    let switch = |on: &str, off: &str| {
+        let flag = match value {
+            "true" => on,
+            "false" => off,
+            _ => return None,
+        };
+        Some(if flag.is_empty() {
+            vec![]
+        } else {
+            vec![flag.into()]
+        })
+    };
+
+    match name {
+        // PDF global settings
+        "size.pageSize" => flag("--page-size"),
+        "size.width" => flag("--page-width"),
+        "size.height" => flag("--page-height"),
+        "orientation" => flag("--orientation"),
+        "colorMode" => match value {
+            "Color" => Some(vec![]),
+            "Grayscale" => Some(vec!["--grayscale".into()]),
+            _ => None,
+        },
+        "dpi" => flag("--dpi"),
+        "documentTitle" => flag("--title"),
+        "copies" => flag("--copies"),
+        "collate" => switch("--collate", "--no-collate"),
+        "outline" => switch("--outline", "--no-outline"),
+        "outlineDepth" => flag("--outline-depth"),
+        "margin.top" => flag("--margin-top"),
+        "margin.bottom" => flag("--margin-bottom"),
+        "margin.left" => flag("--margin-left"),
+        "margin.right" => flag("--margin-right"),
+        "imageDPI" => flag("--image-dpi"),
+        "imageQuality" => flag("--image-quality"),
+        "useCompression" => switch("", "--no-pdf-compression"),
+
+        // Image global settings
+        "fmt" => flag("--format"),
+        "screenWidth" => flag("--width"),
+        "screenHeight" => flag("--height"),
+        "smartWidth" => switch("", "--disable-smart-width"),
+        "transparent" => switch("--transparent", ""),
+        "quality" => flag("--quality"),
+        "crop.left" => flag("--crop-x"),
+        "crop.top" => flag("--crop-y"),
+        "crop.width" => flag("--crop-w"),
+        "crop.height" => flag("--crop-h"),
+
+        // PDF object settings
+        "useExternalLinks" => switch("--enable-external-links", "--disable-external-links"),
+        "useLocalLinks" => switch("--enable-internal-links", "--disable-internal-links"),
+        "produceForms" => switch("--enable-forms", "--disable-forms"),
+        "includeInOutline" => switch("--include-in-outline", "--exclude-from-outline"),
+        "header.fontSize" => flag("--header-font-size"),
+        "header.fontName" => flag("--header-font-name"),
+        "header.left" => flag("--header-left"),
+        "header.center" => flag("--header-center"),
+        "header.right" => flag("--header-right"),
+        "header.line" => switch("--header-line", "--no-header-line"),
+        "header.spacing" => flag("--header-spacing"),
+        "header.htmlUrl" => flag("--header-html"),
+        "footer.fontSize" => flag("--footer-font-size"),
+        "footer.fontName" => flag("--footer-font-name"),
+        "footer.left" => flag("--footer-left"),
+        "footer.center" => flag("--footer-center"),
+        "footer.right" => flag("--footer-right"),
+        "footer.line" => switch("--footer-line", "--no-footer-line"),
+        "footer.spacing" => flag("--footer-spacing"),
+        "footer.htmlUrl" => flag("--footer-html"),
+
+        // Web and load settings (object settings for PDFs, global settings for images)
+        "web.background" => switch("--background", "--no-background"),
+        "web.loadImages" => switch("--images", "--no-images"),
+        "web.enableJavascript" => switch("--enable-javascript", "--disable-javascript"),
+        "web.enableIntelligentShrinking" => {
+            switch("--enable-smart-shrinking", "--disable-smart-shrinking")
+        }
+        "web.minimumFontSize" => flag("--minimum-font-size"),
+        "web.printMediaType" => switch("--print-media-type", "--no-print-media-type"),
+        "web.defaultEncoding" => flag("--encoding"),
+        "web.userStyleSheet" => flag("--user-style-sheet"),
+        "web.enablePlugins" => switch("--enable-plugins", "--disable-plugins"),
+        "load.username" => flag("--username"),
+        "load.password" => flag("--password"),
+        "load.jsdelay" => flag("--javascript-delay"),
+        "load.zoomFactor" => flag("--zoom"),
+        "load.blockLocalFileAccess" => {
+            switch("--disable-local-file-access", "--enable-local-file-access")
+        }
+        "load.stopSlowScripts" => switch("--stop-slow-scripts", "--no-stop-slow-scripts"),
+        "load.debugJavascript" => switch("--debug-javascript", "--no-debug-javascript"),
+        "load.loadErrorHandling" => flag("--load-error-handling"),
+        "load.mediaLoadErrorHandling" => flag("--load-media-error-handling"),
+        "load.proxy" => flag("--proxy"),
+        "load.allowed.append" => flag("--allow"),
+        "load.windowStatus" => flag("--window-status"),
+        "load.cookieJar" => flag("--cookie-jar"),
+        "load.runScript.append" => flag("--run-script"),
+        _ => None,
+    }
+}
+
+// Lines of stderr that report warnings, e.g. from the page's console when debugging javascript
+fn warnings(stderr: &str) -> impl Iterator<Item = &str> {
+    stderr
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| line.strip_prefix("Warning:"))
+        .map(str::trim)
+}
+
+// The non-empty lines of `stderr`, which also separates progress updates with carriage returns
+fn lines(stderr: &str) -> impl Iterator<Item = &str> {
+    stderr
+        .split(['\n', '\r'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+}
+
+// The messages of the lines of `stderr` that report errors
+fn error_lines(stderr: &str) -> impl Iterator<Item = &str> {
+    lines(stderr)
+        .filter_map(|line| line.strip_prefix("Error:"))
+        .map(str::trim)
+}
+
+// Describe why the process failed using the errors it reported on stderr
+fn failure_message(status: ExitStatus, stderr: &str) -> String {
+    let errors: Vec<&str> = error_lines(stderr).collect();
+    if !errors.is_empty() {
+        errors.join("; ")
+    } else if let Some(last) = lines(stderr).last() {
+        last.to_string()
+    } else {
+        format!("exited with {}", status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{CaptureMode, ImageFormat};
+    use crate::pdf::{Orientation, Size};
+    use crate::sandbox::Sandbox;
+
+    fn strings(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn maps_pdf_settings_to_args() {
+        let mut builder = PdfBuilder::new();
+        builder
+            .orientation(Orientation::Landscape)
+            .margin(Size::Millimeters(5))
+            .outline(None)
+            .title("Report")
+            .sandbox(Sandbox::new().allow_path("/srv/assets"));
+        unsafe {
+            builder.object_setting("web.printMediaType", "true");
+        }
+
+        let args = strings(pdf_args(&builder).unwrap());
+        assert_eq!(
+            args,
+            [
+                "--title",
+                "Report",
+                "--margin-bottom",
+                "5mm",
+                "--margin-left",
+                "5mm",
+                "--margin-right",
+                "5mm",
+                "--margin-top",
+                "5mm",
+                "--orientation",
+                "Landscape",
+                "--no-outline",
+                "--allow",
+                "/srv/assets",
+                "--disable-local-file-access",
+                "--print-media-type",
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_image_settings_to_args() {
+        let mut builder = ImageBuilder::new();
+        builder
+            .format(ImageFormat::Png)
+            .transparent(true)
+            .capture_mode(CaptureMode::Viewport {
+                width: 1024,
+                height: 768,
+            });
+
+        // Raster output is cropped after rendering rather than by wkhtmltoimage
+        let args = strings(image_args(&builder).unwrap());
+        assert_eq!(
+            args,
+            [
+                "--format",
+                "png",
+                "--width",
+                "1024",
+                "--disable-smart-width",
+                "--transparent",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_settings_without_flags() {
+        let mut builder = PdfBuilder::new();
+        unsafe {
+            builder.global_setting("resolution", "300");
+        }
+        match pdf_args(&builder) {
+            Err(Error::GlobalSettingFailure(name, value)) => {
+                assert_eq!((name.as_str(), value.as_str()), ("resolution", "300"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn passes_paths_that_cant_be_parsed_as_options() {
+        let arg = |source: Source| source_arg(&source).into_string().unwrap();
+        assert_eq!(
+            arg(Source::Path("--enable-local-file-access".into())),
+            Path::new(".")
+                .join("--enable-local-file-access")
+                .to_str()
+                .unwrap()
+        );
+        assert_eq!(
+            arg(Source::Path("pages/index.html".into())),
+            Path::new(".").join("pages/index.html").to_str().unwrap()
+        );
+        let absolute = std::env::temp_dir().join("-x.html");
+        assert_eq!(
+            arg(Source::Path(absolute.clone())),
+            absolute.to_str().unwrap()
+        );
+        assert_eq!(arg(Source::Html("<p>-</p>".into())), "-");
+    }
+
+    #[test]
+    fn reports_errors_from_stderr() {
+        let stderr = "Loading pages (1/6)\r[====>    ] 50%\rError: Failed loading page http://bad (sometimes it will work just to ignore this error with --load-error-handling ignore)\nExit with code 1 due to network error: HostNotFoundError\n";
+        let status = Command::new("false").status().unwrap();
+        assert_eq!(
+            failure_message(status, stderr),
+            "Failed loading page http://bad (sometimes it will work just to ignore this error with --load-error-handling ignore)"
+        );
+        assert_eq!(
+            failure_message(
+                status,
+                "Exit with code 1 due to network error: HostNotFoundError\n"
+            ),
+            "Exit with code 1 due to network error: HostNotFoundError"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn renders_stdout_and_maps_exit_codes() {
+        use crate::fixtures::TempFile;
+
+        // Stand-ins for wkhtmltopdf, which are removed when the test finishes
+        let echo = TempFile::script("echo", "cat");
+        let fail = TempFile::script("fail", "echo 'Error: Failed loading page' >&2; exit 1");
+        let unreachable = TempFile::script(
+            "unreachable",
+            "echo 'Error: Failed to load http://bad/, with network status code 3 and http status code 0 - Host bad not found' >&2; exit 1",
+        );
+        let hang = TempFile::script("hang", "sleep 10");
+
+        let mut renderer = SubprocessRenderer::new();
+        renderer.pdf_command(echo.path());
+        let builder = renderer.pdf_builder();
+        let html = Source::Html("<h1>piped</h1>".into());
+        assert_eq!(
+            renderer.render_pdf(&builder, &html).unwrap(),
+            b"<h1>piped</h1>"
+        );
+
+        renderer.pdf_command(fail.path());
+        match renderer.render_pdf(&builder, &html) {
+            Err(Error::ConversionFailed(msg)) => assert_eq!(msg, "Failed loading page"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        renderer.pdf_command(unreachable.path());
+        match renderer.render_pdf(&builder, &html) {
+            Err(Error::NetworkError { url, .. }) => assert_eq!(url, "http://bad/"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        renderer.pdf_command(hang.path());
+        let mut builder = renderer.pdf_builder();
+        builder.timeout(std::time::Duration::from_millis(100));
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            renderer.render_pdf(&builder, &html),
+            Err(Error::Timeout(_))
+        ));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+        renderer.pdf_command("/nonexistent/wkhtmltopdf");
+        assert!(matches!(
+            renderer.render_pdf(&builder, &html),
+            Err(Error::IoError(_))
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+//! libwkhtmltox bindings resolved at runtime with libloading
+//!
+//! The functions mirror the signatures of wkhtmltox-sys so the `lowlevel` modules work unchanged
+//!   with either feature. The library is loaded once per process and never unloaded.
+#![allow(non_camel_case_types)]
+use lazy_static::lazy_static;
+use libloading::Library;
+use log::debug;
+use std::ffi::OsStr;
+use std::os::raw::{c_char, c_int, c_long, c_uchar};
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+use types::*;
+
+/// Opaque types and callbacks of the libwkhtmltox C API
+pub mod types {
+    use std::os::raw::{c_char, c_int};
+
+    #[repr(C)]
+    pub struct wkhtmltopdf_global_settings {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct wkhtmltopdf_object_settings {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct wkhtmltopdf_converter {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct wkhtmltoimage_global_settings {
+        _private: [u8; 0],
+    }
+    #[repr(C)]
+    pub struct wkhtmltoimage_converter {
+        _private: [u8; 0],
+    }
+
+    pub type wkhtmltopdf_str_callback =
+        Option<unsafe extern "C" fn(*mut wkhtmltopdf_converter, *const c_char)>;
+    pub type wkhtmltopdf_int_callback =
+        Option<unsafe extern "C" fn(*mut wkhtmltopdf_converter, c_int)>;
+    pub type wkhtmltopdf_void_callback = Option<unsafe extern "C" fn(*mut wkhtmltopdf_converter)>;
+    pub type wkhtmltoimage_str_callback =
+        Option<unsafe extern "C" fn(*mut wkhtmltoimage_converter, *const c_char)>;
+    pub type wkhtmltoimage_int_callback =
+        Option<unsafe extern "C" fn(*mut wkhtmltoimage_converter, c_int)>;
+    pub type wkhtmltoimage_void_callback =
+        Option<unsafe extern "C" fn(*mut wkhtmltoimage_converter)>;
+}
+
+// Generates a table of function pointers resolved from the library,
+//   and a module of wrappers with the same signatures as wkhtmltox-sys
+macro_rules! dynamic_api {
+    ($(mod $module:ident {
+        $(fn $name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)?;)*
+    })*) => {
+        struct Api {
+            $($($name: unsafe extern "C" fn($($ty),*) $(-> $ret)?,)*)*
+            // Keeps the library loaded for as long as the function pointers are in use
+            _library: Library,
+        }
+
+        impl Api {
+            unsafe fn resolve(library: Library) -> std::result::Result<Api, libloading::Error> {
+                Ok(Api {
+                    $($($name: *library.get(concat!(stringify!($name), "\0").as_bytes())?,)*)*
+                    _library: library,
+                })
+            }
+        }
+
+        $(pub mod $module {
+            #![allow(clippy::missing_safety_doc)]
+            pub use super::types::*;
+            use std::os::raw::*;
+
+            $(pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                (super::api().$name)($($arg),*)
+            })*
+        })*
+    };
+}
+
+dynamic_api! {
+    mod pdf {
+        fn wkhtmltopdf_init(use_graphics: c_int) -> c_int;
+        fn wkhtmltopdf_deinit() -> c_int;
+        fn wkhtmltopdf_extended_qt() -> c_int;
+        fn wkhtmltopdf_version() -> *const c_char;
+        fn wkhtmltopdf_create_global_settings() -> *mut wkhtmltopdf_global_settings;
+        fn wkhtmltopdf_destroy_global_settings(settings: *mut wkhtmltopdf_global_settings);
+        fn wkhtmltopdf_create_object_settings() -> *mut wkhtmltopdf_object_settings;
+        fn wkhtmltopdf_destroy_object_settings(settings: *mut wkhtmltopdf_object_settings);
+        fn wkhtmltopdf_set_global_setting(settings: *mut wkhtmltopdf_global_settings, name: *const c_char, value: *const c_char) -> c_int;
+        fn wkhtmltopdf_set_object_setting(settings: *mut wkhtmltopdf_object_settings, name: *const c_char, value: *const c_char) -> c_int;
+        fn wkhtmltopdf_create_converter(settings: *mut wkhtmltopdf_global_settings) -> *mut wkhtmltopdf_converter;
+        fn wkhtmltopdf_destroy_converter(converter: *mut wkhtmltopdf_converter);
+        fn wkhtmltopdf_set_warning_callback(converter: *mut wkhtmltopdf_converter, cb: wkhtmltopdf_str_callback);
+        fn wkhtmltopdf_set_error_callback(converter: *mut wkhtmltopdf_converter, cb: wkhtmltopdf_str_callback);
+        fn wkhtmltopdf_set_finished_callback(converter: *mut wkhtmltopdf_converter, cb: wkhtmltopdf_int_callback);
+        fn wkhtmltopdf_set_phase_changed_callback(converter: *mut wkhtmltopdf_converter, cb: wkhtmltopdf_void_callback);
+        fn wkhtmltopdf_current_phase(converter: *mut wkhtmltopdf_converter) -> c_int;
+        fn wkhtmltopdf_phase_description(converter: *mut wkhtmltopdf_converter, phase: c_int) -> *const c_char;
+        fn wkhtmltopdf_convert(converter: *mut wkhtmltopdf_converter) -> c_int;
+        fn wkhtmltopdf_add_object(converter: *mut wkhtmltopdf_converter, settings: *mut wkhtmltopdf_object_settings, data: *const c_char);
+        fn wkhtmltopdf_get_output(converter: *mut wkhtmltopdf_converter, out: *mut *const c_uchar) -> c_long;
+    }
+
+    mod image {
+        fn wkhtmltoimage_init(use_graphics: c_int) -> c_int;
+        fn wkhtmltoimage_deinit() -> c_int;
+        fn wkhtmltoimage_create_global_settings() -> *mut wkhtmltoimage_global_settings;
+        fn wkhtmltoimage_set_global_setting(settings: *mut wkhtmltoimage_global_settings, name: *const c_char, value: *const c_char) -> c_int;
+        fn wkhtmltoimage_create_converter(settings: *mut wkhtmltoimage_global_settings, data: *const c_char) -> *mut wkhtmltoimage_converter;
+        fn wkhtmltoimage_destroy_converter(converter: *mut wkhtmltoimage_converter);
+        fn wkhtmltoimage_set_warning_callback(converter: *mut wkhtmltoimage_converter, cb: wkhtmltoimage_str_callback);
+        fn wkhtmltoimage_set_error_callback(converter: *mut wkhtmltoimage_converter, cb: wkhtmltoimage_str_callback);
+        fn wkhtmltoimage_set_finished_callback(converter: *mut wkhtmltoimage_converter, cb: wkhtmltoimage_int_callback);
+        fn wkhtmltoimage_set_phase_changed_callback(converter: *mut wkhtmltoimage_converter, cb: wkhtmltoimage_void_callback);
+        fn wkhtmltoimage_current_phase(converter: *mut wkhtmltoimage_converter) -> c_int;
+        fn wkhtmltoimage_phase_description(converter: *mut wkhtmltoimage_converter, phase: c_int) -> *const c_char;
+        fn wkhtmltoimage_convert(converter: *mut wkhtmltoimage_converter) -> c_int;
+        fn wkhtmltoimage_get_output(converter: *mut wkhtmltoimage_converter, out: *mut *const c_uchar) -> c_long;
+    }
+}
+
+lazy_static! {
+    // Leaked once loaded, since Qt can't be safely unloaded from a running process
+    static ref API: Mutex<Option<&'static Api>> = Mutex::new(None);
+}
+
+// Environment variable that overrides the default library location
+const LIBRARY_ENV: &str = "WKHTMLTOX_LIB";
+
+// The names searched (using the platform's library search path) when no path is configured
+fn default_libraries() -> Vec<String> {
+    if let Ok(path) = std::env::var(LIBRARY_ENV) {
+        return vec![path];
+    }
+    let name = libloading::library_filename("wkhtmltox")
+        .to_string_lossy()
+        .into_owned();
+    if cfg!(target_os = "linux") {
+        // The unversioned symlink is often only installed alongside the headers
+        let versioned = format!("{}.0", name);
+        vec![name, versioned]
+    } else {
+        vec![name]
+    }
+}
+
+/// Load libwkhtmltox from `path`
+///
+/// Returns `Error::IllegalInit` if the library has already been loaded.
+pub(crate) fn load_from<P: AsRef<OsStr>>(path: P) -> Result<()> {
+    let mut api = super::lock(&API, "libwkhtmltox")?;
+    if api.is_some() {
+        return Err(Error::IllegalInit);
+    }
+    *api = Some(open(path.as_ref())?);
+    Ok(())
+}
+
+/// Load libwkhtmltox from its default location unless it has already been loaded
+pub(crate) fn load() -> Result<()> {
+    loaded().map(|_| ())
+}
+
+fn loaded() -> Result<&'static Api> {
+    let mut api = super::lock(&API, "libwkhtmltox")?;
+    if let Some(loaded) = *api {
+        return Ok(loaded);
+    }
+    let mut reasons = Vec::new();
+    for name in default_libraries() {
+        match open(name.as_ref()) {
+            Ok(loaded) => {
+                *api = Some(loaded);
+                return Ok(loaded);
+            }
+            Err(Error::LibraryNotLoaded(reason)) => reasons.push(reason),
+            Err(err) => return Err(err),
+        }
+    }
+    Err(Error::LibraryNotLoaded(reasons.join("; ")))
+}
+
+fn open(path: &OsStr) -> Result<&'static Api> {
+    debug!("loading libwkhtmltox from {}", path.to_string_lossy());
+    // libloading's errors already name the library (or symbol) that failed to load
+    let not_loaded = |err: libloading::Error| Error::LibraryNotLoaded(err.to_string());
+    // Loading runs the library's initializers, which is the reason for needing unsafe here
+    let library = unsafe { Library::new(path) }.map_err(not_loaded)?;
+    let api = unsafe { Api::resolve(library) }.map_err(not_loaded)?;
+    Ok(Box::leak(Box::new(api)))
+}
+
+// The loaded API; `load` is called before initializing wkhtmltopdf so this is only reached once loaded
+fn api() -> &'static Api {
+    loaded().unwrap_or_else(|err| panic!("libwkhtmltox is not available: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_missing_library() {
+        match open("/nonexistent/libwkhtmltox.so".as_ref()) {
+            Err(Error::LibraryNotLoaded(reason)) => {
+                assert!(
+                    reason.contains("/nonexistent/libwkhtmltox.so"),
+                    "{}",
+                    reason
+                )
+            }
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("loaded a library that doesn't exist"),
+        }
+    }
+}
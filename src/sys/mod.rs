@@ -0,0 +1,83 @@
+//! The libwkhtmltox functions used by the `lowlevel` modules
+//!
+//! With the `native` feature these are linked at build time by wkhtmltox-sys. With the `dynamic`
+//!   feature they are resolved from a libwkhtmltox shared library when it is first needed.
+//!   The lock helpers are also used by the loopback servers, so they are available with any backend.
+use std::ffi::CString;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::error::{Error, Result};
+
+#[cfg(feature = "dynamic")]
+mod dynamic;
+
+#[cfg(feature = "dynamic")]
+pub(crate) use dynamic::{image, load, load_from, pdf};
+
+#[cfg(all(feature = "native", not(feature = "dynamic")))]
+pub(crate) use wkhtmltox_sys::{image, pdf};
+
+/// Make sure libwkhtmltox is available before calling into it
+#[cfg(all(feature = "native", not(feature = "dynamic")))]
+pub(crate) fn load() -> Result<()> {
+    Ok(())
+}
+
+/// Convert `value` to a C string, returning `Error::InvalidInput` if it contains a NUL byte
+///
+/// `what` describes the value in the error, e.g. "setting value".
+#[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+pub(crate) fn c_string(what: &str, value: &str) -> Result<CString> {
+    CString::new(value).map_err(|err| {
+        Error::InvalidInput(format!(
+            "{} contains a NUL byte at offset {}",
+            what,
+            err.nul_position()
+        ))
+    })
+}
+
+/// Lock `mutex`, returning `Error::Poisoned` if a thread panicked while holding it
+#[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+pub(crate) fn lock<'a, T>(mutex: &'a Mutex<T>, name: &'static str) -> Result<MutexGuard<'a, T>> {
+    mutex.lock().map_err(|_| Error::Poisoned(name))
+}
+
+/// Lock `mutex` where an error can't be returned (e.g. in callbacks from C, in `Drop`, or on proxy threads)
+///
+/// The data behind these locks is only updated by single operations (e.g. inserting a callback),
+///   so it is still consistent if a thread panicked while holding the lock.
+pub(crate) fn lock_or_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn rejects_nul_bytes() {
+        assert_eq!(c_string("setting value", "A4").unwrap().as_bytes(), b"A4");
+        match c_string("setting value", "A4\0") {
+            Err(Error::InvalidInput(msg)) => {
+                assert_eq!(msg, "setting value contains a NUL byte at offset 2")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_poisoned_locks() {
+        let mutex = Arc::new(Mutex::new(1));
+        let poisoner = mutex.clone();
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(matches!(lock(&mutex, "test"), Err(Error::Poisoned("test"))));
+        assert_eq!(*lock_or_recover(&mutex), 1);
+    }
+}
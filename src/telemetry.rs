@@ -0,0 +1,183 @@
+//! Instrumentation of conversions with `tracing` spans and `metrics` (both optional features)
+//!
+//! With the `tracing` feature, each conversion runs in a `conversion` span (target `wkhtmltopdf`)
+//!   with the fields `kind` ("pdf" or "image"), `input` ("url", "path", or "html"), and `settings`
+//!   (the number of settings passed to wkhtmltopdf). The phases reported by in-process conversions
+//!   are recorded as `phase` events within the span, and the span's `bytes`, `duration_ms`,
+//!   and `error` fields are recorded when the conversion finishes.
+//!
+//! With the `metrics` feature, conversions are reported to the installed `metrics` recorder as:
+//!
+//! - `wkhtmltopdf_conversions_total` (counter, labeled with `kind` and `outcome`, which is "ok" or
+//!   the kind of error, e.g. "timeout" or "load_failed")
+//! - `wkhtmltopdf_conversion_duration_seconds` (histogram, labeled with `kind`)
+//! - `wkhtmltopdf_output_bytes` (histogram of successful conversions, labeled with `kind`)
+//! - `wkhtmltopdf_conversions_in_progress` (gauge, labeled with `kind`)
+use log::debug;
+use std::time::Instant;
+
+use crate::error::*;
+
+/// A conversion being instrumented, from `start` until it finishes in `run`
+pub(crate) struct Conversion {
+    kind: &'static str,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl Conversion {
+    /// Describe a conversion of `kind` ("pdf" or "image") from an `input` ("url", "path", or "html")
+    pub(crate) fn start(kind: &'static str, input: &'static str, settings: usize) -> Conversion {
+        debug!(
+            "starting {} conversion from {} with {} settings",
+            kind, input, settings
+        );
+        Conversion {
+            kind,
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                target: "wkhtmltopdf",
+                "conversion",
+                kind,
+                input,
+                settings,
+                bytes = tracing::field::Empty,
+                duration_ms = tracing::field::Empty,
+                error = tracing::field::Empty,
+            ),
+        }
+    }
+
+    /// Run `convert` within the conversion's span, recording its outcome and the length of its output
+    pub(crate) fn run<T, F, L>(self, convert: F, len: L) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+        L: FnOnce(&T) -> usize,
+    {
+        #[cfg(feature = "metrics")]
+        let in_progress =
+            metrics::gauge!("wkhtmltopdf_conversions_in_progress", "kind" => self.kind);
+        #[cfg(feature = "metrics")]
+        in_progress.increment(1.0);
+
+        let start = Instant::now();
+        #[cfg(feature = "tracing")]
+        let result = self.span.in_scope(convert);
+        #[cfg(not(feature = "tracing"))]
+        let result = convert();
+        let elapsed = start.elapsed();
+        let bytes = result.as_ref().ok().map(len);
+
+        #[cfg(feature = "metrics")]
+        {
+            in_progress.decrement(1.0);
+            let outcome = result.as_ref().map_or_else(outcome, |_| "ok");
+            metrics::counter!("wkhtmltopdf_conversions_total", "kind" => self.kind, "outcome" => outcome)
+                .increment(1);
+            metrics::histogram!("wkhtmltopdf_conversion_duration_seconds", "kind" => self.kind)
+                .record(elapsed.as_secs_f64());
+            if let Some(bytes) = bytes {
+                metrics::histogram!("wkhtmltopdf_output_bytes", "kind" => self.kind)
+                    .record(bytes as f64);
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        {
+            let _entered = self.span.enter();
+            self.span.record("duration_ms", elapsed.as_millis() as u64);
+            match (&result, bytes) {
+                (Ok(_), Some(bytes)) => {
+                    self.span.record("bytes", bytes as u64);
+                    tracing::info!(target: "wkhtmltopdf", bytes, "conversion finished");
+                }
+                (Err(err), _) => {
+                    self.span.record("error", tracing::field::display(err));
+                    tracing::warn!(target: "wkhtmltopdf", error = %err, "conversion failed");
+                }
+                _ => {}
+            }
+        }
+
+        match (&result, bytes) {
+            (Ok(_), Some(bytes)) => debug!(
+                "{} conversion produced {} bytes in {:?}",
+                self.kind, bytes, elapsed
+            ),
+            (Err(err), _) => debug!(
+                "{} conversion failed after {:?}: {}",
+                self.kind, elapsed, err
+            ),
+            _ => {}
+        }
+        result
+    }
+}
+
+/// Record a phase transition reported by wkhtmltopdf during an in-process conversion of `kind`
+///
+/// Phase callbacks run on the converting thread, so the event belongs to the current conversion's span.
+#[cfg_attr(not(any(feature = "native", feature = "dynamic")), allow(dead_code))]
+pub(crate) fn phase(kind: &'static str, phase: i32, description: &str) {
+    debug!("{} conversion phase {}: {}", kind, phase, description);
+    #[cfg(feature = "tracing")]
+    tracing::debug!(target: "wkhtmltopdf", kind, phase, description, "phase");
+}
+
+// The `outcome` label of a failed conversion
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+fn outcome(err: &Error) -> &'static str {
+    match err {
+        Error::IoError(_) => "io_error",
+        Error::ImageError(_) => "image_error",
+        Error::RendererCrashed { .. } => "renderer_crashed",
+        Error::Timeout(_) => "timeout",
+        Error::Cancelled => "cancelled",
+        Error::LoadFailed { .. } => "load_failed",
+        Error::NetworkError { .. } => "network_error",
+        Error::OutputWriteFailed(_) => "output_write_failed",
+        Error::ConversionFailed(_) => "conversion_failed",
+        Error::ElementNotFound(_) => "element_not_found",
+        Error::InvalidInput(_) | Error::PathNotAllowed(_) | Error::ResourceNotFound(_) => {
+            "invalid_input"
+        }
+        Error::Unsupported(_)
+        | Error::GlobalSettingFailure(..)
+        | Error::ObjectSettingFailure(..) => "invalid_settings",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_results_unchanged() {
+        let output = Conversion::start("pdf", "html", 3).run(|| Ok(vec![1, 2, 3]), Vec::len);
+        assert_eq!(output.unwrap(), vec![1, 2, 3]);
+
+        let failed = Conversion::start("image", "url", 0).run(
+            || -> Result<Vec<u8>> { Err(Error::Timeout(std::time::Duration::from_secs(1))) },
+            |_| unreachable!("no output to measure"),
+        );
+        assert!(matches!(failed, Err(Error::Timeout(_))));
+    }
+
+    #[test]
+    fn labels_failures_by_kind() {
+        assert_eq!(outcome(&Error::Cancelled), "cancelled");
+        assert_eq!(
+            outcome(&Error::NetworkError {
+                url: "http://bad/".into(),
+                reason: "Host bad not found".into(),
+            }),
+            "network_error"
+        );
+        assert_eq!(
+            outcome(&Error::InvalidInput("NUL byte".into())),
+            "invalid_input"
+        );
+        assert_eq!(outcome(&Error::Blocked), "other");
+    }
+}
@@ -0,0 +1,175 @@
+//! Render PDFs from templates (`tera`, `handlebars`, or `askama` features)
+//!
+//! [`PdfBuilder::build_from_template`](../pdf/struct.PdfBuilder.html#method.build_from_template)
+//!   renders a template with a [`TemplateEngine`](trait.TemplateEngine.html) and builds a PDF from the
+//!   resulting HTML. Header and footer templates set with `header_template` and `footer_template`
+//!   are rendered with the same context.
+//!
+//! `TemplateEngine` is implemented for `tera::Tera` and `handlebars::Handlebars` with any
+//!   `Serialize` context (e.g. `tera::Context::into_json()`), and for [`Askama`](struct.Askama.html),
+//!   which renders `askama::Template` values.
+//!
+//! ## Example
+//! ```no_run
+//! # #[cfg(all(feature = "tera", any(feature = "native", feature = "dynamic")))] {
+//! use wkhtmltopdf::*;
+//!
+//! let mut tera = tera::Tera::default();
+//! tera.add_raw_template("invoice.html", "<h1>Invoice {{ number }}</h1>").unwrap();
+//! tera.add_raw_template("footer.html", "<p>Invoice {{ number }}</p>").unwrap();
+//!
+//! let mut context = tera::Context::new();
+//! context.insert("number", &42);
+//!
+//! let pdf_app = PdfApplication::new().expect("Failed to init PDF application");
+//! let pdfout = pdf_app.builder()
+//!     .footer_template("footer.html")
+//!     .build_from_template(&tera, "invoice.html", &context.into_json())
+//!     .expect("failed to build pdf");
+//! # }
+//! ```
+#[cfg(any(feature = "tera", feature = "handlebars"))]
+use serde::Serialize;
+use std::error::Error as StdError;
+
+use crate::error::*;
+
+/// A template engine that can render a named template with a context of type `C`
+pub trait TemplateEngine<C: ?Sized> {
+    /// Render the template `name` with `context` into HTML
+    fn render_html(&self, name: &str, context: &C) -> Result<String>;
+
+    /// Whether templates are selected by `name`
+    ///
+    /// Header and footer templates are only supported by engines that select templates by name.
+    fn renders_by_name(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "tera")]
+impl<C: Serialize + ?Sized> TemplateEngine<C> for tera::Tera {
+    fn render_html(&self, name: &str, context: &C) -> Result<String> {
+        let context =
+            tera::Context::from_serialize(context).map_err(|err| template_error(name, err))?;
+        self.render(name, &context)
+            .map_err(|err| template_error(name, err))
+    }
+}
+
+#[cfg(feature = "handlebars")]
+impl<'reg, C: Serialize + ?Sized> TemplateEngine<C> for handlebars::Handlebars<'reg> {
+    fn render_html(&self, name: &str, context: &C) -> Result<String> {
+        self.render(name, &context)
+            .map_err(|err| template_error(name, err))
+    }
+}
+
+/// Renders `askama::Template` values, which are their own context
+///
+/// Since each Askama template is a type, the template `name` is ignored (and only used in errors),
+///   so header and footer templates aren't supported.
+#[cfg(feature = "askama")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Askama;
+
+#[cfg(feature = "askama")]
+impl<T: askama::Template> TemplateEngine<T> for Askama {
+    fn render_html(&self, name: &str, template: &T) -> Result<String> {
+        template.render().map_err(|err| template_error(name, err))
+    }
+
+    fn renders_by_name(&self) -> bool {
+        false
+    }
+}
+
+// Keeps the engine's error as the source, so callers can walk or downcast it
+pub(crate) fn template_error<E>(name: &str, err: E) -> Error
+where
+    E: StdError + Send + Sync + 'static,
+{
+    Error::Template {
+        name: name.to_string(),
+        source: Box::new(err),
+    }
+}
+
+/// The header and footer templates of a `PdfBuilder`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PageTemplates {
+    pub(crate) header: Option<String>,
+    pub(crate) footer: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_error_sources() {
+        use std::io;
+        let err = io::Error::other("variable `number` not found");
+        let err = io::Error::other(err);
+        match template_error("invoice.html", err) {
+            err @ Error::Template { .. } => {
+                assert_eq!(err.to_string(), "Failed to render template 'invoice.html'");
+                assert!(
+                    describe_sources(&err).ends_with("variable `number` not found"),
+                    "{:?}",
+                    err
+                );
+                assert!(err.source().unwrap().is::<io::Error>());
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "tera")]
+    #[test]
+    fn renders_tera_templates() {
+        let mut tera = tera::Tera::default();
+        tera.add_raw_template("page.html", "<h1>{{ title }}</h1>")
+            .unwrap();
+        let context = std::collections::HashMap::from([("title", "Report")]);
+        assert_eq!(
+            tera.render_html("page.html", &context).unwrap(),
+            "<h1>Report</h1>"
+        );
+        match tera.render_html("missing.html", &context) {
+            Err(Error::Template { name, .. }) => assert_eq!(name, "missing.html"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "handlebars")]
+    #[test]
+    fn renders_handlebars_templates() {
+        let mut handlebars = handlebars::Handlebars::new();
+        handlebars
+            .register_template_string("page", "<h1>{{title}}</h1>")
+            .unwrap();
+        let context = std::collections::HashMap::from([("title", "Report")]);
+        assert_eq!(
+            handlebars.render_html("page", &context).unwrap(),
+            "<h1>Report</h1>"
+        );
+    }
+
+    #[cfg(feature = "askama")]
+    #[test]
+    fn renders_askama_templates() {
+        #[derive(askama::Template)]
+        #[template(source = "<h1>{{ title }}</h1>", ext = "html")]
+        struct Page<'a> {
+            title: &'a str,
+        }
+
+        let page = Page { title: "Report" };
+        assert_eq!(
+            Askama.render_html("page", &page).unwrap(),
+            "<h1>Report</h1>"
+        );
+        assert!(!TemplateEngine::<Page>::renders_by_name(&Askama));
+    }
+}
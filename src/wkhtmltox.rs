@@ -0,0 +1,79 @@
+//! Generate both PDFs and images from a single application
+//!
+//! Wkhtmltopdf and wkhtmltoimage share a single Qt instance, so they share its limitations:
+//!   both must be used from the thread that initialized them, only one conversion
+//!   (PDF or image) may run at a time, and neither can be reinitialized once both are dropped.
+//!
+//! ## Example
+//! ```no_run
+//! use wkhtmltopdf::*;
+//!
+//! let wk = Wkhtmltox::new().expect("Failed to init wkhtmltox");
+//! let html = r#"<html><body><h1>Quarterly report</h1></body></html>"#;
+//!
+//! wk.pdf_builder()
+//!     .title("Quarterly report")
+//!     .build_from_html(html)
+//!     .expect("failed to build pdf")
+//!     .save("report.pdf")
+//!     .expect("failed to save report.pdf");
+//!
+//! wk.image_builder()
+//!     .format(ImageFormat::Png)
+//!     .build_from_html(html)
+//!     .expect("failed to build image")
+//!     .save("report.png")
+//!     .expect("failed to save report.png");
+//! ```
+use crate::error::*;
+use crate::image::{ImageApplication, ImageBuilder};
+use crate::library::Capabilities;
+use crate::pdf::{PdfApplication, PdfBuilder};
+
+/// Structure for initializing both wkhtmltopdf and wkhtmltoimage
+///
+/// This is a wrapper around `PdfApplication` and `ImageApplication` that initializes
+///   and deinitializes both backends together.
+pub struct Wkhtmltox {
+    pdf: PdfApplication,
+    image: ImageApplication,
+}
+
+impl Wkhtmltox {
+    /// Initializes wkhtmltopdf and wkhtmltoimage
+    ///
+    /// Both backends remain initialized for this process until `Wkhtmltox` is dropped,
+    ///   and all conversions must happen from the thread that initialized them.
+    ///
+    /// Returns `Error::IllegalInit` if either backend was already initialized in this process.
+    pub fn new() -> Result<Wkhtmltox> {
+        let pdf = PdfApplication::new()?;
+        let image = ImageApplication::new()?;
+        Ok(Wkhtmltox { pdf, image })
+    }
+
+    /// Instantiate a `PdfBuilder`
+    pub fn pdf_builder(&self) -> PdfBuilder {
+        self.pdf.builder()
+    }
+
+    /// Instantiate an `ImageBuilder`
+    pub fn image_builder(&self) -> ImageBuilder {
+        self.image.builder()
+    }
+
+    /// The optional features supported by this wkhtmltopdf
+    pub fn capabilities(&self) -> Capabilities {
+        self.pdf.capabilities()
+    }
+
+    /// The PDF application managed by this instance
+    pub fn pdf(&self) -> &PdfApplication {
+        &self.pdf
+    }
+
+    /// The image application managed by this instance
+    pub fn image(&self) -> &ImageApplication {
+        &self.image
+    }
+}